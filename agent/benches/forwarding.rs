@@ -0,0 +1,166 @@
+//! Forwarding throughput and latency through a whole proxy session, over
+//! stdio pipes and an in-memory bridge (`ook::bench`): `cargo bench`.
+//!
+//! Every group runs by message size. `bridge_to_zed` and `zed_to_bridge`
+//! are one-way throughput, reported in messages per second; `flush_policy`
+//! is `bridge_to_zed` under each `OOK_FLUSH_POLICY`; `round_trip` is the
+//! latency from a stdin line to its echo on stdout, with one message in
+//! flight at a time.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ook::bench::{session, Ends, FlushPolicy};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Runtime;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Message sizes in bytes: a short response, a typical update, a file read.
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+/// Messages sent ahead of the ones received, in the throughput groups.
+const IN_FLIGHT: u64 = 64;
+
+/// An ACP notification `size` bytes long (or the shortest one, if longer).
+fn message(size: usize) -> String {
+    let with_pad = |pad: &str| {
+        format!(
+            r#"{{"jsonrpc":"2.0","method":"x","params":{{"pad":"{}"}}}}"#,
+            pad
+        )
+    };
+    let envelope = with_pad("").len();
+    with_pad(&"x".repeat(size.saturating_sub(envelope)))
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime")
+}
+
+/// Send `iters` copies of `frame` from the bridge and time until the last
+/// is on stdout.
+async fn burst_to_zed(flush_policy: FlushPolicy, frame: &str, iters: u64) -> Duration {
+    session(flush_policy, |mut ends: Ends| async move {
+        let started = Instant::now();
+        let (mut sent, mut received) = (0, 0);
+        while received < iters {
+            while sent < iters && sent - received < IN_FLIGHT {
+                ends.bridge.send(Message::Text(frame.to_string()));
+                sent += 1;
+            }
+            ends.stdout
+                .next_line()
+                .await
+                .unwrap()
+                .expect("a line on stdout");
+            received += 1;
+        }
+        let elapsed = started.elapsed();
+        drop(ends);
+        elapsed
+    })
+    .await
+}
+
+fn bridge_to_zed(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("bridge_to_zed");
+    group.throughput(Throughput::Elements(1));
+    for size in SIZES {
+        let frame = message(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &frame, |b, frame| {
+            b.iter_custom(|iters| rt.block_on(burst_to_zed(FlushPolicy::Immediate, frame, iters)))
+        });
+    }
+    group.finish();
+}
+
+fn flush_policy(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("flush_policy");
+    group.throughput(Throughput::Elements(1));
+    for policy in [FlushPolicy::Immediate, FlushPolicy::Coalesce] {
+        for size in SIZES {
+            let frame = message(size);
+            group.bench_with_input(BenchmarkId::new(policy.name(), size), &frame, |b, frame| {
+                b.iter_custom(|iters| rt.block_on(burst_to_zed(policy, frame, iters)))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn zed_to_bridge(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("zed_to_bridge");
+    group.throughput(Throughput::Elements(1));
+    for size in SIZES {
+        let line = message(size) + "\n";
+        group.bench_with_input(BenchmarkId::from_parameter(size), &line, |b, line| {
+            b.iter_custom(|iters| {
+                rt.block_on(session(
+                    FlushPolicy::Immediate,
+                    |mut ends: Ends| async move {
+                        let started = Instant::now();
+                        let (mut sent, mut received) = (0, 0);
+                        while received < iters {
+                            while sent < iters && sent - received < IN_FLIGHT {
+                                ends.stdin.write_all(line.as_bytes()).await.unwrap();
+                                sent += 1;
+                            }
+                            ends.bridge.recv().await.expect("a message at the bridge");
+                            received += 1;
+                        }
+                        let elapsed = started.elapsed();
+                        drop(ends);
+                        elapsed
+                    },
+                ))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn round_trip(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("round_trip");
+    for size in SIZES {
+        let line = message(size) + "\n";
+        group.bench_with_input(BenchmarkId::from_parameter(size), &line, |b, line| {
+            b.iter_custom(|iters| {
+                rt.block_on(session(
+                    FlushPolicy::Immediate,
+                    |mut ends: Ends| async move {
+                        let started = Instant::now();
+                        for _ in 0..iters {
+                            ends.stdin.write_all(line.as_bytes()).await.unwrap();
+                            let echo = ends.bridge.recv().await.expect("a message at the bridge");
+                            ends.bridge.send(echo);
+                            ends.stdout
+                                .next_line()
+                                .await
+                                .unwrap()
+                                .expect("a line on stdout");
+                        }
+                        let elapsed = started.elapsed();
+                        drop(ends);
+                        elapsed
+                    },
+                ))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bridge_to_zed,
+    flush_policy,
+    zed_to_bridge,
+    round_trip
+);
+criterion_main!(benches);
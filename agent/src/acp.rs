@@ -0,0 +1,114 @@
+//! Messages the agent writes to Zed itself, rather than relaying from the
+//! bridge. They follow the JSON-RPC 2.0 framing ACP uses.
+
+use serde_json::{json, Value};
+
+/// The bridge could not be reached (implementation-defined server error).
+pub const BRIDGE_UNREACHABLE: i64 = -32001;
+
+/// `--dry-run` answers requests without a bridge to send them to.
+pub const DRY_RUN: i64 = -32002;
+
+/// The agent itself failed (JSON-RPC's internal error), e.g. a panic.
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// A JSON-RPC error response with a `null` id, for failures that are not
+/// tied to a single request.
+pub fn error_response(code: i64, message: &str) -> String {
+    error_response_to(&Value::Null, code, message)
+}
+
+/// A JSON-RPC error response to the request with this `id`.
+pub fn error_response_to(id: &Value, code: i64, message: &str) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": code,
+            "message": message,
+        },
+    })
+    .to_string()
+}
+
+/// Method of the connection state notifications (`OOK_EMIT_STATE`).
+pub const CONNECTION_STATE: &str = "ook/connectionState";
+
+/// Method of the stdout keepalive (`OOK_STDOUT_KEEPALIVE_SECS`). ACP
+/// reserves methods starting with `_` for extensions and has receivers
+/// ignore notifications they don't recognise, so Zed drops it.
+pub const KEEPALIVE: &str = "_ook/keepalive";
+
+/// A JSON-RPC notification (no `id`).
+pub fn notification(method: &str, params: Value) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+    })
+    .to_string()
+}
+
+/// The bridge closed the connection with a code worth telling the user about.
+pub const BRIDGE_CLOSED: i64 = -32003;
+
+/// The bridge answered `initialize` with an ACP version outside
+/// `OOK_ACP_VERSIONS`.
+pub const UNSUPPORTED_ACP_VERSION: i64 = -32004;
+
+/// Which bridge close frames become a `BRIDGE_CLOSED` error for Zed
+/// (`OOK_REPORT_CLOSE`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportClose {
+    None,
+    /// Application-defined codes (4000-4999) only, which only a bridge
+    /// that means something by them sends.
+    App,
+    /// Every code except a normal close (1000).
+    All,
+}
+
+impl ReportClose {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(Self::None),
+            "app" => Ok(Self::App),
+            "all" => Ok(Self::All),
+            other => Err(format!(
+                "Unsupported OOK_REPORT_CLOSE {:?} (expected \"none\", \"app\", or \"all\")",
+                other
+            )),
+        }
+    }
+
+    pub fn covers(self, code: u16) -> bool {
+        match self {
+            Self::None => false,
+            Self::App => (4000..5000).contains(&code),
+            Self::All => code != 1000,
+        }
+    }
+}
+
+/// Close codes the bridge is known to send, and what they mean.
+const CLOSE_CODES: &[(u16, &str)] = &[
+    (1001, "the bridge is shutting down"),
+    (1011, "the agent process on the bridge failed"),
+    (1013, "the bridge is busy with another client"),
+];
+
+/// The message of the error reported for a bridge close frame.
+pub fn close_message(code: u16, reason: &str) -> String {
+    let meaning = CLOSE_CODES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or("the bridge ended the session", |(_, meaning)| meaning);
+    if reason.is_empty() {
+        format!("Ook Bridge closed the connection: {} ({})", meaning, code)
+    } else {
+        format!(
+            "Ook Bridge closed the connection: {} ({}: {})",
+            meaning, code, reason
+        )
+    }
+}
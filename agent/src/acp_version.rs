@@ -0,0 +1,215 @@
+//! Refusing a bridge on an ACP version the agent doesn't support
+//! (`OOK_CHECK_ACP_VERSION=1`, range `OOK_ACP_VERSIONS`).
+//!
+//! The id of Zed's `initialize` request is noted on its way out, and the
+//! bridge's response to it is checked for `result.protocolVersion`. A
+//! version outside the supported range is replaced by an error response to
+//! the same request, and everything after it from the bridge is dropped;
+//! the session then ends. A response without the field, or an error
+//! response, is taken as compatible. Only the first `initialize` is checked.
+
+use crate::acp;
+use serde_json::Value;
+use std::ops::RangeInclusive;
+use tracing::{debug, error};
+
+/// ACP versions this agent relays correctly, unless `OOK_ACP_VERSIONS`
+/// says otherwise.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u64> = 1..=1;
+
+/// Parse an `OOK_ACP_VERSIONS` range: `N` or `MIN-MAX`.
+pub fn parse_versions(value: &str) -> Result<RangeInclusive<u64>, String> {
+    let invalid = || {
+        format!(
+            "Invalid OOK_ACP_VERSIONS {:?} (expected a version like \"1\" or a range like \"1-2\")",
+            value
+        )
+    };
+    let (min, max) = value.split_once('-').unwrap_or((value, value));
+    let min: u64 = min.trim().parse().map_err(|_| invalid())?;
+    let max: u64 = max.trim().parse().map_err(|_| invalid())?;
+    if min > max {
+        return Err(invalid());
+    }
+    Ok(min..=max)
+}
+
+/// What to do with a message from the bridge.
+pub enum Verdict {
+    Forward,
+    /// Write this error response instead, then end the session.
+    Refuse(String),
+    /// The session was refused; nothing more goes to Zed.
+    Drop,
+}
+
+enum State {
+    /// Waiting for Zed's `initialize`.
+    Idle,
+    /// Waiting for the bridge's response to the request with this id.
+    Expecting(Value),
+    Checked,
+    Refused,
+}
+
+pub struct AcpVersion {
+    supported: RangeInclusive<u64>,
+    state: State,
+}
+
+impl AcpVersion {
+    pub fn new(supported: RangeInclusive<u64>) -> Self {
+        Self {
+            supported,
+            state: State::Idle,
+        }
+    }
+
+    pub fn supported(&self) -> &RangeInclusive<u64> {
+        &self.supported
+    }
+
+    /// Whether the bridge's version was refused; the session should end.
+    pub fn refused(&self) -> bool {
+        matches!(self.state, State::Refused)
+    }
+
+    /// Note a line from Zed, looking for the `initialize` request.
+    pub fn observe_zed(&mut self, line: &str) {
+        if !matches!(self.state, State::Idle) || !line.contains("initialize") {
+            return;
+        }
+        let Ok(value) = serde_json::from_str::<Value>(line) else {
+            return;
+        };
+        if let (Some("initialize"), Some(id)) =
+            (value.get("method").and_then(Value::as_str), value.get("id"))
+        {
+            self.state = State::Expecting(id.clone());
+        }
+    }
+
+    /// Check a message from the bridge.
+    pub fn check_bridge(&mut self, text: &str) -> Verdict {
+        let expected = match &self.state {
+            State::Refused => return Verdict::Drop,
+            State::Expecting(id) => id,
+            State::Idle | State::Checked => return Verdict::Forward,
+        };
+        let Ok(value) = serde_json::from_str::<Value>(text) else {
+            return Verdict::Forward;
+        };
+        if value.get("id") != Some(expected) || value.get("method").is_some() {
+            return Verdict::Forward;
+        }
+        let id = expected.clone();
+        self.state = State::Checked;
+        let Some(version) = value
+            .get("result")
+            .and_then(|result| result.get("protocolVersion"))
+        else {
+            debug!("Bridge's initialize response has no protocolVersion, assuming compatible");
+            return Verdict::Forward;
+        };
+        if version
+            .as_u64()
+            .is_some_and(|v| self.supported.contains(&v))
+        {
+            debug!(protocol_version = %version, "Bridge's ACP version is supported");
+            return Verdict::Forward;
+        }
+
+        self.state = State::Refused;
+        let message = format!(
+            "Ook Bridge speaks ACP version {}, but this agent supports {}-{} (OOK_ACP_VERSIONS)",
+            version,
+            self.supported.start(),
+            self.supported.end()
+        );
+        error!(protocol_version = %version, "{}", message);
+        Verdict::Refuse(acp::error_response_to(
+            &id,
+            acp::UNSUPPORTED_ACP_VERSION,
+            &message,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INITIALIZE: &str = r#"{"jsonrpc":"2.0","id":0,"method":"initialize","params":{}}"#;
+
+    /// A checker that has seen Zed's `initialize`.
+    fn initialized() -> AcpVersion {
+        let mut check = AcpVersion::new(1..=2);
+        check.observe_zed(INITIALIZE);
+        check
+    }
+
+    #[test]
+    fn parses_a_version_or_a_range() {
+        assert_eq!(parse_versions("1"), Ok(1..=1));
+        assert_eq!(parse_versions("1-2"), Ok(1..=2));
+        assert_eq!(parse_versions(" 2 - 3 "), Ok(2..=3));
+        for value in ["2-1", "x", "", "1-", "-1", "1-2-3"] {
+            assert!(parse_versions(value).is_err(), "accepted {:?}", value);
+        }
+    }
+
+    #[test]
+    fn forwards_a_supported_or_missing_version() {
+        let mut check = initialized();
+        let response = r#"{"jsonrpc":"2.0","id":0,"result":{"protocolVersion":2}}"#;
+        assert!(matches!(check.check_bridge(response), Verdict::Forward));
+
+        let mut check = initialized();
+        let bare = r#"{"jsonrpc":"2.0","id":0,"result":{}}"#;
+        assert!(matches!(check.check_bridge(bare), Verdict::Forward));
+        assert!(!check.refused());
+    }
+
+    #[test]
+    fn forwards_an_error_response() {
+        let mut check = initialized();
+        let error = r#"{"jsonrpc":"2.0","id":0,"error":{"code":-32603,"message":"busy"}}"#;
+        assert!(matches!(check.check_bridge(error), Verdict::Forward));
+        assert!(!check.refused());
+    }
+
+    #[test]
+    fn refuses_an_unsupported_version_then_drops_the_rest() {
+        let mut check = initialized();
+        let notice = r#"{"jsonrpc":"2.0","method":"session/update","params":{}}"#;
+        assert!(matches!(check.check_bridge(notice), Verdict::Forward));
+
+        let response = r#"{"jsonrpc":"2.0","id":0,"result":{"protocolVersion":3}}"#;
+        let Verdict::Refuse(error) = check.check_bridge(response) else {
+            panic!("version 3 was not refused");
+        };
+        let error: Value = serde_json::from_str(&error).unwrap();
+        assert_eq!(error["id"], 0);
+        assert_eq!(error["error"]["code"], acp::UNSUPPORTED_ACP_VERSION);
+        assert!(check.refused());
+        assert!(matches!(check.check_bridge(notice), Verdict::Drop));
+    }
+
+    #[test]
+    fn refuses_a_non_numeric_version() {
+        let mut check = initialized();
+        let response = r#"{"jsonrpc":"2.0","id":0,"result":{"protocolVersion":"1.0"}}"#;
+        assert!(matches!(check.check_bridge(response), Verdict::Refuse(_)));
+    }
+
+    #[test]
+    fn only_the_first_initialize_is_checked() {
+        let mut check = initialized();
+        let ok = r#"{"jsonrpc":"2.0","id":0,"result":{"protocolVersion":1}}"#;
+        assert!(matches!(check.check_bridge(ok), Verdict::Forward));
+
+        check.observe_zed(&INITIALIZE.replace(r#""id":0"#, r#""id":1"#));
+        let late = r#"{"jsonrpc":"2.0","id":1,"result":{"protocolVersion":9}}"#;
+        assert!(matches!(check.check_bridge(late), Verdict::Forward));
+    }
+}
@@ -0,0 +1,151 @@
+//! Bridge host allowlist (`OOK_ALLOWED_HOSTS`).
+//!
+//! A comma-separated list of host patterns, matched case-insensitively
+//! against each TCP bridge host (and any redirect target) before the agent
+//! connects. A pattern is an exact name or address, or a glob where `*`
+//! matches any run of characters and `?` any one, e.g. `*.internal` or
+//! `10.0.0.?`. IPv6 addresses are written without brackets.
+
+#[derive(Debug, Clone)]
+pub struct HostAllowlist {
+    patterns: Vec<String>,
+}
+
+impl HostAllowlist {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let patterns: Vec<String> = value
+            .split(',')
+            .map(|pattern| pattern.trim().to_ascii_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .collect();
+        if patterns.is_empty() {
+            return Err("OOK_ALLOWED_HOSTS is set but lists no hosts".to_string());
+        }
+        Ok(Self { patterns })
+    }
+
+    pub fn allows(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let host = host.to_ascii_lowercase();
+        self.patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), host.as_bytes()))
+    }
+
+    /// Refuse `host` with an error naming it, unless it is allowed.
+    pub fn check(&self, host: &str) -> Result<(), String> {
+        if self.allows(host) {
+            return Ok(());
+        }
+        Err(format!(
+            "Bridge host {:?} is not in OOK_ALLOWED_HOSTS ({})",
+            host,
+            self.patterns.join(", ")
+        ))
+    }
+}
+
+/// `*`/`?` glob match, backtracking to the last `*` on a mismatch.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(value: &str) -> HostAllowlist {
+        HostAllowlist::parse(value).unwrap()
+    }
+
+    #[test]
+    fn exact_names_and_addresses() {
+        let list = allowlist("bridge.internal, 10.0.0.5");
+        assert!(list.allows("bridge.internal"));
+        assert!(list.allows("10.0.0.5"));
+        assert!(!list.allows("bridge.internal.evil.com"));
+        assert!(!list.allows("evil-bridge.internal"));
+        assert!(!list.allows("10.0.0.50"));
+    }
+
+    #[test]
+    fn wildcards() {
+        let list = allowlist("*.internal,lima-*-sandbox,10.0.0.?");
+        assert!(list.allows("bridge.internal"));
+        assert!(list.allows("a.b.internal"));
+        assert!(!list.allows("internal"));
+        assert!(list.allows("lima-mac-sandbox"));
+        assert!(list.allows("lima--sandbox"));
+        assert!(!list.allows("lima-mac-sandbox.evil.com"));
+        assert!(list.allows("10.0.0.7"));
+        assert!(!list.allows("10.0.0.70"));
+        assert!(allowlist("*").allows("anything.at.all"));
+    }
+
+    #[test]
+    fn case_is_ignored_on_both_sides() {
+        let list = allowlist("Bridge.INTERNAL,*.Corp");
+        assert!(list.allows("bridge.internal"));
+        assert!(list.allows("BRIDGE.internal"));
+        assert!(list.allows("build.CORP"));
+    }
+
+    #[test]
+    fn only_the_host_is_matched_not_the_port() {
+        // Hosts are checked without their port: any port on an allowed host
+        // is allowed, and a pattern with a port matches no host
+        let list = allowlist("bridge.internal");
+        assert!(list.allows("bridge.internal"));
+        assert!(!list.allows("bridge.internal:8647"));
+        assert!(!allowlist("bridge.internal:8647").allows("bridge.internal"));
+    }
+
+    #[test]
+    fn ipv6_is_matched_without_brackets() {
+        let list = allowlist("::1,fd00::*");
+        assert!(list.allows("::1"));
+        assert!(list.allows("[::1]"));
+        assert!(list.allows("fd00::abcd"));
+        assert!(!list.allows("fe80::1"));
+    }
+
+    #[test]
+    fn an_empty_list_is_an_error() {
+        assert!(HostAllowlist::parse(" , ,").is_err());
+    }
+
+    #[test]
+    fn refusals_name_the_host_and_the_list() {
+        let list = allowlist("a.internal, b.internal");
+        assert_eq!(list.check("a.internal"), Ok(()));
+        assert_eq!(
+            list.check("evil.com"),
+            Err(
+                "Bridge host \"evil.com\" is not in OOK_ALLOWED_HOSTS (a.internal, b.internal)"
+                    .to_string()
+            )
+        );
+    }
+}
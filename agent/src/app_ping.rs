@@ -0,0 +1,109 @@
+//! Application-level keepalive (`OOK_APP_PING_SECS`).
+//!
+//! WebSocket pongs prove the connection is up, but a proxy in front of the
+//! bridge can answer them while the bridge itself is hung. This sends a
+//! real JSON-RPC `ping` request through the bridge every interval and
+//! expects its response, result or error alike, before the next one is
+//! due; otherwise the connection is treated as dead.
+//!
+//! Our ids are strings starting with `ID_PREFIX`, a range Zed's numeric
+//! ids can't collide with. Responses carrying one are consumed here and
+//! never reach Zed, even late ones from an earlier connection.
+
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::debug;
+
+pub const ID_PREFIX: &str = "ook-ping-";
+
+pub struct AppPing {
+    interval: Duration,
+    /// Number for the next ping's id; unique across connections.
+    next_id: u64,
+    /// The ping awaiting its response, and when it was sent.
+    outstanding: Option<(String, Instant)>,
+    due: Instant,
+}
+
+impl AppPing {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_id: 1,
+            outstanding: None,
+            due: Instant::now() + interval,
+        }
+    }
+
+    /// Start over for a new connection.
+    pub fn reset(&mut self) {
+        self.outstanding = None;
+        self.due = Instant::now() + self.interval;
+    }
+
+    /// When `ping` should next be called.
+    pub fn due(&self) -> Instant {
+        self.due
+    }
+
+    /// The next ping request to send, or `None` if the last one is still
+    /// unanswered.
+    pub fn ping(&mut self) -> Option<String> {
+        if self.outstanding.is_some() {
+            return None;
+        }
+        let id = format!("{}{}", ID_PREFIX, self.next_id);
+        self.next_id += 1;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "ping",
+        })
+        .to_string();
+        self.outstanding = Some((id, Instant::now()));
+        self.due = Instant::now() + self.interval;
+        Some(request)
+    }
+
+    /// Whether `message` from the bridge is the response to one of our
+    /// pings, in which case it is consumed and must not be forwarded.
+    pub fn answer(&mut self, message: &[u8]) -> bool {
+        // Cheap pre-check: nearly every message is Zed's
+        let Some(text) = std::str::from_utf8(message)
+            .ok()
+            .filter(|text| text.contains(ID_PREFIX))
+        else {
+            return false;
+        };
+        let Ok(value) = serde_json::from_str::<Value>(text) else {
+            return false;
+        };
+        let Some(id) = value
+            .get("id")
+            .and_then(Value::as_str)
+            .filter(|id| id.starts_with(ID_PREFIX))
+        else {
+            return false;
+        };
+        if value.get("method").is_some() {
+            return false;
+        }
+        match &self.outstanding {
+            Some((expected, sent_at)) if expected == id => {
+                let rtt_ms = sent_at.elapsed().as_secs_f64() * 1000.0;
+                debug!(id, rtt_ms, "Received app-level ping response");
+                self.outstanding = None;
+            }
+            _ => debug!(id, "Dropping stale app-level ping response"),
+        }
+        true
+    }
+
+    /// How long the outstanding ping has waited, for the error log.
+    pub fn waited(&self) -> Option<Duration> {
+        self.outstanding
+            .as_ref()
+            .map(|(_, sent_at)| sent_at.elapsed())
+    }
+}
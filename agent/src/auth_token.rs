@@ -0,0 +1,238 @@
+//! Where the bridge auth token comes from: `OOK_AUTH_TOKEN` (or the
+//! `auth_token` config key), the file named by `OOK_AUTH_TOKEN_FILE`, or
+//! the stdout of `OOK_AUTH_TOKEN_CMD`.
+//!
+//! The file and command keep the secret out of the environment, process
+//! listings, and shell history. Both are read again for every connect, so
+//! a short-lived token from the command is fresh on each reconnect. The
+//! command runs through the shell with its stderr going to ours, and is
+//! killed if it outlives the connect timeout; while it runs on a reconnect,
+//! stdin keeps being buffered. Trailing whitespace is trimmed, and an empty
+//! token is an error.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// The token to send, if any. Only one source may be set; the command may
+/// take up to `timeout`.
+pub async fn resolve(
+    configured: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<Option<String>, String> {
+    let file = env::var_os("OOK_AUTH_TOKEN_FILE").map(PathBuf::from);
+    let command = env::var("OOK_AUTH_TOKEN_CMD").ok();
+    from_sources(configured, file, command, timeout).await
+}
+
+async fn from_sources(
+    configured: Option<&str>,
+    file: Option<PathBuf>,
+    command: Option<String>,
+    timeout: Option<Duration>,
+) -> Result<Option<String>, String> {
+    match (configured, file, command) {
+        (None, None, None) => Ok(None),
+        (Some(token), None, None) => Ok(Some(token.to_string())),
+        (None, Some(path), None) => {
+            let text = tokio::fs::read_to_string(&path).await.map_err(|e| {
+                format!("Failed to read OOK_AUTH_TOKEN_FILE {}: {}", path.display(), e)
+            })?;
+            non_empty(&text, || format!("OOK_AUTH_TOKEN_FILE {}", path.display()))
+        }
+        (None, None, Some(command)) => match timeout {
+            Some(limit) => tokio::time::timeout(limit, run(&command))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(format!(
+                        "OOK_AUTH_TOKEN_CMD took over {}s (OOK_CONNECT_TIMEOUT_SECS)",
+                        limit.as_secs()
+                    ))
+                }),
+            None => run(&command).await,
+        },
+        _ => Err(
+            "Only one of OOK_AUTH_TOKEN (or auth_token), OOK_AUTH_TOKEN_FILE, and OOK_AUTH_TOKEN_CMD may be set"
+                .to_string(),
+        ),
+    }
+}
+
+/// Killed if dropped before it exits, as on a timeout or shutdown; on Unix
+/// along with anything it started, which runs in its process group.
+async fn run(command: &str) -> Result<Option<String>, String> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    #[cfg(unix)]
+    shell.process_group(0);
+    let child = shell
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to run OOK_AUTH_TOKEN_CMD: {}", e))?;
+    #[cfg(unix)]
+    let mut group = ProcessGroup(child.id());
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("Failed to run OOK_AUTH_TOKEN_CMD: {}", e))?;
+    #[cfg(unix)]
+    group.0.take();
+    if !output.status.success() {
+        return Err(format!("OOK_AUTH_TOKEN_CMD failed ({})", output.status));
+    }
+    let text = String::from_utf8(output.stdout)
+        .map_err(|_| "OOK_AUTH_TOKEN_CMD printed a token that isn't UTF-8".to_string())?;
+    non_empty(&text, || "OOK_AUTH_TOKEN_CMD output".to_string())
+}
+
+/// Kills the group led by this pid when dropped, unless taken first.
+#[cfg(unix)]
+struct ProcessGroup(Option<u32>);
+
+#[cfg(unix)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0.and_then(|pid| libc::pid_t::try_from(pid).ok()) {
+            // The shell's own kill_on_drop doesn't reach its children
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+        }
+    }
+}
+
+fn non_empty(text: &str, source: impl FnOnce() -> String) -> Result<Option<String>, String> {
+    let token = text.trim_end();
+    if token.is_empty() {
+        return Err(format!("{} is empty", source()));
+    }
+    Ok(Some(token.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    const LIMIT: Option<Duration> = Some(Duration::from_secs(10));
+
+    fn command(script: &str) -> Option<String> {
+        Some(script.to_string())
+    }
+
+    #[tokio::test]
+    async fn no_source_means_no_token() {
+        assert_eq!(from_sources(None, None, None, LIMIT).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn configured_token_is_used_as_is() {
+        let token = from_sources(Some("abc "), None, None, LIMIT).await;
+        assert_eq!(token, Ok(Some("abc ".to_string())));
+    }
+
+    #[tokio::test]
+    async fn file_token_is_trimmed() {
+        let path = env::temp_dir().join(format!("ook-token-{}", std::process::id()));
+        std::fs::write(&path, "from-file\r\n").unwrap();
+        let token = from_sources(None, Some(path.clone()), None, LIMIT).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(token, Ok(Some("from-file".to_string())));
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_an_error() {
+        let path = PathBuf::from("/nonexistent/ook-token");
+        let error = from_sources(None, Some(path), None, LIMIT)
+            .await
+            .unwrap_err();
+        assert!(
+            error.starts_with("Failed to read OOK_AUTH_TOKEN_FILE"),
+            "{}",
+            error
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_token_is_its_trimmed_stdout() {
+        let token = from_sources(None, None, command("echo from-cmd"), LIMIT).await;
+        assert_eq!(token, Ok(Some("from-cmd".to_string())));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failing_or_silent_command_is_an_error() {
+        let failed = from_sources(None, None, command("exit 3"), LIMIT).await;
+        assert!(failed.unwrap_err().starts_with("OOK_AUTH_TOKEN_CMD failed"));
+        let silent = from_sources(None, None, command("true"), LIMIT).await;
+        assert_eq!(
+            silent,
+            Err("OOK_AUTH_TOKEN_CMD output is empty".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn slow_command_is_cut_off_at_the_timeout() {
+        let started = Instant::now();
+        let limit = Some(Duration::from_millis(100));
+        let error = from_sources(None, None, command("sleep 30"), limit)
+            .await
+            .unwrap_err();
+        assert!(
+            error.starts_with("OOK_AUTH_TOKEN_CMD took over"),
+            "{}",
+            error
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// Linux, to tell a killed process still waiting to be reaped from a
+    /// running one.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn timed_out_command_takes_its_children_with_it() {
+        let pid_file = env::temp_dir().join(format!("ook-token-child-{}", std::process::id()));
+        let script = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let limit = Some(Duration::from_millis(500));
+        from_sources(None, None, command(&script), limit)
+            .await
+            .unwrap_err();
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+        let stat = PathBuf::from(format!("/proc/{}/stat", pid.trim()));
+        for _ in 0..50 {
+            match std::fs::read_to_string(&stat) {
+                Err(_) => return,
+                Ok(line) if line.rsplit_once(") ").unwrap().1.starts_with('Z') => return,
+                Ok(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+        panic!("sleep {} outlived the token command", pid.trim());
+    }
+
+    #[tokio::test]
+    async fn more_than_one_source_is_an_error() {
+        let file = Some(PathBuf::from("/nonexistent/ook-token"));
+        let cases = [
+            from_sources(Some("a"), file.clone(), None, LIMIT).await,
+            from_sources(Some("a"), None, command("echo b"), LIMIT).await,
+            from_sources(None, file, command("echo b"), LIMIT).await,
+        ];
+        for case in cases {
+            assert!(case.unwrap_err().starts_with("Only one of"));
+        }
+    }
+}
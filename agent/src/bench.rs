@@ -0,0 +1,70 @@
+//! A whole proxy session for `benches/`, which only see the public API:
+//! the agent on the default config, over an in-memory bridge connection
+//! (`transport::memory`). Stdio is a pair of OS pipes on Unix, as Zed's
+//! is, so every stdout flush costs the write it does in production; it is
+//! in memory elsewhere.
+
+use crate::config::Config;
+use crate::signal::ReconnectSignal;
+use crate::transport::memory;
+use crate::Mode;
+use std::future::Future;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+
+pub use crate::flush::FlushPolicy;
+pub use crate::transport::memory::Peer;
+
+#[cfg(unix)]
+pub type PipeWriter = tokio::net::unix::pipe::Sender;
+#[cfg(unix)]
+pub type PipeReader = tokio::net::unix::pipe::Receiver;
+#[cfg(not(unix))]
+pub type PipeWriter = tokio::io::DuplexStream;
+#[cfg(not(unix))]
+pub type PipeReader = tokio::io::DuplexStream;
+
+/// Zed's ends of the agent's stdio, and the bridge's end of its connection.
+pub struct Ends {
+    pub stdin: PipeWriter,
+    pub stdout: Lines<BufReader<PipeReader>>,
+    pub bridge: Peer,
+}
+
+/// Run one session under `flush_policy` while `drive` plays Zed and the
+/// bridge. The session ends when `drive` drops the `Ends`, closing stdin, so
+/// `drive` must hold on to all of it: an `async move` block that never
+/// touches `stdin` drops it (and ends the session) straight away.
+pub async fn session<F>(flush_policy: FlushPolicy, drive: impl FnOnce(Ends) -> F) -> F::Output
+where
+    F: Future,
+{
+    let (stdin, agent_stdin) = pipe();
+    let (agent_stdout, stdout) = pipe();
+    let mut agent = crate::build_agent(&Config::default(), &Mode::Proxy, agent_stdin, agent_stdout)
+        .await
+        .expect("agent from the default config");
+    agent.zed.flush_policy = flush_policy;
+    let mut hangup = ReconnectSignal::install().expect("SIGHUP handler");
+    let (transport, bridge) = memory::pair();
+    let ends = Ends {
+        stdin,
+        stdout: BufReader::new(stdout).lines(),
+        bridge,
+    };
+    let (_, output) = tokio::join!(
+        crate::run_session(transport, &mut agent, &mut hangup),
+        drive(ends)
+    );
+    output
+}
+
+#[cfg(unix)]
+fn pipe() -> (PipeWriter, PipeReader) {
+    tokio::net::unix::pipe::pipe().expect("pipe")
+}
+
+/// As much as a Linux pipe holds.
+#[cfg(not(unix))]
+fn pipe() -> (PipeWriter, PipeReader) {
+    tokio::io::duplex(64 * 1024)
+}
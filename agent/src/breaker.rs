@@ -0,0 +1,63 @@
+//! Reconnect circuit breaker (`OOK_RECONNECT_COOLDOWN_AFTER`).
+//!
+//! During a long outage the normal backoff settles at its 30s cap and logs
+//! every attempt. After enough consecutive failures the breaker trips: the
+//! wait becomes the longer cooldown and attempts are only logged at debug
+//! level, with a single warning when it trips.
+
+use std::time::Duration;
+
+pub struct ReconnectBreaker {
+    /// Consecutive failures that trip the breaker; `None` disables it.
+    threshold: Option<u32>,
+    cooldown: Duration,
+    /// Failed attempts since a connection last carried traffic.
+    failures: u32,
+    /// Messages forwarded so far when the last connection opened.
+    messages_at_connect: u64,
+}
+
+impl ReconnectBreaker {
+    pub fn new(threshold: Option<u32>, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            failures: 0,
+            messages_at_connect: 0,
+        }
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// Whether attempts are on the cooldown schedule.
+    pub fn tripped(&self) -> bool {
+        self.threshold.is_some_and(|n| self.failures >= n)
+    }
+
+    /// A connection opened; `messages` is the running total forwarded.
+    pub fn connected(&mut self, messages: u64) {
+        self.messages_at_connect = messages;
+    }
+
+    /// The connection dropped. Only one that carried traffic resets the
+    /// count, so a bridge that accepts and drops at once still trips it.
+    pub fn disconnected(&mut self, messages: u64) {
+        if messages > self.messages_at_connect {
+            self.failures = 0;
+        }
+    }
+
+    /// Count a failed attempt. Returns `true` if this one tripped the
+    /// breaker.
+    pub fn failed(&mut self) -> bool {
+        let was_tripped = self.tripped();
+        self.failures += 1;
+        !was_tripped && self.tripped()
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
@@ -0,0 +1,1044 @@
+//! Opening the WebSocket to the Ook Bridge.
+//!
+//! Each connect runs the transport, TLS, and upgrade steps explicitly over a
+//! boxed byte stream so every transport yields the same `WsStream` type.
+//!
+//! A connect attempt runs in a `connect` span, with one child span per step
+//! (`dns`, `tcp`, `tls`, `ws_upgrade`, or `uds`/`pipe` for local
+//! transports). Span close events carry each step's duration.
+
+use crate::allowlist::HostAllowlist;
+use crate::logging;
+use crate::proxy::Proxy;
+use futures_util::{SinkExt, StreamExt};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::{ProtocolError, SubProtocolError, TlsError};
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::http::header::{
+    AUTHORIZATION, LOCATION, SEC_WEBSOCKET_PROTOCOL, SERVER, SET_COOKIE,
+};
+use tokio_tungstenite::tungstenite::http::{HeaderMap, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{client_async_with_config, WebSocketStream};
+use tracing::{debug, debug_span, info, info_span, warn, Instrument};
+use url::Url;
+
+/// Redirects one connect follows with `OOK_FOLLOW_REDIRECTS=1`.
+const MAX_REDIRECTS: usize = 3;
+
+/// A byte stream the WebSocket can run over.
+pub trait BridgeIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> BridgeIo for T {}
+
+pub type WsStream = WebSocketStream<Box<dyn BridgeIo>>;
+
+/// Where the bridge listens.
+pub enum Endpoint {
+    Tcp {
+        host: String,
+        port: u16,
+        /// OS-level keepalive on the socket (`OOK_TCP_KEEPALIVE_SECS`).
+        keepalive: Option<TcpKeepalive>,
+        /// Local address to connect from (`OOK_BIND_ADDR`); also used for
+        /// the connection to a proxy.
+        bind: Option<IpAddr>,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// Windows named pipe, e.g. `\\.\pipe\ook-bridge`.
+    #[cfg(windows)]
+    Pipe(String),
+}
+
+/// TCP keepalive probing: after `idle` without traffic, probe every
+/// `interval` and give up on the peer after `retries` unanswered probes.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpKeepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+impl TcpKeepalive {
+    /// Turn on `SO_KEEPALIVE` for `tcp`, before anything is sent on it.
+    fn apply(&self, tcp: &TcpStream) -> io::Result<()> {
+        let params = socket2::TcpKeepalive::new()
+            .with_time(self.idle)
+            .with_interval(self.interval)
+            .with_retries(self.retries);
+        socket2::SockRef::from(tcp).set_tcp_keepalive(&params)
+    }
+}
+
+/// Everything needed to (re)open the WebSocket to the bridge.
+pub struct BridgeTarget {
+    pub endpoint: Endpoint,
+    /// Request URL for the upgrade; for Unix sockets only the path matters.
+    pub url: String,
+    /// Added to the upgrade request on every (re)connect.
+    pub headers: HeaderMap,
+    /// Set for `wss`; wraps the transport before the upgrade.
+    pub tls: Option<native_tls::TlsConnector>,
+    /// Message and frame size limits for the connection.
+    pub ws_config: WebSocketConfig,
+    /// HTTP CONNECT proxy for TCP endpoints, unless it bypasses the host
+    /// (`NO_PROXY`); the bridge host is then resolved by the proxy, not
+    /// locally.
+    pub proxy: Option<Proxy>,
+    /// Limit on the whole connect (transport, proxy, TLS, and upgrade);
+    /// `None` waits as long as the OS does.
+    pub connect_timeout: Option<Duration>,
+    /// Follow HTTP redirects of the upgrade request (`OOK_FOLLOW_REDIRECTS`);
+    /// TCP endpoints only, and never from `wss` to `ws`.
+    pub follow_redirects: bool,
+    /// Checked again for redirect targets (`OOK_ALLOWED_HOSTS`).
+    pub allowed_hosts: Option<HostAllowlist>,
+    /// Log every upgrade response header (`OOK_LOG_HANDSHAKE`).
+    pub log_handshake: bool,
+}
+
+impl BridgeTarget {
+    /// Human-readable description of the endpoint for logs.
+    pub fn describe(&self) -> String {
+        match &self.endpoint {
+            Endpoint::Tcp { .. } => self.url.clone(),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => format!("unix:{}", path.display()),
+            #[cfg(windows)]
+            Endpoint::Pipe(name) => format!("pipe:{}", name),
+        }
+    }
+
+    /// `host:port`, or the socket path or pipe name, for `target_host` in
+    /// logs.
+    pub fn target_host(&self) -> String {
+        match &self.endpoint {
+            Endpoint::Tcp { host, port, .. } => format!("{}:{}", host, port),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => path.display().to_string(),
+            #[cfg(windows)]
+            Endpoint::Pipe(name) => name.clone(),
+        }
+    }
+
+    /// The proxy the connection goes through, if any: TCP only, and not for
+    /// a host the proxy bypasses.
+    pub fn proxy(&self) -> Option<&Proxy> {
+        match &self.endpoint {
+            Endpoint::Tcp { host, .. } => self.proxy.as_ref().filter(|proxy| proxy.covers(host)),
+            #[cfg(any(unix, windows))]
+            _ => None,
+        }
+    }
+
+    pub async fn connect(&self) -> Result<WsStream, WsError> {
+        let attempt = async {
+            let Some(limit) = self.connect_timeout else {
+                return self.open().await;
+            };
+            match tokio::time::timeout(limit, self.open()).await {
+                Ok(result) => result,
+                Err(_) => Err(WsError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connection timed out after {}s", limit.as_secs()),
+                ))),
+            }
+        };
+        attempt
+            .instrument(info_span!("connect", url = %self.describe()))
+            .await
+    }
+
+    /// `open_once`, following redirects if allowed. A redirect from `wss`
+    /// to `ws` is always refused: it would drop TLS.
+    async fn open(&self) -> Result<WsStream, WsError> {
+        let mut redirected: Option<BridgeTarget> = None;
+        for followed in 0.. {
+            let target = redirected.as_ref().unwrap_or(self);
+            match target.open_once().await {
+                Err(WsError::Http(response)) if response.status().is_redirection() => {
+                    let next = target.redirect(&response).map_err(redirect_error)?;
+                    if followed == MAX_REDIRECTS {
+                        return Err(redirect_error(format!(
+                            "Ook Bridge redirected the handshake more than {} times",
+                            MAX_REDIRECTS
+                        )));
+                    }
+                    if !self.follow_redirects {
+                        return Err(redirect_error(format!(
+                            "Ook Bridge redirected the handshake (HTTP {}) to {}; \
+                             update the bridge URL or set OOK_FOLLOW_REDIRECTS=1",
+                            response.status(),
+                            next.url
+                        )));
+                    }
+                    info!(
+                        status = response.status().as_u16(),
+                        from = %target.url,
+                        to = %next.url,
+                        "Following Ook Bridge redirect"
+                    );
+                    redirected = Some(next);
+                }
+                result => return result,
+            }
+        }
+        unreachable!("the redirect loop only ends by returning")
+    }
+
+    /// Where a redirect `response` to our upgrade request points, as a
+    /// target to connect to next, or why it can't be followed.
+    fn redirect(&self, response: &Response) -> Result<BridgeTarget, String> {
+        let status = response.status();
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| format!("Ook Bridge sent HTTP {} without a usable Location", status))?;
+        let mut url = Url::parse(&self.url)
+            .and_then(|base| base.join(location))
+            .map_err(|e| {
+                format!(
+                    "Ook Bridge redirected to an invalid URL {:?}: {}",
+                    location, e
+                )
+            })?;
+        let secure = match url.scheme() {
+            "ws" | "http" => false,
+            "wss" | "https" => true,
+            other => {
+                return Err(format!(
+                    "Ook Bridge redirected to unsupported scheme {:?} ({})",
+                    other, url
+                ))
+            }
+        };
+        if self.tls.is_some() && !secure {
+            return Err(format!(
+                "Refusing Ook Bridge redirect (HTTP {}) from wss to ws ({}): it would drop TLS",
+                status, url
+            ));
+        }
+        let _ = url.set_scheme(if secure { "wss" } else { "ws" });
+
+        let Endpoint::Tcp {
+            host: old_host,
+            port: old_port,
+            keepalive,
+            bind,
+        } = &self.endpoint
+        else {
+            return Err(format!(
+                "Ook Bridge redirected to {}, which can't be followed over a local socket",
+                url
+            ));
+        };
+        let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+            return Err(format!("Ook Bridge redirected to {} without a host", url));
+        };
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        if let Some(allowlist) = &self.allowed_hosts {
+            allowlist.check(&host)?;
+        }
+
+        // The token is for the bridge we were configured with, not wherever
+        // it sends us; another port on the same host may be another service
+        let mut headers = self.headers.clone();
+        let same_bridge = host.eq_ignore_ascii_case(old_host) && port == *old_port;
+        if !same_bridge && headers.remove(AUTHORIZATION).is_some() {
+            warn!(host = %host, "Not sending OOK_AUTH_TOKEN to the redirected host");
+        }
+        let tls = match (&self.tls, secure) {
+            (Some(tls), true) => Some(tls.clone()),
+            (None, true) => Some(
+                tls_connector(false)
+                    .map_err(|e| format!("Failed to set up TLS for redirect to {}: {}", url, e))?,
+            ),
+            (_, false) => None,
+        };
+        Ok(BridgeTarget {
+            endpoint: Endpoint::Tcp {
+                host,
+                port,
+                keepalive: *keepalive,
+                bind: *bind,
+            },
+            url: url.to_string(),
+            headers,
+            tls,
+            ws_config: self.ws_config,
+            proxy: self.proxy.clone(),
+            connect_timeout: self.connect_timeout,
+            follow_redirects: self.follow_redirects,
+            allowed_hosts: self.allowed_hosts.clone(),
+            log_handshake: self.log_handshake,
+        })
+    }
+
+    async fn open_once(&self) -> Result<WsStream, WsError> {
+        let mut request = self.url.as_str().into_client_request()?;
+        request.headers_mut().extend(self.headers.clone());
+
+        let stream: Box<dyn BridgeIo> = match &self.endpoint {
+            Endpoint::Tcp {
+                host,
+                port,
+                keepalive,
+                bind,
+            } => {
+                let tcp = match self.proxy() {
+                    Some(proxy) => {
+                        let addrs = resolve(&proxy.host, proxy.port)
+                            .instrument(debug_span!("dns", host = %proxy.host))
+                            .await?;
+                        // The tunnel is part of reaching the bridge over TCP
+                        async {
+                            let mut tcp = connect_tcp(&addrs, *bind).await.map_err(|e| {
+                                if is_bind_error(&e) {
+                                    return e;
+                                }
+                                let context = format!(
+                                    "failed to connect to proxy {}: {}",
+                                    proxy.describe(),
+                                    e
+                                );
+                                io::Error::new(e.kind(), context)
+                            })?;
+                            proxy.tunnel(&mut tcp, host, *port).await?;
+                            Ok::<_, io::Error>(tcp)
+                        }
+                        .instrument(debug_span!("tcp", proxy = %proxy.describe()))
+                        .await?
+                    }
+                    None => {
+                        let addrs = resolve(host, *port)
+                            .instrument(debug_span!("dns", host = %host))
+                            .await?;
+                        connect_tcp(&addrs, *bind)
+                            .instrument(debug_span!("tcp"))
+                            .await?
+                    }
+                };
+                if let Some(keepalive) = keepalive {
+                    // Dead peers are then still caught by the WebSocket pings
+                    if let Err(e) = keepalive.apply(&tcp) {
+                        warn!(error = %e, "Failed to enable TCP keepalive");
+                    }
+                }
+                match &self.tls {
+                    Some(tls) => {
+                        let tls = tokio_native_tls::TlsConnector::from(tls.clone());
+                        let stream = tls
+                            .connect(host, tcp)
+                            .instrument(debug_span!("tls"))
+                            .await
+                            .map_err(|e| WsError::Tls(TlsError::Native(e)))?;
+                        Box::new(stream)
+                    }
+                    None => Box::new(tcp),
+                }
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Box::new(
+                tokio::net::UnixStream::connect(path)
+                    .instrument(debug_span!("uds"))
+                    .await?,
+            ),
+            #[cfg(windows)]
+            Endpoint::Pipe(name) => {
+                Box::new(open_pipe(name).instrument(debug_span!("pipe")).await?)
+            }
+        };
+
+        // tungstenite fails the handshake if the selection doesn't match
+        // what we offered (`OOK_SUBPROTOCOL`)
+        let (ws_stream, response) = client_async_with_config(request, stream, Some(self.ws_config))
+            .instrument(debug_span!("ws_upgrade"))
+            .await
+            .inspect_err(|e| {
+                if let WsError::Http(response) = e {
+                    self.log_response(response);
+                }
+            })?;
+        self.log_response(&response);
+        if let Some(selected) = response.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+            info!(
+                subprotocol = %String::from_utf8_lossy(selected.as_bytes()),
+                "Bridge selected WebSocket subprotocol"
+            );
+        }
+        Ok(ws_stream)
+    }
+
+    /// The upgrade response, at debug level: the status and headers that
+    /// say who answered and how. With `OOK_LOG_HANDSHAKE=1`, every header
+    /// at info level instead, cookie values masked.
+    fn log_response(&self, response: &Response) {
+        let headers = response.headers();
+        let text = |value: &HeaderValue| String::from_utf8_lossy(value.as_bytes()).into_owned();
+        if self.log_handshake {
+            let all: Vec<String> = headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = if name == SET_COOKIE {
+                        "redacted".to_string()
+                    } else {
+                        text(value)
+                    };
+                    format!("{}: {}", name, value)
+                })
+                .collect();
+            info!(
+                status = response.status().as_u16(),
+                headers = ?all,
+                "Handshake response"
+            );
+            return;
+        }
+        let ook: Vec<String> = headers
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("x-ook-"))
+            .map(|(name, value)| format!("{}: {}", name, text(value)))
+            .collect();
+        debug!(
+            status = response.status().as_u16(),
+            subprotocol = headers.get(SEC_WEBSOCKET_PROTOCOL).map(text),
+            server = headers.get(SERVER).map(text),
+            ook_headers = ?ook,
+            "Handshake response"
+        );
+    }
+
+    /// Connect, ping, and wait up to `timeout` for the pong. Returns the
+    /// round-trip time, or a diagnostic describing what failed.
+    pub async fn check(&self, timeout: Duration) -> Result<Duration, String> {
+        let mut ws_stream = self
+            .connect()
+            .await
+            .map_err(|e| format!("handshake failed: {}", describe_connect_error(&e)))?;
+
+        let sent_at = Instant::now();
+        ws_stream
+            .send(Message::Ping(Vec::new()))
+            .await
+            .map_err(|e| format!("failed to send ping: {}", e))?;
+
+        let pong = tokio::time::timeout(timeout, async {
+            while let Some(msg) = ws_stream.next().await {
+                match msg {
+                    Ok(Message::Pong(_)) => return Ok(()),
+                    Ok(Message::Close(frame)) => {
+                        return Err(format!("bridge closed the connection: {:?}", frame))
+                    }
+                    Ok(_) => {}
+                    Err(e) => return Err(format!("WebSocket error: {}", e)),
+                }
+            }
+            Err("connection ended before pong".to_string())
+        })
+        .await
+        .map_err(|_| format!("no pong within {}s", timeout.as_secs()))?;
+        let rtt = sent_at.elapsed();
+
+        let _ = ws_stream.close(None).await;
+        pong.map(|()| rtt)
+    }
+}
+
+/// Host name lookup failure, kept distinct from connect errors so callers
+/// can tell "VM not up yet" from "bridge not listening".
+#[derive(Debug)]
+struct ResolveError {
+    host: String,
+    source: io::Error,
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to resolve {}: {}", self.host, self.source)
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// The local socket couldn't be bound to `OOK_BIND_ADDR`, kept distinct
+/// from connect errors: the fix is in the local configuration.
+#[derive(Debug)]
+struct BindError {
+    addr: IpAddr,
+    source: io::Error,
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to bind to local address {}: {}",
+            self.addr, self.source
+        )
+    }
+}
+
+impl std::error::Error for BindError {}
+
+fn is_bind_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<BindError>())
+}
+
+/// Connect to the first of `addrs` that answers, from `bind` if set. A
+/// bound socket only tries addresses of its own family.
+async fn connect_tcp(addrs: &[SocketAddr], bind: Option<IpAddr>) -> io::Result<TcpStream> {
+    let Some(bind) = bind else {
+        return TcpStream::connect(addrs).await;
+    };
+    let to_error =
+        |source: io::Error| io::Error::new(source.kind(), BindError { addr: bind, source });
+    let mut last_error = None;
+    for addr in addrs.iter().filter(|addr| addr.is_ipv4() == bind.is_ipv4()) {
+        let socket = match bind {
+            IpAddr::V4(_) => TcpSocket::new_v4(),
+            IpAddr::V6(_) => TcpSocket::new_v6(),
+        }?;
+        socket.bind(SocketAddr::new(bind, 0)).map_err(to_error)?;
+        match socket.connect(*addr).await {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        let family = if bind.is_ipv4() { "IPv4" } else { "IPv6" };
+        to_error(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no {} address to connect to", family),
+        ))
+    }))
+}
+
+/// A handshake redirect that isn't followed; its message is the whole
+/// explanation.
+#[derive(Debug)]
+struct RedirectError(String);
+
+impl fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RedirectError {}
+
+fn redirect_error(message: String) -> WsError {
+    WsError::Io(io::Error::other(RedirectError(message)))
+}
+
+async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let to_error = |source: io::Error| {
+        let resolve_error = ResolveError {
+            host: host.to_string(),
+            source,
+        };
+        io::Error::new(io::ErrorKind::NotFound, resolve_error)
+    };
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(to_error)?
+        .collect();
+    if addrs.is_empty() {
+        return Err(to_error(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no addresses found",
+        )));
+    }
+    Ok(addrs)
+}
+
+/// Open a named pipe client, waiting while every server instance is busy
+/// (the connect timeout bounds the wait).
+#[cfg(windows)]
+async fn open_pipe(name: &str) -> io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    loop {
+        match ClientOptions::new().open(name) {
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+            result => return result,
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Broad cause of a failed connect, for logs and retry decisions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureClass {
+    /// The bridge host name did not resolve (e.g. the Lima VM is still booting).
+    NameResolution,
+    /// The local socket couldn't be bound to `OOK_BIND_ADDR`.
+    Bind,
+    /// The host is up but nothing is listening on the port.
+    ConnectionRefused,
+    /// No answer within `OOK_CONNECT_TIMEOUT_SECS` (e.g. a filtered port).
+    TimedOut,
+    /// The bridge refused the handshake over its `Origin` header.
+    OriginRejected,
+    Other,
+}
+
+impl FailureClass {
+    pub fn of(e: &WsError) -> Self {
+        match e {
+            WsError::Io(io) if io.get_ref().is_some_and(|inner| inner.is::<ResolveError>()) => {
+                Self::NameResolution
+            }
+            WsError::Io(io) if is_bind_error(io) => Self::Bind,
+            WsError::Io(io) if io.kind() == io::ErrorKind::ConnectionRefused => {
+                Self::ConnectionRefused
+            }
+            WsError::Io(io) if io.kind() == io::ErrorKind::TimedOut => Self::TimedOut,
+            _ if is_origin_rejection(e) => Self::OriginRejected,
+            _ => Self::Other,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NameResolution => "name resolution",
+            Self::Bind => "bind failed",
+            Self::ConnectionRefused => "connection refused",
+            Self::TimedOut => "connection timed out",
+            Self::OriginRejected => "origin rejected",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Whether the bridge refused the handshake for our credentials (HTTP
+/// 401/403) rather than failing some other way.
+pub fn is_auth_rejection(e: &WsError) -> bool {
+    matches!(
+        e,
+        WsError::Http(response)
+            if matches!(response.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+    )
+}
+
+/// Whether the bridge refused the handshake over its `Origin` header: an
+/// HTTP 403 whose body mentions the origin. A bare 403 can't be told apart
+/// from a rejected token and counts as `is_auth_rejection`.
+pub fn is_origin_rejection(e: &WsError) -> bool {
+    let WsError::Http(response) = e else {
+        return false;
+    };
+    response.status() == StatusCode::FORBIDDEN
+        && response.body().as_ref().is_some_and(|body| {
+            String::from_utf8_lossy(body)
+                .to_ascii_lowercase()
+                .contains("origin")
+        })
+}
+
+/// Cap incoming messages and frames at `max_bytes`; tungstenite's defaults
+/// (64 MiB / 16 MiB) are otherwise implicit.
+pub fn ws_config(max_bytes: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_bytes),
+        max_frame_size: Some(max_bytes),
+        ..Default::default()
+    }
+}
+
+/// Candidate bridges, tried in order until one connects.
+///
+/// Each attempt starts from the last candidate that worked, so a reconnect
+/// sticks with a healthy host instead of going back to a dead primary.
+pub struct Bridges {
+    candidates: Vec<BridgeTarget>,
+    current: usize,
+    port_file: bool,
+}
+
+impl Bridges {
+    /// `candidates` must not be empty.
+    pub fn new(candidates: Vec<BridgeTarget>) -> Self {
+        assert!(!candidates.is_empty(), "no bridge candidates");
+        Self {
+            candidates,
+            current: 0,
+            port_file: false,
+        }
+    }
+
+    /// Mark the TCP port as the bridge's port file's to decide, so that
+    /// reconnects read it again (`set_port`).
+    pub fn with_port_file(mut self) -> Self {
+        self.port_file = true;
+        self
+    }
+
+    pub fn port_file(&self) -> bool {
+        self.port_file
+    }
+
+    pub fn candidates(&self) -> &[BridgeTarget] {
+        &self.candidates
+    }
+
+    /// Every candidate, for logs.
+    pub fn describe(&self) -> String {
+        self.candidates
+            .iter()
+            .map(BridgeTarget::describe)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Replace every candidate's `Authorization` header (`None` removes
+    /// it). Returns whether it changed.
+    pub fn set_authorization(&mut self, value: Option<HeaderValue>) -> bool {
+        let mut changed = false;
+        for candidate in &mut self.candidates {
+            if candidate.headers.get(AUTHORIZATION) == value.as_ref() {
+                continue;
+            }
+            changed = true;
+            match &value {
+                Some(value) => candidate.headers.insert(AUTHORIZATION, value.clone()),
+                None => candidate.headers.remove(AUTHORIZATION),
+            };
+        }
+        changed
+    }
+
+    /// Point every TCP candidate at `port`, rewriting the port that ends its
+    /// URL. Returns whether it changed.
+    pub fn set_port(&mut self, port: u16) -> bool {
+        let mut changed = false;
+        for candidate in &mut self.candidates {
+            let Endpoint::Tcp { port: current, .. } = &mut candidate.endpoint else {
+                continue;
+            };
+            if *current == port {
+                continue;
+            }
+            changed = true;
+            *current = port;
+            // Built as `scheme://host:port`, so the last colon is the port's
+            if let Some((base, _)) = candidate.url.rsplit_once(':') {
+                candidate.url = format!("{}:{}", base, port);
+            }
+        }
+        changed
+    }
+
+    /// Try each candidate once, each with its own connect timeout. Returns
+    /// the last error if none connect.
+    pub async fn connect(&mut self) -> Result<WsStream, WsError> {
+        let count = self.candidates.len();
+        let mut last_error = None;
+        for offset in 0..count {
+            let index = (self.current + offset) % count;
+            let candidate = &self.candidates[index];
+            match candidate.connect().await {
+                Ok(ws_stream) => {
+                    if count > 1 {
+                        logging::set_target_host(&candidate.target_host());
+                        info!(url = %candidate.describe(), "Selected bridge host");
+                    }
+                    self.current = index;
+                    return Ok(ws_stream);
+                }
+                Err(e) => {
+                    if count > 1 {
+                        warn!(
+                            url = %candidate.describe(),
+                            failure_class = FailureClass::of(&e).as_str(),
+                            error = %describe_connect_error(&e),
+                            "Bridge host candidate failed"
+                        );
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("at least one candidate"))
+    }
+
+    /// `BridgeTarget::check` against each candidate in turn, returning the
+    /// first that answers and its round-trip time, or the last failure.
+    pub async fn check(&self, timeout: Duration) -> Result<(&BridgeTarget, Duration), String> {
+        let mut last_error = String::new();
+        for candidate in &self.candidates {
+            match candidate.check(timeout).await {
+                Ok(rtt) => return Ok((candidate, rtt)),
+                Err(e) => {
+                    last_error = if self.candidates.len() > 1 {
+                        format!("{} ({})", e, candidate.describe())
+                    } else {
+                        e
+                    };
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Verified TLS, or one that accepts any certificate for self-signed
+/// bridges during development (`OOK_TLS_INSECURE=1`).
+pub fn tls_connector(insecure: bool) -> Result<native_tls::TlsConnector, native_tls::Error> {
+    native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(insecure)
+        .danger_accept_invalid_hostnames(insecure)
+        .build()
+}
+
+/// Turn a handshake error into something a user can act on.
+pub fn describe_connect_error(e: &WsError) -> String {
+    match e {
+        WsError::Tls(tls) => {
+            let detail = tls.to_string();
+            if detail.contains("certificate") {
+                format!(
+                    "TLS certificate verification failed: {} \
+                     (set OOK_TLS_INSECURE=1 to accept self-signed certificates during development)",
+                    detail
+                )
+            } else {
+                format!("TLS handshake failed: {}", detail)
+            }
+        }
+        WsError::Io(io)
+            if io
+                .get_ref()
+                .is_some_and(|inner| inner.is::<RedirectError>()) =>
+        {
+            io.get_ref().map_or_else(String::new, ToString::to_string)
+        }
+        WsError::Io(io) if is_bind_error(io) => {
+            let detail = io.get_ref().map_or_else(String::new, ToString::to_string);
+            format!("{}; check OOK_BIND_ADDR", detail)
+        }
+        WsError::Http(response) if is_origin_rejection(e) => {
+            format!(
+                "Ook Bridge rejected the handshake's Origin (HTTP {}); check OOK_ORIGIN",
+                response.status()
+            )
+        }
+        WsError::Http(response) if is_auth_rejection(e) => {
+            format!(
+                "Ook Bridge rejected the auth token (HTTP {}); check OOK_AUTH_TOKEN",
+                response.status()
+            )
+        }
+        WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(e)) => {
+            let detail = match e {
+                SubProtocolError::NoSubProtocol => {
+                    "the bridge accepted none of the subprotocols in OOK_SUBPROTOCOL"
+                }
+                SubProtocolError::InvalidSubProtocol => {
+                    "the bridge selected a subprotocol that OOK_SUBPROTOCOL did not offer"
+                }
+                SubProtocolError::ServerSentSubProtocolNoneRequested => {
+                    "the bridge requires a subprotocol; set OOK_SUBPROTOCOL"
+                }
+            };
+            format!("Incompatible Ook Bridge: {}", detail)
+        }
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(host: &str, url: &str, port: u16) -> BridgeTarget {
+        BridgeTarget {
+            endpoint: Endpoint::Tcp {
+                host: host.to_string(),
+                port,
+                keepalive: None,
+                bind: None,
+            },
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            tls: None,
+            ws_config: WebSocketConfig::default(),
+            proxy: None,
+            connect_timeout: None,
+            follow_redirects: false,
+            allowed_hosts: None,
+            log_handshake: false,
+        }
+    }
+
+    #[test]
+    fn set_port_moves_every_candidate() {
+        let mut bridges = Bridges::new(vec![
+            tcp("a", "ws://a:8647", 8647),
+            tcp("::1", "wss://[::1]:8647", 8647),
+        ]);
+        assert!(bridges.set_port(9000));
+        let urls: Vec<_> = bridges.candidates().iter().map(|c| c.describe()).collect();
+        assert_eq!(urls, ["ws://a:9000", "wss://[::1]:9000"]);
+        assert_eq!(bridges.candidates()[1].target_host(), "::1:9000");
+        assert!(!bridges.set_port(9000));
+    }
+
+    fn http_error(status: StatusCode, body: Option<&str>) -> WsError {
+        let mut response = Response::new(body.map(|body| body.as_bytes().to_vec()));
+        *response.status_mut() = status;
+        WsError::Http(response)
+    }
+
+    #[test]
+    fn classifies_handshake_rejections() {
+        let bare = http_error(StatusCode::FORBIDDEN, None);
+        assert!(!is_origin_rejection(&bare));
+        assert!(is_auth_rejection(&bare));
+        assert_eq!(FailureClass::of(&bare), FailureClass::Other);
+        assert_eq!(
+            describe_connect_error(&bare),
+            "Ook Bridge rejected the auth token (HTTP 403 Forbidden); check OOK_AUTH_TOKEN"
+        );
+
+        let origin = http_error(StatusCode::FORBIDDEN, Some("Origin not allowed"));
+        assert!(is_origin_rejection(&origin));
+        assert_eq!(FailureClass::of(&origin), FailureClass::OriginRejected);
+        assert_eq!(
+            describe_connect_error(&origin),
+            "Ook Bridge rejected the handshake's Origin (HTTP 403 Forbidden); check OOK_ORIGIN"
+        );
+
+        let unauthorized = http_error(StatusCode::UNAUTHORIZED, Some("bad origin or token"));
+        assert!(!is_origin_rejection(&unauthorized));
+        assert!(is_auth_rejection(&unauthorized));
+        assert_eq!(FailureClass::of(&unauthorized), FailureClass::Other);
+        assert_eq!(
+            describe_connect_error(&unauthorized),
+            "Ook Bridge rejected the auth token (HTTP 401 Unauthorized); check OOK_AUTH_TOKEN"
+        );
+
+        let other = http_error(StatusCode::NOT_FOUND, None);
+        assert!(!is_auth_rejection(&other) && !is_origin_rejection(&other));
+    }
+
+    #[test]
+    fn classifies_io_failures() {
+        let io_error = |kind| WsError::Io(io::Error::from(kind));
+        assert_eq!(
+            FailureClass::of(&io_error(io::ErrorKind::ConnectionRefused)),
+            FailureClass::ConnectionRefused
+        );
+        assert_eq!(
+            FailureClass::of(&io_error(io::ErrorKind::TimedOut)),
+            FailureClass::TimedOut
+        );
+        assert_eq!(
+            FailureClass::of(&io_error(io::ErrorKind::BrokenPipe)),
+            FailureClass::Other
+        );
+    }
+
+    fn redirect_to(location: &str) -> Response {
+        let mut response = Response::new(None);
+        *response.status_mut() = StatusCode::TEMPORARY_REDIRECT;
+        response
+            .headers_mut()
+            .insert(LOCATION, HeaderValue::from_str(location).unwrap());
+        response
+    }
+
+    fn with_token(mut target: BridgeTarget) -> BridgeTarget {
+        target
+            .headers
+            .insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cret"));
+        target
+    }
+
+    #[test]
+    fn redirect_keeps_the_token_only_for_the_same_bridge() {
+        let bridge = with_token(tcp("Bridge.local", "ws://Bridge.local:8647/acp", 8647));
+
+        let next = bridge.redirect(&redirect_to("/v2/acp")).unwrap();
+        assert_eq!(next.url, "ws://bridge.local:8647/v2/acp");
+        assert_eq!(next.target_host(), "bridge.local:8647");
+        assert!(next.headers.contains_key(AUTHORIZATION));
+
+        for location in [
+            "ws://elsewhere.local:8647/acp",
+            "ws://bridge.local:9000/acp",
+        ] {
+            let next = bridge.redirect(&redirect_to(location)).unwrap();
+            assert!(!next.headers.contains_key(AUTHORIZATION), "{}", location);
+        }
+    }
+
+    #[test]
+    fn redirect_never_drops_tls() {
+        let mut bridge = tcp("bridge.local", "wss://bridge.local:8647/acp", 8647);
+        bridge.tls = Some(tls_connector(false).unwrap());
+        let err = bridge
+            .redirect(&redirect_to("ws://bridge.local:8647/acp"))
+            .err()
+            .unwrap();
+        assert!(err.contains("from wss to ws"), "{}", err);
+        assert!(bridge
+            .redirect(&redirect_to("https://bridge.local/acp"))
+            .is_ok());
+    }
+
+    #[test]
+    fn redirect_to_wss_gets_tls() {
+        // A verifying connector: OOK_TLS_INSECURE only applies to the
+        // configured bridge, which had no TLS here
+        let bridge = tcp("bridge.local", "ws://bridge.local:8647/acp", 8647);
+        let next = bridge
+            .redirect(&redirect_to("wss://bridge.local/acp"))
+            .unwrap();
+        assert!(next.tls.is_some());
+        assert_eq!(next.url, "wss://bridge.local/acp");
+        assert_eq!(next.target_host(), "bridge.local:443");
+    }
+
+    #[test]
+    fn redirect_is_checked_against_the_allowlist() {
+        let mut bridge = tcp("bridge.local", "ws://bridge.local:8647/acp", 8647);
+        bridge.allowed_hosts = Some(HostAllowlist::parse("*.local").unwrap());
+        assert!(bridge
+            .redirect(&redirect_to("ws://other.local:8647/acp"))
+            .is_ok());
+        let err = bridge
+            .redirect(&redirect_to("ws://evil.example:8647/acp"))
+            .err()
+            .unwrap();
+        assert!(err.contains("OOK_ALLOWED_HOSTS"), "{}", err);
+    }
+
+    #[test]
+    fn redirect_over_a_local_socket_is_refused() {
+        let mut bridge = tcp("localhost", "ws://localhost/acp", 80);
+        #[cfg(unix)]
+        {
+            bridge.endpoint = Endpoint::Unix(PathBuf::from("/run/ook.sock"));
+        }
+        #[cfg(windows)]
+        {
+            bridge.endpoint = Endpoint::Pipe(r"\\.\pipe\ook-bridge".to_string());
+        }
+        let err = bridge.redirect(&redirect_to("/v2/acp")).err().unwrap();
+        assert!(err.contains("local socket"), "{}", err);
+    }
+}
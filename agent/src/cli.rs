@@ -0,0 +1,179 @@
+//! Command-line flags. Anything given here overrides the matching env var
+//! and `OOK_CONFIG` key.
+
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: ook [OPTIONS]
+       ook show [--direction <DIR>] [--method <METHOD>] <FILE>
+
+Proxies ACP between Zed (stdio) and a remote Ook Bridge (WebSocket).
+`ook show` prints a session captured with OOK_RECORD_PATH for reading.
+
+Options:
+      --host <HOSTS>      Bridge host, or comma-separated failover list
+                          (overrides OOK_REMOTE_HOSTS / OOK_REMOTE_HOST)
+      --port <PORT>       Bridge port (overrides OOK_REMOTE_PORT)
+      --scheme <SCHEME>   ws or wss (overrides OOK_REMOTE_SCHEME)
+      --check             Connect and ping the bridge once, then exit 0/1
+      --once[=<JSON>]     Send one JSON-RPC request (from the flag, or else
+                          stdin), print the bridge's response, then exit
+      --dry-run           Log stdin messages and answer requests with an
+                          error, without connecting to the bridge
+      --print-config      Print the resolved configuration as JSON (secrets
+                          masked), then exit
+  -h, --help              Print this help and exit
+  -V, --version           Print version and exit
+
+Options for `ook show`:
+      --direction <DIR>   Only frames going zed->bridge or bridge->zed
+      --method <METHOD>   Only requests and notifications with this method,
+                          and responses to those requests
+";
+
+/// Connection overrides taken from the command line.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub scheme: Option<String>,
+}
+
+/// What `ook show` prints.
+#[derive(Debug)]
+pub struct ShowArgs {
+    pub path: PathBuf,
+    /// `zed->bridge` or `bridge->zed`.
+    pub direction: Option<String>,
+    pub method: Option<String>,
+}
+
+pub enum Command {
+    Run(Args),
+    /// One-off handshake + ping health check.
+    Check(Args),
+    /// Send one request (inline, or `None` to read stdin) and print the
+    /// response.
+    Once(Args, Option<String>),
+    /// Read and log stdin without touching the network.
+    DryRun(Args),
+    /// Print the merged flags, env, and `OOK_CONFIG` settings.
+    PrintConfig(Args),
+    /// Pretty-print a capture file.
+    Show(ShowArgs),
+    Help,
+    Version,
+}
+
+pub fn version() -> String {
+    format!(
+        "ook {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("OOK_GIT_HASH")
+    )
+}
+
+/// Parse arguments (without the program name). Accepts `--flag value` and
+/// `--flag=value`.
+pub fn parse(argv: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut argv = argv.peekable();
+    if argv.peek().is_some_and(|arg| arg == "show") {
+        argv.next();
+        return parse_show(argv);
+    }
+    let mut args = Args::default();
+    let mut check = false;
+    let mut dry_run = false;
+    let mut print_config = false;
+    let mut once = None;
+
+    while let Some(arg) = argv.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| argv.next())
+                .ok_or_else(|| format!("{} requires a value", flag))
+        };
+
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--check" => check = true,
+            "--dry-run" => dry_run = true,
+            "--print-config" => print_config = true,
+            "--once" => once = Some(inline.clone()),
+            "--host" => args.host = Some(value()?),
+            "--port" => {
+                let port = value()?;
+                args.port = Some(
+                    port.parse()
+                        .map_err(|_| format!("Invalid --port {:?}", port))?,
+                );
+            }
+            "--scheme" => args.scheme = Some(value()?),
+            other => return Err(format!("Unknown argument {:?}", other)),
+        }
+    }
+
+    match (check, dry_run, print_config, once) {
+        (false, false, false, None) => Ok(Command::Run(args)),
+        (true, false, false, None) => Ok(Command::Check(args)),
+        (false, true, false, None) => Ok(Command::DryRun(args)),
+        (false, false, true, None) => Ok(Command::PrintConfig(args)),
+        (false, false, false, Some(request)) => Ok(Command::Once(args, request)),
+        _ => Err("--check, --dry-run, --print-config, and --once cannot be combined".to_string()),
+    }
+}
+
+/// The arguments after `show`.
+fn parse_show(mut argv: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut path = None;
+    let mut direction = None;
+    let mut method = None;
+
+    while let Some(arg) = argv.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| argv.next())
+                .ok_or_else(|| format!("{} requires a value", flag))
+        };
+
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--direction" => {
+                let value = value()?;
+                if value != "zed->bridge" && value != "bridge->zed" {
+                    return Err(format!(
+                        "Invalid --direction {:?} (expected \"zed->bridge\" or \"bridge->zed\")",
+                        value
+                    ));
+                }
+                direction = Some(value);
+            }
+            "--method" => method = Some(value()?),
+            other if other.starts_with('-') => return Err(format!("Unknown argument {:?}", other)),
+            _ if path.is_some() => return Err("show takes a single capture file".to_string()),
+            _ => path = Some(PathBuf::from(flag)),
+        }
+    }
+
+    let path = path.ok_or_else(|| "show requires a capture file".to_string())?;
+    Ok(Command::Show(ShowArgs {
+        path,
+        direction,
+        method,
+    }))
+}
@@ -0,0 +1,174 @@
+//! Coalescing bursts of bridge->zed notifications (`OOK_COALESCE_MS`).
+//!
+//! A JSON-RPC notification (a `method` and no `id`) is held for up to the
+//! window; if another one with the same method arrives meanwhile it replaces
+//! the held one, so Zed only sees the latest. Anything else (requests,
+//! responses, non-JSON) is never delayed or dropped, and first releases the
+//! held notifications so ordering relative to it is kept.
+
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub struct Coalescer {
+    window: Duration,
+    /// Latest held notification per method, in first-arrival order.
+    pending: Vec<(String, Vec<u8>)>,
+    /// When the held notifications are due; set by the first of a burst.
+    deadline: Option<Instant>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// When `flush` should next be called, if anything is held.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Take a message from the bridge. Returns what to forward now, in
+    /// order, and how many held notifications this one replaced (0 or 1).
+    pub fn push(&mut self, message: Vec<u8>, now: Instant) -> (Vec<Vec<u8>>, u64) {
+        let Some(method) = notification_method(&message) else {
+            let mut out = self.flush();
+            out.push(message);
+            return (out, 0);
+        };
+
+        self.deadline.get_or_insert(now + self.window);
+        match self.pending.iter_mut().find(|(m, _)| *m == method) {
+            Some((_, held)) => {
+                *held = message;
+                (Vec::new(), 1)
+            }
+            None => {
+                self.pending.push((method, message));
+                (Vec::new(), 0)
+            }
+        }
+    }
+
+    /// Release everything held.
+    pub fn flush(&mut self) -> Vec<Vec<u8>> {
+        self.deadline = None;
+        self.pending.drain(..).map(|(_, message)| message).collect()
+    }
+}
+
+/// The method of a JSON-RPC notification, or `None` for anything else
+/// (including bytes that aren't UTF-8, which pass straight through).
+pub fn notification_method(message: &[u8]) -> Option<String> {
+    let Ok(Value::Object(object)) = serde_json::from_slice::<Value>(message) else {
+        return None;
+    };
+    if object.contains_key("id") {
+        return None;
+    }
+    match object.get("method") {
+        Some(Value::String(method)) => Some(method.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(50);
+
+    fn update(n: u32) -> Vec<u8> {
+        format!(
+            r#"{{"jsonrpc":"2.0","method":"session/update","params":{{"n":{}}}}}"#,
+            n
+        )
+        .into_bytes()
+    }
+
+    fn progress(n: u32) -> Vec<u8> {
+        format!(
+            r#"{{"jsonrpc":"2.0","method":"$/progress","params":{{"n":{}}}}}"#,
+            n
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn a_burst_of_one_method_leaves_only_the_latest() {
+        let mut coalescer = Coalescer::new(WINDOW);
+        let start = Instant::now();
+        assert_eq!(coalescer.push(update(1), start), (vec![], 0));
+        assert_eq!(coalescer.deadline(), Some(start + WINDOW));
+        assert_eq!(coalescer.push(update(2), start + WINDOW / 2), (vec![], 1));
+        assert_eq!(coalescer.push(update(3), start + WINDOW / 2), (vec![], 1));
+        // The deadline is the burst's first arrival's, not pushed back
+        assert_eq!(coalescer.deadline(), Some(start + WINDOW));
+        assert_eq!(coalescer.flush(), [update(3)]);
+        assert_eq!(coalescer.deadline(), None);
+        assert!(coalescer.flush().is_empty());
+    }
+
+    #[test]
+    fn methods_are_held_separately_in_first_arrival_order() {
+        let mut coalescer = Coalescer::new(WINDOW);
+        let now = Instant::now();
+        coalescer.push(progress(1), now);
+        coalescer.push(update(1), now);
+        coalescer.push(progress(2), now);
+        assert_eq!(coalescer.flush(), [progress(2), update(1)]);
+    }
+
+    #[test]
+    fn anything_else_releases_the_held_notifications_first() {
+        let mut coalescer = Coalescer::new(WINDOW);
+        let now = Instant::now();
+        coalescer.push(update(1), now);
+        coalescer.push(update(2), now);
+        let response = br#"{"jsonrpc":"2.0","id":7,"result":{}}"#.to_vec();
+        let (out, replaced) = coalescer.push(response.clone(), now);
+        assert_eq!((out, replaced), (vec![update(2), response], 0));
+        assert_eq!(coalescer.deadline(), None);
+
+        // With nothing held, it goes straight out
+        let request = br#"{"jsonrpc":"2.0","id":8,"method":"session/prompt"}"#.to_vec();
+        assert_eq!(coalescer.push(request.clone(), now), (vec![request], 0));
+    }
+
+    #[test]
+    fn a_flush_starts_a_new_window() {
+        let mut coalescer = Coalescer::new(WINDOW);
+        let start = Instant::now();
+        coalescer.push(update(1), start);
+        assert_eq!(coalescer.flush(), [update(1)]);
+        let later = start + WINDOW * 3;
+        assert_eq!(coalescer.push(update(2), later), (vec![], 0));
+        assert_eq!(coalescer.deadline(), Some(later + WINDOW));
+    }
+
+    #[test]
+    fn only_notifications_are_coalesced() {
+        assert_eq!(
+            notification_method(&update(1)).as_deref(),
+            Some("session/update")
+        );
+        for message in [
+            &br#"{"id":1,"method":"session/update"}"#[..],
+            br#"{"id":null,"method":"session/update"}"#,
+            br#"{"method":7}"#,
+            br#"[{"method":"session/update"}]"#,
+            b"not json",
+            b"\xff\xfe",
+        ] {
+            assert_eq!(notification_method(message), None);
+        }
+    }
+}
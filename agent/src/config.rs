@@ -0,0 +1,121 @@
+//! Connection settings from an optional TOML file (`OOK_CONFIG`).
+//!
+//! Each key is the matching env var without the `OOK_` prefix, lowercased
+//! (`remote_port` for `OOK_REMOTE_PORT`). Env vars override the file, and
+//! the `--host`/`--port`/`--scheme` flags override both. Settings not
+//! listed here are env-only.
+
+use serde::Deserialize;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The connection settings, resolved from the file and then the env.
+///
+/// `None` means unset; the getters at the crate root apply CLI flags and defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub remote_host: Option<String>,
+    pub remote_hosts: Option<Vec<String>>,
+    pub remote_port: Option<u16>,
+    pub remote_scheme: Option<String>,
+    pub remote_url: Option<String>,
+    pub remote_uds: Option<PathBuf>,
+    pub auth_token: Option<String>,
+    /// Same `Name: value; Name: value` syntax as `OOK_EXTRA_HEADERS`.
+    pub extra_headers: Option<String>,
+    pub tls_insecure: Option<bool>,
+    pub connect_timeout_secs: Option<u64>,
+    pub reconnect_max_retries: Option<u32>,
+    pub reconnect_cooldown_after: Option<u32>,
+    pub reconnect_cooldown_secs: Option<u64>,
+    pub ping_interval_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl Config {
+    /// Read `OOK_CONFIG` if set, then apply env overrides.
+    ///
+    /// A missing or unreadable file, bad TOML, and unknown keys are all
+    /// errors, so typos don't go unnoticed.
+    pub fn load() -> Result<Self, String> {
+        let mut config = match env::var_os("OOK_CONFIG") {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                let text = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read OOK_CONFIG {}: {}", path.display(), e))?;
+                toml::from_str(&text)
+                    .map_err(|e| format!("Invalid OOK_CONFIG {}: {}", path.display(), e))?
+            }
+            None => Self::default(),
+        };
+        config.apply_env();
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) {
+        // Either host var replaces both file keys, so a file `remote_hosts`
+        // can't outrank an env `OOK_REMOTE_HOST`
+        let hosts = env_string("OOK_REMOTE_HOSTS");
+        let host = env_string("OOK_REMOTE_HOST");
+        if hosts.is_some() || host.is_some() {
+            self.remote_hosts = hosts.map(|list| vec![list]);
+            self.remote_host = host;
+        }
+
+        override_with(&mut self.remote_port, env_parse("OOK_REMOTE_PORT"));
+        override_with(&mut self.remote_scheme, env_string("OOK_REMOTE_SCHEME"));
+        override_with(&mut self.remote_url, env_string("OOK_REMOTE_URL"));
+        override_with(
+            &mut self.remote_uds,
+            env::var_os("OOK_REMOTE_UDS").map(PathBuf::from),
+        );
+        override_with(&mut self.auth_token, env_string("OOK_AUTH_TOKEN"));
+        override_with(&mut self.extra_headers, env_string("OOK_EXTRA_HEADERS"));
+        override_with(
+            &mut self.tls_insecure,
+            env::var_os("OOK_TLS_INSECURE").map(|_| crate::env_flag("OOK_TLS_INSECURE")),
+        );
+        override_with(
+            &mut self.connect_timeout_secs,
+            env_parse("OOK_CONNECT_TIMEOUT_SECS"),
+        );
+        override_with(
+            &mut self.reconnect_max_retries,
+            env_parse("OOK_RECONNECT_MAX_RETRIES"),
+        );
+        override_with(
+            &mut self.reconnect_cooldown_after,
+            env_parse("OOK_RECONNECT_COOLDOWN_AFTER"),
+        );
+        override_with(
+            &mut self.reconnect_cooldown_secs,
+            env_parse("OOK_RECONNECT_COOLDOWN_SECS"),
+        );
+        override_with(
+            &mut self.ping_interval_secs,
+            env_parse("OOK_PING_INTERVAL_SECS"),
+        );
+        override_with(
+            &mut self.idle_timeout_secs,
+            env_parse("OOK_IDLE_TIMEOUT_SECS"),
+        );
+    }
+}
+
+fn override_with<T>(slot: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *slot = value;
+    }
+}
+
+fn env_string(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+/// The env var parsed as `T`; unparsable values are ignored, as they always
+/// have been for numeric settings.
+fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().and_then(|v| v.parse().ok())
+}
@@ -0,0 +1,54 @@
+//! Dropping repeated bridge notifications (`OOK_DEDUP_CONSECUTIVE=1`).
+//!
+//! Some bridges send the same notification frame twice back to back. A
+//! bridge->zed frame is dropped when it is byte-identical to the one just
+//! before it, arrived within `WINDOW` of it, and is a JSON-RPC notification.
+//! Anything with an `id` always passes: a repeated request or response is
+//! for Zed to judge.
+
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// How close together two identical frames must be to count as a repeat.
+const WINDOW: Duration = Duration::from_millis(500);
+
+/// Frames larger than this aren't kept for comparison, so always pass.
+const MAX_FRAME_BYTES: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct Dedup {
+    /// The previous frame, if small enough to keep, and when it arrived.
+    last: Option<(Vec<u8>, Instant)>,
+    dropped: u64,
+}
+
+impl Dedup {
+    /// Whether `message` repeats the previous frame and should be dropped.
+    pub fn is_repeat(&mut self, message: &[u8]) -> bool {
+        let now = Instant::now();
+        let repeat = self.last.as_ref().is_some_and(|(last, at)| {
+            last.as_slice() == message && now.duration_since(*at) < WINDOW
+        }) && is_notification(message);
+        if repeat {
+            self.dropped += 1;
+            debug!(
+                bytes = message.len(),
+                dropped = self.dropped,
+                "Dropping repeated bridge notification (OOK_DEDUP_CONSECUTIVE)"
+            );
+            if let Some((_, at)) = &mut self.last {
+                *at = now;
+            }
+            return true;
+        }
+        self.last = (message.len() <= MAX_FRAME_BYTES).then(|| (message.to_vec(), now));
+        false
+    }
+}
+
+fn is_notification(message: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(message)
+        .is_ok_and(|value| value.get("method").is_some() && value.get("id").is_none())
+}
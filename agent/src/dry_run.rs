@@ -0,0 +1,63 @@
+//! `--dry-run`: read stdin exactly as a real session would and log what
+//! would be forwarded, without connecting anywhere.
+//!
+//! Requests get a JSON-RPC error back so Zed doesn't wait on them; this is
+//! for checking the agent is wired into Zed, not for using it.
+
+use crate::{acp, Agent};
+use serde_json::Value;
+use tokio::select;
+use tracing::{info, warn};
+
+pub async fn run(agent: &mut Agent) {
+    info!("Dry run: logging stdin messages, not connecting to the bridge");
+    let mut forwarded = 0u64;
+
+    loop {
+        select! {
+            open = agent.zed.read_line() => {
+                while let Some(line) = agent.zed.outbound.pop_front() {
+                    forwarded += 1;
+                    let parsed = serde_json::from_str::<Value>(&line).ok();
+                    info!(
+                        direction = "zed->bridge",
+                        valid_json = parsed.is_some(),
+                        bytes = line.len(),
+                        message = %agent.zed.log_body(&line),
+                        "Dry run: would forward message"
+                    );
+
+                    let Some(id) = parsed.as_ref().and_then(request_id) else {
+                        continue;
+                    };
+                    let reply = acp::error_response_to(id, acp::DRY_RUN, "ook --dry-run: no bridge connected");
+                    if let Err(e) = agent.zed.forward_to_stdout(reply.as_bytes()).await {
+                        warn!(error = %e, "Failed to write to stdout");
+                        return;
+                    }
+                }
+                if let Err(e) = agent.zed.flush_stdout().await {
+                    warn!(error = %e, "Failed to write to stdout");
+                    return;
+                }
+                if !open {
+                    break;
+                }
+            }
+
+            signal = agent.signals.recv() => {
+                info!(signal, "Received shutdown signal");
+                break;
+            }
+        }
+    }
+
+    info!(messages = forwarded, "Dry run finished");
+}
+
+/// The id of a JSON-RPC request (`method` and `id`), or `None` for
+/// notifications and responses.
+fn request_id(message: &Value) -> Option<&Value> {
+    message.get("method")?;
+    message.get("id")
+}
@@ -0,0 +1,109 @@
+//! Fault injection for resilience testing (cargo feature `fault-injection`).
+//!
+//! - `OOK_FAULT_DROP_EVERY_N` drops every Nth forwarded message.
+//! - `OOK_FAULT_DELAY_MS` delays every forwarded message.
+//! - `OOK_FAULT_CLOSE_AFTER_N` closes the WebSocket once N messages have
+//!   been forwarded.
+//! - `OOK_FAULT_PANIC_AFTER_N` panics at the Nth, to exercise the panic
+//!   hook.
+//!
+//! Messages are counted across both directions. Every fault logs a warning
+//! each time it fires, so test output shows exactly what was injected.
+
+use std::env;
+use std::time::Duration;
+use tracing::warn;
+
+pub struct Faults {
+    drop_every: Option<u64>,
+    delay: Option<Duration>,
+    close_after: Option<u64>,
+    panic_after: Option<u64>,
+    /// Messages seen so far, dropped ones included.
+    messages: u64,
+    /// The close has fired; it only does once per run.
+    closed: bool,
+}
+
+impl Faults {
+    /// Read the knobs; unset, `0` or unparsable leaves a fault off.
+    pub fn from_env() -> Self {
+        let knob = |name| {
+            env::var(name)
+                .ok()
+                .and_then(|n| n.parse::<u64>().ok())
+                .filter(|&n| n > 0)
+        };
+        let faults = Self {
+            drop_every: knob("OOK_FAULT_DROP_EVERY_N"),
+            delay: knob("OOK_FAULT_DELAY_MS").map(Duration::from_millis),
+            close_after: knob("OOK_FAULT_CLOSE_AFTER_N"),
+            panic_after: knob("OOK_FAULT_PANIC_AFTER_N"),
+            messages: 0,
+            closed: false,
+        };
+        if faults.drop_every.is_some()
+            || faults.delay.is_some()
+            || faults.close_after.is_some()
+            || faults.panic_after.is_some()
+        {
+            warn!(
+                drop_every_n = faults.drop_every,
+                delay_ms = faults.delay.map(|d| d.as_millis() as u64),
+                close_after_n = faults.close_after,
+                panic_after_n = faults.panic_after,
+                "FAULT INJECTION ENABLED"
+            );
+        }
+        faults
+    }
+
+    /// Apply the faults to one message about to be forwarded: waits out
+    /// the delay, then returns `false` if the message is to be dropped.
+    pub async fn pass(&mut self, direction: &'static str) -> bool {
+        self.messages += 1;
+        if self.panic_after == Some(self.messages) {
+            warn!(
+                direction,
+                messages = self.messages,
+                "FAULT INJECTED: panicking"
+            );
+            panic!("fault injected by OOK_FAULT_PANIC_AFTER_N");
+        }
+        if let Some(delay) = self.delay {
+            warn!(
+                direction,
+                messages = self.messages,
+                delay_ms = delay.as_millis() as u64,
+                "FAULT INJECTED: delaying message"
+            );
+            tokio::time::sleep(delay).await;
+        }
+        if self
+            .drop_every
+            .is_some_and(|n| self.messages.is_multiple_of(n))
+        {
+            warn!(
+                direction,
+                messages = self.messages,
+                "FAULT INJECTED: dropping message"
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Whether the WebSocket should be closed now; `true` once, when
+    /// `OOK_FAULT_CLOSE_AFTER_N` is reached.
+    pub fn close_due(&mut self) -> bool {
+        if self.closed || self.close_after.is_none_or(|n| self.messages < n) {
+            return false;
+        }
+        self.closed = true;
+        warn!(
+            messages = self.messages,
+            "FAULT INJECTED: closing the WebSocket"
+        );
+        true
+    }
+}
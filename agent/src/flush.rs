@@ -0,0 +1,64 @@
+//! When bridge->zed writes are flushed to stdout (`OOK_FLUSH_POLICY`).
+//!
+//! `immediate` flushes after every message, for the lowest latency.
+//! `coalesce` leaves messages in the stdout buffer while more are already
+//! waiting on the bridge connection, and flushes as soon as its read would
+//! block, or `COALESCE_FLUSH_DELAY` after the first unflushed write if the
+//! bridge never pauses. A burst then costs one write to Zed instead of one
+//! per message, and no message waits longer than the delay.
+
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::task::Poll;
+use std::time::Duration;
+
+/// Longest a message may sit unflushed under `coalesce`.
+pub const COALESCE_FLUSH_DELAY: Duration = Duration::from_millis(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    Immediate,
+    Coalesce,
+}
+
+impl FlushPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "immediate" => Ok(Self::Immediate),
+            "coalesce" => Ok(Self::Coalesce),
+            other => Err(format!(
+                "Unsupported OOK_FLUSH_POLICY {:?} (expected \"immediate\" or \"coalesce\")",
+                other
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Immediate => "immediate",
+            Self::Coalesce => "coalesce",
+        }
+    }
+}
+
+/// What `read_or_idle` resolved with.
+pub enum Read<T> {
+    Item(T),
+    /// Nothing was ready to read, and a flush was wanted.
+    Idle,
+}
+
+/// Await `read`, unless `idle` is set and it isn't ready yet, in which
+/// case resolve with `Read::Idle` instead of waiting.
+///
+/// `read` should be cancel-safe: after `Idle` it is dropped, having only
+/// been polled once, and the read is started again next time.
+pub async fn read_or_idle<F: Future>(read: F, idle: bool) -> Read<F::Output> {
+    let mut read = pin!(read);
+    poll_fn(|cx| match read.as_mut().poll(cx) {
+        Poll::Ready(item) => Poll::Ready(Read::Item(item)),
+        Poll::Pending if idle => Poll::Ready(Read::Idle),
+        Poll::Pending => Poll::Pending,
+    })
+    .await
+}
@@ -0,0 +1,515 @@
+use std::borrow::Cow;
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tracing::{error, warn};
+
+/// LSP framing: longest header line accepted; real headers are tiny.
+const MAX_LSP_HEADER_BYTES: usize = 8 * 1024;
+
+/// How the input stream is split into messages (`OOK_FRAMING`, or
+/// `OOK_STDIO_FRAMING=lsp`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One message per line (NDJSON). Cheap: a single newline search per
+    /// buffer.
+    Lines,
+    /// One message per complete top-level JSON object or array, however many
+    /// lines it spans. Every byte goes through a small state machine, and
+    /// input that isn't JSON is skipped a line at a time.
+    Json,
+    /// LSP-style frames: `Content-Length: N` (other headers are ignored), a
+    /// blank line, then exactly N bytes of body.
+    Lsp,
+}
+
+impl Framing {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "lines" => Ok(Self::Lines),
+            "json" => Ok(Self::Json),
+            other => Err(format!(
+                "Unsupported OOK_FRAMING {:?} (expected \"lines\" or \"json\")",
+                other
+            )),
+        }
+    }
+}
+
+/// Message reader that refuses to buffer messages longer than `max_len`
+/// bytes.
+///
+/// With `Framing::Lines`, like `tokio::io::Lines`, the trailing `\n` (and a
+/// `\r` before it) is stripped and a final unterminated line is still
+/// returned at EOF. With `Framing::Json`, raw newlines inside a value (which
+/// valid JSON only allows as whitespace) are dropped so every message comes
+/// out as a single line. Either way `next_line` is cancel-safe: partial
+/// messages live in `self`, not in the future.
+pub struct LineReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    max_len: usize,
+    /// Where the lines come from, for logs (e.g. `"stdin"`).
+    source: &'static str,
+    framing: Framing,
+    /// Set after an oversized message, until its end is seen.
+    discarding: bool,
+    /// JSON framing: nesting depth of the value being read; 0 between values.
+    depth: u32,
+    /// JSON framing: inside a string literal, and just after a backslash in one.
+    in_string: bool,
+    escaped: bool,
+    /// JSON framing: skipping non-JSON input up to the next newline.
+    skipping_line: bool,
+    /// LSP framing: `Content-Length` of the frame whose headers are being read.
+    content_length: Option<usize>,
+    /// LSP framing: body bytes still to come, once past the headers.
+    body_remaining: Option<usize>,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    pub fn new(reader: R, max_len: usize, source: &'static str) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            max_len,
+            source,
+            framing: Framing::Lines,
+            discarding: false,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            skipping_line: false,
+            content_length: None,
+            body_remaining: None,
+        }
+    }
+
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Read the next message, skipping (and logging) any that exceed `max_len`.
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        match self.framing {
+            Framing::Lines => self.next_ndjson_line().await,
+            Framing::Json => self.next_json_value().await,
+            Framing::Lsp => self.next_lsp_frame().await,
+        }
+    }
+
+    async fn next_ndjson_line(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+
+            if available.is_empty() {
+                // EOF: hand back an unterminated final line, if any
+                if self.discarding || self.buf.is_empty() {
+                    self.discarding = false;
+                    self.buf.clear();
+                    return Ok(None);
+                }
+                return finish_line(std::mem::take(&mut self.buf)).map(Some);
+            }
+
+            let newline = available.iter().position(|&b| b == b'\n');
+            let chunk = &available[..newline.unwrap_or(available.len())];
+            let consumed = chunk.len() + usize::from(newline.is_some());
+
+            if !self.discarding {
+                if self.buf.len() + chunk.len() > self.max_len {
+                    error!(
+                        source = self.source,
+                        max_line_bytes = self.max_len,
+                        "Line exceeds OOK_MAX_LINE_BYTES (lines of exactly that many bytes, \
+                         excluding the newline, are accepted); skipping to the next newline"
+                    );
+                    self.buf.clear();
+                    self.discarding = true;
+                } else {
+                    self.buf.extend_from_slice(chunk);
+                }
+            }
+            self.reader.consume(consumed);
+
+            if newline.is_some() {
+                if self.discarding {
+                    self.discarding = false;
+                    continue;
+                }
+                return finish_line(std::mem::take(&mut self.buf)).map(Some);
+            }
+        }
+    }
+
+    async fn next_json_value(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+
+            if available.is_empty() {
+                if self.depth > 0 && !self.discarding {
+                    warn!(
+                        source = self.source,
+                        bytes = self.buf.len(),
+                        "Input ended inside a JSON value; dropping it"
+                    );
+                }
+                self.buf.clear();
+                self.depth = 0;
+                self.in_string = false;
+                self.escaped = false;
+                self.discarding = false;
+                self.skipping_line = false;
+                return Ok(None);
+            }
+
+            let mut consumed = available.len();
+            let mut complete = false;
+            for (i, &b) in available.iter().enumerate() {
+                if self.skipping_line {
+                    self.skipping_line = b != b'\n';
+                    continue;
+                }
+
+                if self.depth == 0 {
+                    match b {
+                        b' ' | b'\t' | b'\r' | b'\n' => continue,
+                        b'{' | b'[' => self.depth = 1,
+                        _ => {
+                            warn!(
+                                source = self.source,
+                                "Skipping input that is not a JSON object or array, up to the next newline"
+                            );
+                            self.skipping_line = b != b'\n';
+                            continue;
+                        }
+                    }
+                } else if self.in_string {
+                    if self.escaped {
+                        self.escaped = false;
+                    } else if b == b'\\' {
+                        self.escaped = true;
+                    } else if b == b'"' {
+                        self.in_string = false;
+                    }
+                } else {
+                    match b {
+                        b'"' => self.in_string = true,
+                        b'{' | b'[' => self.depth += 1,
+                        b'}' | b']' => self.depth -= 1,
+                        // Whitespace between tokens; drop it to keep one message per line
+                        b'\r' | b'\n' => continue,
+                        _ => {}
+                    }
+                }
+
+                if !self.discarding {
+                    if self.buf.len() >= self.max_len {
+                        error!(
+                            source = self.source,
+                            max_line_bytes = self.max_len,
+                            "JSON value exceeds OOK_MAX_LINE_BYTES; skipping to its end"
+                        );
+                        self.buf.clear();
+                        self.discarding = true;
+                    } else {
+                        self.buf.push(b);
+                    }
+                }
+
+                if self.depth == 0 {
+                    if self.discarding {
+                        self.discarding = false;
+                        continue;
+                    }
+                    consumed = i + 1;
+                    complete = true;
+                    break;
+                }
+            }
+            self.reader.consume(consumed);
+
+            if complete {
+                return finish_line(std::mem::take(&mut self.buf)).map(Some);
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    async fn next_lsp_frame(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+
+            if available.is_empty() {
+                if !self.buf.is_empty()
+                    || self.content_length.is_some()
+                    || self.body_remaining.is_some()
+                {
+                    warn!(
+                        source = self.source,
+                        "Input ended inside an LSP frame; dropping it"
+                    );
+                }
+                self.buf.clear();
+                self.content_length = None;
+                self.body_remaining = None;
+                self.discarding = false;
+                return Ok(None);
+            }
+
+            if let Some(remaining) = self.body_remaining {
+                let take = remaining.min(available.len());
+                if !self.discarding {
+                    self.buf.extend_from_slice(&available[..take]);
+                }
+                self.reader.consume(take);
+                if take < remaining {
+                    self.body_remaining = Some(remaining - take);
+                    continue;
+                }
+                self.body_remaining = None;
+                if self.discarding {
+                    self.discarding = false;
+                    continue;
+                }
+                // The body is the message as is; no line ending to strip
+                let body = std::mem::take(&mut self.buf);
+                return String::from_utf8(body).map(Some).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "stream did not contain valid UTF-8",
+                    )
+                });
+            }
+
+            // Headers, one line at a time
+            let newline = available.iter().position(|&b| b == b'\n');
+            let chunk = &available[..newline.unwrap_or(available.len())];
+            if self.buf.len() + chunk.len() > MAX_LSP_HEADER_BYTES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "LSP header line too long",
+                ));
+            }
+            self.buf.extend_from_slice(chunk);
+            let consumed = chunk.len() + usize::from(newline.is_some());
+            self.reader.consume(consumed);
+            if newline.is_none() {
+                continue;
+            }
+
+            let header = finish_line(std::mem::take(&mut self.buf))?;
+            if !header.is_empty() {
+                let Some((name, value)) = header.split_once(':') else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed LSP header {:?}", header),
+                    ));
+                };
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    let length = value.trim().parse().map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid Content-Length {:?}", value.trim()),
+                        )
+                    })?;
+                    self.content_length = Some(length);
+                }
+                continue;
+            }
+
+            // Blank line: the headers are done, if there were any
+            let Some(length) = self.content_length.take() else {
+                continue;
+            };
+            if length > self.max_len {
+                error!(
+                    source = self.source,
+                    bytes = length,
+                    max_line_bytes = self.max_len,
+                    "LSP frame exceeds OOK_MAX_LINE_BYTES; skipping it"
+                );
+                self.discarding = true;
+            }
+            self.body_remaining = Some(length);
+        }
+    }
+}
+
+fn finish_line(mut line: Vec<u8>) -> io::Result<String> {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        )
+    })
+}
+
+/// Drop every `\r` from a message (`OOK_NORMALIZE_EOL`), so a CRLF becomes
+/// LF and a stray carriage return never reaches the other side.
+///
+/// Safe for JSON: a raw `\r` can only be whitespace between tokens, since
+/// inside strings it has to be escaped.
+pub fn normalize_eol(message: &str) -> Cow<'_, str> {
+    if message.contains('\r') {
+        Cow::Owned(message.replace('\r', ""))
+    } else {
+        Cow::Borrowed(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    /// Every message in `input`, read with `framing` through a buffer of
+    /// `capacity` bytes, so that small capacities split messages across
+    /// reads. Stops at the first error, which is the last item.
+    async fn read(
+        input: &str,
+        framing: Framing,
+        max_len: usize,
+        capacity: usize,
+    ) -> Vec<io::Result<String>> {
+        let reader = BufReader::with_capacity(capacity, input.as_bytes());
+        let mut lines = LineReader::new(reader, max_len, "test").with_framing(framing);
+        let mut out = Vec::new();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => out.push(Ok(line)),
+                Ok(None) => return out,
+                Err(e) => {
+                    out.push(Err(e));
+                    return out;
+                }
+            }
+        }
+    }
+
+    /// `read`, expecting no error.
+    async fn messages(
+        input: &str,
+        framing: Framing,
+        max_len: usize,
+        capacity: usize,
+    ) -> Vec<String> {
+        read(input, framing, max_len, capacity)
+            .await
+            .into_iter()
+            .map(|line| line.expect("no read error"))
+            .collect()
+    }
+
+    const PRETTY: &str =
+        "{\n  \"jsonrpc\": \"2.0\",\n  \"params\": {\n    \"text\": \"a}\\\"{\\n\"\n  }\n}";
+
+    #[tokio::test]
+    async fn json_values_are_whole_however_the_reads_split_them() {
+        let input = format!("{}\n[1,\n 2]{{\"id\":3}}\n", PRETTY);
+        for capacity in [1, 3, 7, 1024] {
+            let out = messages(&input, Framing::Json, 1024, capacity).await;
+            assert_eq!(
+                out,
+                [
+                    r#"{  "jsonrpc": "2.0",  "params": {    "text": "a}\"{\n"  }}"#,
+                    "[1, 2]",
+                    r#"{"id":3}"#,
+                ],
+                "capacity {}",
+                capacity
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn json_skips_lines_that_are_not_json() {
+        let input = "garbage {\n{\"id\":1}\n";
+        let out = messages(input, Framing::Json, 1024, 4).await;
+        assert_eq!(out, [r#"{"id":1}"#]);
+    }
+
+    #[tokio::test]
+    async fn json_value_cut_off_at_eof_is_dropped() {
+        let input = "{\"id\":1}\n{\"id\":2,\"params\":{";
+        let out = messages(input, Framing::Json, 1024, 3).await;
+        assert_eq!(out, [r#"{"id":1}"#]);
+    }
+
+    #[tokio::test]
+    async fn oversized_json_value_is_skipped_to_its_end() {
+        let input = "{\"big\":\"0123456789\"}\n{\"id\":2}";
+        let out = messages(input, Framing::Json, 12, 5).await;
+        assert_eq!(out, [r#"{"id":2}"#]);
+    }
+
+    #[tokio::test]
+    async fn lsp_header_split_across_reads() {
+        let input = "Content-Length: 8\r\n\r\n{\"id\":1}";
+        for capacity in [1, 2, 5] {
+            let out = messages(input, Framing::Lsp, 1024, capacity).await;
+            assert_eq!(out, [r#"{"id":1}"#], "capacity {}", capacity);
+        }
+    }
+
+    #[tokio::test]
+    async fn lsp_partial_body_at_eof_is_dropped() {
+        let input = "Content-Length: 8\r\n\r\n{\"id\":1}Content-Length: 8\r\n\r\n{\"id";
+        let out = messages(input, Framing::Lsp, 1024, 3).await;
+        assert_eq!(out, [r#"{"id":1}"#]);
+    }
+
+    #[tokio::test]
+    async fn lsp_oversized_content_length_skips_the_body() {
+        let input =
+            "Content-Length: 20\r\n\r\n{\"big\":\"0123456789\"}Content-Length: 8\r\n\r\n{\"id\":2}";
+        let out = messages(input, Framing::Lsp, 10, 4).await;
+        assert_eq!(out, [r#"{"id":2}"#]);
+    }
+
+    #[tokio::test]
+    async fn lsp_frames_back_to_back_with_other_headers() {
+        let bodies = ["{\"id\":1}", "{\"text\":\"line\nbreak ü\"}", "[]"];
+        let mut input = String::new();
+        for (i, body) in bodies.iter().enumerate() {
+            if i == 1 {
+                input.push_str("Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n");
+            }
+            let name = if i == 2 {
+                "content-length"
+            } else {
+                "Content-Length"
+            };
+            input.push_str(&format!("{}: {}\r\n\r\n{}", name, body.len(), body));
+        }
+        let out = messages(&input, Framing::Lsp, 1024, 16).await;
+        assert_eq!(out, bodies);
+    }
+
+    #[tokio::test]
+    async fn lsp_bad_content_length_is_an_error() {
+        let out = read("Content-Length: -1\r\n\r\n", Framing::Lsp, 1024, 64).await;
+        let error = out.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "invalid Content-Length \"-1\"");
+    }
+
+    #[test]
+    fn normalize_eol_drops_every_carriage_return() {
+        assert_eq!(normalize_eol("{\"id\":1}\r\n"), "{\"id\":1}\n");
+        assert_eq!(normalize_eol("{\"id\":1}\r"), "{\"id\":1}");
+        assert_eq!(normalize_eol("{\r\"id\":\r1}"), "{\"id\":1}");
+        assert_eq!(normalize_eol("\r\n\r\n"), "\n\n");
+    }
+
+    #[test]
+    fn normalize_eol_borrows_when_there_is_nothing_to_drop() {
+        let message = "{\"text\":\"escaped \\r stays\"}\n";
+        assert!(matches!(normalize_eol(message), Cow::Borrowed(m) if m == message));
+        assert!(matches!(normalize_eol(""), Cow::Borrowed("")));
+    }
+}
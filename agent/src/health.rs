@@ -0,0 +1,186 @@
+//! Aggregated health warning (`OOK_ANOMALY_THRESHOLD`,
+//! `OOK_ANOMALY_WINDOW_SECS`).
+//!
+//! Each reconnect, oversized message and invalid JSON line already logs on
+//! its own, which is easy to miss when they trickle in. Here they are also
+//! counted over a sliding window, and once any kind reaches the threshold a
+//! single warning sums up all of them with likely causes. After that it
+//! stays quiet for a window, so a lasting problem warns once per window
+//! rather than once per event.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+pub const DEFAULT_ANOMALY_THRESHOLD: u32 = 5;
+pub const DEFAULT_ANOMALY_WINDOW: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anomaly {
+    /// The bridge connection was lost and is being re-established.
+    Reconnect,
+    /// A message over `OOK_MAX_MESSAGE_BYTES`, either direction.
+    Oversized,
+    /// A stdin line dropped by `OOK_VALIDATE_JSON`.
+    InvalidJson,
+}
+
+const ANOMALIES: [Anomaly; 3] = [Anomaly::Reconnect, Anomaly::Oversized, Anomaly::InvalidJson];
+
+impl Anomaly {
+    fn index(self) -> usize {
+        match self {
+            Self::Reconnect => 0,
+            Self::Oversized => 1,
+            Self::InvalidJson => 2,
+        }
+    }
+
+    fn likely_cause(self) -> &'static str {
+        match self {
+            Self::Reconnect => {
+                "the bridge keeps restarting or the network is unstable; check the bridge's logs"
+            }
+            Self::Oversized => {
+                "messages are larger than OOK_MAX_MESSAGE_BYTES; raise it if they are legitimate"
+            }
+            Self::InvalidJson => {
+                "Zed and the agent disagree on stdio framing; check OOK_STDIO_FRAMING and OOK_FRAMING"
+            }
+        }
+    }
+}
+
+pub struct Health {
+    /// Events of one kind within `window` that trigger the warning; `0` is off.
+    threshold: u32,
+    window: Duration,
+    /// Recent events per kind, oldest first, indexed by `Anomaly::index`.
+    events: [VecDeque<Instant>; 3],
+    warned_at: Option<Instant>,
+}
+
+impl Health {
+    pub fn new(threshold: u32, window: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            events: Default::default(),
+            warned_at: None,
+        }
+    }
+
+    /// Count one event, warning if that brings its kind to the threshold.
+    pub fn record(&mut self, anomaly: Anomaly) {
+        if self.record_at(anomaly, Instant::now()) {
+            self.warn();
+        }
+    }
+
+    /// `record` at `now`, returning whether to warn.
+    fn record_at(&mut self, anomaly: Anomaly, now: Instant) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        for events in &mut self.events {
+            while events
+                .front()
+                .is_some_and(|&at| now.duration_since(at) >= self.window)
+            {
+                events.pop_front();
+            }
+        }
+        let events = &mut self.events[anomaly.index()];
+        events.push_back(now);
+
+        let quiet = self
+            .warned_at
+            .is_some_and(|at| now.duration_since(at) < self.window);
+        if events.len() >= self.threshold as usize && !quiet {
+            self.warned_at = Some(now);
+            return true;
+        }
+        false
+    }
+
+    fn warn(&self) {
+        let count = |anomaly: Anomaly| self.events[anomaly.index()].len();
+        let likely_causes: Vec<&str> = ANOMALIES
+            .iter()
+            .filter(|&&anomaly| count(anomaly) >= self.threshold as usize)
+            .map(|anomaly| anomaly.likely_cause())
+            .collect();
+        warn!(
+            reconnects = count(Anomaly::Reconnect),
+            oversized_messages = count(Anomaly::Oversized),
+            invalid_json = count(Anomaly::InvalidJson),
+            window_secs = self.window.as_secs(),
+            likely_causes = %likely_causes.join("; "),
+            "Agent health: unusually many problems recently"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Whether each of `count` events of `anomaly`, a second apart from
+    /// `start`, warned.
+    fn burst(health: &mut Health, anomaly: Anomaly, start: Instant, count: u32) -> Vec<bool> {
+        (0..count)
+            .map(|i| health.record_at(anomaly, start + SECOND * i))
+            .collect()
+    }
+
+    #[test]
+    fn warns_once_the_threshold_is_reached_then_stays_quiet_for_a_window() {
+        let mut health = Health::new(3, WINDOW);
+        let start = Instant::now();
+        let warned = burst(&mut health, Anomaly::Reconnect, start, 5);
+        assert_eq!(warned, [false, false, true, false, false]);
+        // Still over the threshold once the quiet window is over
+        let later = start + WINDOW + SECOND * 2;
+        assert!(health.record_at(Anomaly::Reconnect, later));
+    }
+
+    #[test]
+    fn events_older_than_the_window_stop_counting() {
+        let mut health = Health::new(3, WINDOW);
+        let start = Instant::now();
+        assert_eq!(
+            burst(&mut health, Anomaly::Oversized, start, 2),
+            [false, false]
+        );
+        let later = start + WINDOW + SECOND;
+        assert_eq!(
+            burst(&mut health, Anomaly::Oversized, later, 3),
+            [false, false, true]
+        );
+        assert_eq!(health.events[Anomaly::Oversized.index()].len(), 3);
+    }
+
+    #[test]
+    fn kinds_are_counted_separately() {
+        let mut health = Health::new(2, WINDOW);
+        let now = Instant::now();
+        assert!(!health.record_at(Anomaly::Reconnect, now));
+        assert!(!health.record_at(Anomaly::Oversized, now));
+        assert!(!health.record_at(Anomaly::InvalidJson, now));
+        assert!(health.record_at(Anomaly::InvalidJson, now + SECOND));
+        // Any kind reaching it again is covered by the warning just given
+        assert!(!health.record_at(Anomaly::Reconnect, now + SECOND));
+    }
+
+    #[test]
+    fn a_zero_threshold_turns_it_off() {
+        let mut health = Health::new(0, WINDOW);
+        let warned = burst(&mut health, Anomaly::Reconnect, Instant::now(), 10);
+        assert!(warned.iter().all(|&w| !w));
+        assert!(health.events.iter().all(VecDeque::is_empty));
+    }
+}
@@ -0,0 +1,271 @@
+//! What happens to bridge frames that arrive faster than stdout drains
+//! (`OOK_INBOUND_OVERFLOW`).
+//!
+//! With the default `block`, frames are read from the WebSocket only as
+//! fast as they are written to stdout, so a stuck Zed stops the reading and
+//! the bridge backs up behind TCP flow control. With the other policies the
+//! WebSocket is read on its own task into a queue of up to
+//! `OOK_INBOUND_QUEUE_SIZE` frames of any kind, and a full queue is a
+//! decision: `drop-oldest` discards the oldest queued text/binary frame
+//! (control frames are never dropped), `disconnect` ends the connection
+//! with an error once the frames ahead of it are forwarded, and the agent
+//! reconnects. A queue full of control frames has nothing to drop, so
+//! `drop-oldest` disconnects too.
+
+use futures_util::task::AtomicWaker;
+use futures_util::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::env;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tracing::{error, warn, Instrument};
+
+const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// What to do with a bridge frame when the inbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundPolicy {
+    DropOldest,
+    Disconnect,
+}
+
+/// The queue settings; `None` from `from_env` means `block`, no queue.
+#[derive(Debug, Clone, Copy)]
+pub struct InboundOverflow {
+    policy: InboundPolicy,
+    capacity: usize,
+}
+
+impl InboundOverflow {
+    /// Build from `OOK_INBOUND_OVERFLOW` and `OOK_INBOUND_QUEUE_SIZE`.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let policy = match env::var("OOK_INBOUND_OVERFLOW").as_deref() {
+            Err(_) | Ok("block") => return Ok(None),
+            Ok("drop-oldest") => InboundPolicy::DropOldest,
+            Ok("disconnect") => InboundPolicy::Disconnect,
+            Ok(other) => {
+                return Err(format!(
+                    "Unsupported OOK_INBOUND_OVERFLOW {:?} (expected \"block\", \"drop-oldest\", or \"disconnect\")",
+                    other
+                ))
+            }
+        };
+        let capacity = env::var("OOK_INBOUND_QUEUE_SIZE")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_QUEUE_SIZE);
+        Ok(Some(Self { policy, capacity }))
+    }
+
+    pub fn policy_name(&self) -> &'static str {
+        match self.policy {
+            InboundPolicy::DropOldest => "drop-oldest",
+            InboundPolicy::Disconnect => "disconnect",
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Frames read ahead of stdout, handed out in order.
+pub struct InboundQueue {
+    shared: Arc<Shared>,
+    reader: JoinHandle<()>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    waker: AtomicWaker,
+}
+
+#[derive(Default)]
+struct State {
+    frames: VecDeque<Result<Message, WsError>>,
+    /// The reader is done; nothing more will be queued.
+    ended: bool,
+}
+
+impl InboundQueue {
+    /// Start reading `stream` on a background task. The task ends with the
+    /// stream, after queueing the overflow error, or when this is dropped.
+    pub fn spawn<S>(mut stream: S, overflow: InboundOverflow) -> Self
+    where
+        S: Stream<Item = Result<Message, WsError>> + Unpin + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            waker: AtomicWaker::new(),
+        });
+        let writer = Arc::clone(&shared);
+        let reader = tokio::spawn(
+            async move {
+                let mut dropped = 0u64;
+                while let Some(frame) = stream.next().await {
+                    let mut state = writer.state.lock().expect("inbound queue lock");
+                    if state.frames.len() >= overflow.capacity {
+                        let oldest = match overflow.policy {
+                            InboundPolicy::DropOldest => state.frames.iter().position(is_data),
+                            InboundPolicy::Disconnect => None,
+                        };
+                        if let Some(oldest) = oldest {
+                            state.frames.remove(oldest);
+                            dropped += 1;
+                            warn!(
+                                capacity = overflow.capacity,
+                                dropped,
+                                "Inbound queue full, stdout is not keeping up; dropped oldest bridge message"
+                            );
+                        } else {
+                            error!(
+                                capacity = overflow.capacity,
+                                policy = overflow.policy_name(),
+                                "Inbound queue full, stdout is not keeping up; disconnecting (OOK_INBOUND_OVERFLOW)"
+                            );
+                            let full = io::Error::other("inbound queue full (OOK_INBOUND_OVERFLOW)");
+                            state.frames.push_back(Err(WsError::Io(full)));
+                            break;
+                        }
+                    }
+                    state.frames.push_back(frame);
+                    drop(state);
+                    writer.waker.wake();
+                }
+                writer.state.lock().expect("inbound queue lock").ended = true;
+                writer.waker.wake();
+            }
+            .in_current_span(),
+        );
+        Self { shared, reader }
+    }
+}
+
+impl Stream for InboundQueue {
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Registered before looking, so a frame queued in between still wakes us
+        self.shared.waker.register(cx.waker());
+        let mut state = self.shared.state.lock().expect("inbound queue lock");
+        match state.frames.pop_front() {
+            Some(frame) => Poll::Ready(Some(frame)),
+            None if state.ended => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for InboundQueue {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+fn is_data(frame: &Result<Message, WsError>) -> bool {
+    matches!(frame, Ok(Message::Text(_) | Message::Binary(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overflow(policy: InboundPolicy, capacity: usize) -> InboundOverflow {
+        InboundOverflow { policy, capacity }
+    }
+
+    fn ping() -> Message {
+        Message::Ping(Vec::new())
+    }
+
+    /// Queue all of `frames` with nothing reading, then drain the queue.
+    async fn queued(frames: Vec<Message>, overflow: InboundOverflow) -> Vec<String> {
+        let stream = futures_util::stream::iter(frames.into_iter().map(Ok));
+        let queue = InboundQueue::spawn(stream, overflow);
+        // Let the reader run to the end of the stream before anything is taken
+        while !queue.shared.state.lock().unwrap().ended {
+            tokio::task::yield_now().await;
+        }
+        queue
+            .map(|frame| match frame {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Ping(_)) => "ping".to_string(),
+                Ok(other) => format!("{:?}", other),
+                Err(e) => format!("error: {}", e),
+            })
+            .collect()
+            .await
+    }
+
+    fn texts(texts: &[&str]) -> Vec<Message> {
+        texts.iter().map(|t| Message::Text(t.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn under_capacity_everything_is_kept_in_order() {
+        let frames = vec![ping(), Message::Text("a".into()), Message::Text("b".into())];
+        let out = queued(frames, overflow(InboundPolicy::Disconnect, 3)).await;
+        assert_eq!(out, ["ping", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_newest_messages() {
+        let out = queued(
+            texts(&["a", "b", "c", "d"]),
+            overflow(InboundPolicy::DropOldest, 2),
+        )
+        .await;
+        assert_eq!(out, ["c", "d"]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_never_drops_control_frames() {
+        let frames = vec![ping(), Message::Text("a".into()), Message::Text("b".into())];
+        let out = queued(frames, overflow(InboundPolicy::DropOldest, 2)).await;
+        assert_eq!(out, ["ping", "b"]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_disconnects_with_only_control_frames_queued() {
+        let frames = vec![ping(), ping(), ping(), Message::Text("a".into())];
+        let out = queued(frames, overflow(InboundPolicy::DropOldest, 2)).await;
+        assert_eq!(
+            out,
+            [
+                "ping",
+                "ping",
+                "error: IO error: inbound queue full (OOK_INBOUND_OVERFLOW)"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_stops_reading_once_full() {
+        let out = queued(
+            texts(&["a", "b", "c", "d"]),
+            overflow(InboundPolicy::Disconnect, 2),
+        )
+        .await;
+        assert_eq!(
+            out,
+            [
+                "a",
+                "b",
+                "error: IO error: inbound queue full (OOK_INBOUND_OVERFLOW)"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn control_frames_count_towards_capacity() {
+        let frames = vec![ping(), ping(), Message::Text("a".into())];
+        let out = queued(frames, overflow(InboundPolicy::Disconnect, 2)).await;
+        assert_eq!(out.len(), 3);
+        assert!(out[2].starts_with("error: "), "{:?}", out);
+    }
+}
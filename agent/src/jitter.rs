@@ -0,0 +1,101 @@
+//! Randomized timer jitter (`OOK_JITTER_PCT`).
+//!
+//! Many agents sharing a bridge would otherwise reconnect, and ping, in
+//! lockstep after a bridge restart. Each reconnect delay is scaled by a
+//! random factor within ±`OOK_JITTER_PCT` percent (default 20); keepalive
+//! pings get the same only with `OOK_PING_JITTER=1`. The RNG is a
+//! xorshift64* seeded per process, plenty for spreading timers out.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+pub const DEFAULT_JITTER_PCT: u32 = 20;
+
+pub struct Jitter {
+    /// Largest deviation, as a fraction of the delay; `0.0` is off.
+    spread: f64,
+    ping: bool,
+    state: u64,
+}
+
+impl Jitter {
+    /// `percent` must be at most 100.
+    pub fn new(percent: u32, ping: bool) -> Self {
+        // A fresh `RandomState` is randomly keyed, which is all the seed needs
+        let seed = RandomState::new().build_hasher().finish();
+        Self {
+            spread: f64::from(percent) / 100.0,
+            ping,
+            state: seed | 1,
+        }
+    }
+
+    /// A reconnect delay, jittered.
+    pub fn reconnect(&mut self, delay: Duration) -> Duration {
+        self.apply(delay)
+    }
+
+    /// A keepalive interval, jittered only with `OOK_PING_JITTER=1`.
+    pub fn ping(&mut self, interval: Duration) -> Duration {
+        if self.ping {
+            self.apply(interval)
+        } else {
+            interval
+        }
+    }
+
+    fn apply(&mut self, delay: Duration) -> Duration {
+        if self.spread == 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + self.spread * (2.0 * self.next_unit() - 1.0);
+        delay.mul_f64(factor)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_unit(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let bits = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_millis(1000);
+
+    #[test]
+    fn jittered_delays_stay_within_the_spread() {
+        let mut jitter = Jitter::new(DEFAULT_JITTER_PCT, true);
+        let (low, high) = (DELAY.mul_f64(0.8), DELAY.mul_f64(1.2));
+        let delays: Vec<_> = (0..1000)
+            .flat_map(|_| [jitter.reconnect(DELAY), jitter.ping(DELAY)])
+            .collect();
+        assert!(delays.iter().all(|d| (low..=high).contains(d)));
+        // Spread out, not stuck on one value
+        assert!(delays.iter().any(|&d| d < DELAY.mul_f64(0.9)));
+        assert!(delays.iter().any(|&d| d > DELAY.mul_f64(1.1)));
+    }
+
+    #[test]
+    fn full_spread_never_goes_negative() {
+        let mut jitter = Jitter::new(100, false);
+        for _ in 0..1000 {
+            assert!(jitter.reconnect(DELAY) <= DELAY * 2);
+        }
+    }
+
+    #[test]
+    fn zero_percent_and_unjittered_pings_are_exact() {
+        let mut off = Jitter::new(0, true);
+        assert_eq!(off.reconnect(DELAY), DELAY);
+        assert_eq!(off.ping(DELAY), DELAY);
+        let mut reconnect_only = Jitter::new(DEFAULT_JITTER_PCT, false);
+        assert_eq!(reconnect_only.ping(DELAY), DELAY);
+    }
+}
@@ -0,0 +1,3336 @@
+//! The `ook` agent. `main.rs` only starts the runtime; the agent lives in
+//! this library so that `benches/` can drive the forwarding loop (`bench`).
+
+mod acp;
+mod acp_version;
+mod allowlist;
+mod app_ping;
+mod auth_token;
+#[doc(hidden)]
+pub mod bench;
+mod breaker;
+mod bridge;
+mod cli;
+mod coalesce;
+mod config;
+mod dedup;
+mod dry_run;
+#[cfg(feature = "fault-injection")]
+mod fault;
+mod flush;
+mod framing;
+mod health;
+mod inbound;
+mod jitter;
+mod listen;
+mod logging;
+#[cfg(feature = "loopback")]
+mod loopback;
+mod metrics;
+mod mux;
+mod once;
+#[cfg(feature = "otel")]
+mod otel;
+mod outbound;
+mod panic_hook;
+mod pid_file;
+mod print_config;
+mod prometheus;
+mod proxy;
+mod record;
+mod redact;
+mod seq_tag;
+mod signal;
+mod skew;
+mod status;
+mod stdin;
+mod tracking;
+mod transport;
+
+use acp::ReportClose;
+use acp_version::{AcpVersion, Verdict};
+use allowlist::HostAllowlist;
+use app_ping::AppPing;
+use breaker::ReconnectBreaker;
+use bridge::{
+    describe_connect_error, is_auth_rejection, is_origin_rejection, BridgeTarget, Bridges,
+    Endpoint, FailureClass, TcpKeepalive, WsStream,
+};
+use coalesce::Coalescer;
+use config::Config;
+use dedup::Dedup;
+use flush::{FlushPolicy, Read};
+use framing::{normalize_eol, Framing, LineReader};
+use futures_util::future::Either;
+use futures_util::{SinkExt, StreamExt};
+use health::{Anomaly, Health};
+use inbound::{InboundOverflow, InboundQueue};
+use jitter::Jitter;
+use metrics::Metrics;
+use mux::Mux;
+use outbound::OutboundQueue;
+use pid_file::PidFile;
+use proxy::{NoProxy, Proxy};
+use record::Recorder;
+use redact::{LogBody, MethodFilter};
+use seq_tag::SeqTag;
+use serde_json::json;
+use signal::{ReconnectSignal, ShutdownSignal};
+use skew::SkewCheck;
+use std::borrow::Cow;
+use std::env;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+use stdin::StdinChannel;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::http::header::{
+    HeaderName, HeaderValue, AUTHORIZATION, ORIGIN, SEC_WEBSOCKET_PROTOCOL,
+};
+use tokio_tungstenite::tungstenite::http::HeaderMap;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tracing::{debug, error, info, info_span, warn, Instrument, Level};
+use tracking::{Direction, IdTracker};
+use transport::{MessageSink, MessageStream, Transport};
+use url::{Host, Url};
+
+const DEFAULT_PORT: u16 = 8647;
+const DEFAULT_SCHEME: &str = "ws";
+const DEFAULT_RECONNECT_MAX_RETRIES: u32 = 10;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_RECONNECT_COOLDOWN_AFTER: u32 = 10;
+const DEFAULT_RECONNECT_COOLDOWN_SECS: u64 = 300;
+/// How much of a rejected message to include in the log line.
+const PREVIEW_CHARS: usize = 120;
+const DEFAULT_MAX_LINE_BYTES: usize = 16 * 1024 * 1024;
+/// Matches tungstenite's own default for incoming messages.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_PING_INTERVAL_SECS: u64 = 30;
+/// Consecutive unanswered pings after which the connection counts as dead.
+const MAX_UNANSWERED_PINGS: u32 = 2;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_TCP_KEEPALIVE_INTERVAL_SECS: u64 = 10;
+const DEFAULT_TCP_KEEPALIVE_RETRIES: u32 = 3;
+const DEFAULT_TRACK_IDS_TIMEOUT_SECS: u64 = 60;
+/// How often `OOK_TRACK_IDS` looks for overdue requests.
+const TRACK_IDS_SWEEP: Duration = Duration::from_secs(1);
+/// How long shutdown waits for the bridge's close reply while forwarding
+/// messages it already had in flight.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+/// Handshake header carrying the session id.
+const SESSION_HEADER: &str = "x-ook-session";
+/// How long `--check` waits for the bridge to answer its ping.
+const CHECK_PONG_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `--once` waits for its response, unless `OOK_ONCE_TIMEOUT_SECS`
+/// says otherwise.
+const DEFAULT_ONCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a session over a single WebSocket connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionEnd {
+    /// Zed closed stdin; the agent should exit.
+    StdinClosed,
+    /// SIGTERM/SIGINT (or Ctrl-C on Windows); the agent should exit.
+    Signal,
+    /// Stdout is a broken pipe: Zed exited. A normal exit, not a crash.
+    ClientGone,
+    /// Writing to stdout failed some other way; the agent should exit with
+    /// an error.
+    StdoutFailed,
+    /// The bridge connection dropped; the agent should reconnect.
+    Disconnected,
+    /// Nothing flowed either way for `OOK_IDLE_TIMEOUT_SECS`; the agent should exit.
+    IdleTimeout,
+    /// SIGHUP asked for a fresh connection; the agent should reconnect.
+    Reconnect,
+    /// The bridge speaks an ACP version outside `OOK_ACP_VERSIONS`; the
+    /// agent should exit.
+    AcpVersion,
+    /// The connection reached `OOK_MAX_SESSION_SECS`; the agent should
+    /// reconnect, or exit with `OOK_MAX_SESSION_EXIT`.
+    MaxAge,
+}
+
+impl SessionEnd {
+    /// Short reason, used both in the close frame and the logs.
+    fn reason(self) -> &'static str {
+        match self {
+            Self::StdinClosed => "stdin closed",
+            Self::Signal => "shutdown signal",
+            Self::ClientGone => "client gone",
+            Self::StdoutFailed => "stdout write failed",
+            Self::Disconnected => "connection error",
+            Self::IdleTimeout => "idle timeout",
+            Self::Reconnect => "reconnect requested",
+            Self::AcpVersion => "unsupported ACP version",
+            Self::MaxAge => "max session age",
+        }
+    }
+
+    /// Close code for the bridge: 1000 for a normal exit (1001 when Zed
+    /// went away), 4000-range (application-defined) otherwise.
+    fn close_code(self) -> CloseCode {
+        match self {
+            Self::StdinClosed | Self::Signal => CloseCode::Normal,
+            Self::ClientGone => CloseCode::Away,
+            Self::IdleTimeout => CloseCode::Library(4000),
+            Self::StdoutFailed => CloseCode::Library(4001),
+            Self::Disconnected => CloseCode::Library(4002),
+            Self::Reconnect => CloseCode::Library(4003),
+            Self::AcpVersion => CloseCode::Library(4004),
+            Self::MaxAge => CloseCode::Library(4005),
+        }
+    }
+
+    /// How a failed stdout write ends the session.
+    fn stdout_error(e: &io::Error) -> Self {
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            info!("Zed closed stdout, shutting down");
+            Self::ClientGone
+        } else if e.kind() == io::ErrorKind::TimedOut {
+            error!(error = %e, "Zed stopped reading stdout, shutting down");
+            Self::StdoutFailed
+        } else {
+            error!(error = %e, "Failed to write to stdout");
+            Self::StdoutFailed
+        }
+    }
+}
+
+/// Why the agent exited, as the process exit status, so a supervisor can
+/// key its retry policy on the reason. Bad command-line usage exits 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    /// Zed closed stdin or went away, or a shutdown signal arrived.
+    Clean,
+    /// Anything not covered below, e.g. invalid configuration or stdout
+    /// failing.
+    Failed,
+    /// The bridge was unreachable at startup, or reconnecting gave up.
+    ConnectFailed,
+    /// The bridge rejected the handshake (HTTP 401/403), over the auth token
+    /// or the `Origin`. They share a code because a bare 403 can't be told
+    /// apart, and either way retrying unchanged won't help.
+    AuthFailed,
+    /// The bridge answered, but not as a compatible WebSocket server (a
+    /// failed upgrade or a subprotocol mismatch).
+    Protocol,
+    /// Nothing flowed for `OOK_IDLE_TIMEOUT_SECS`.
+    IdleTimeout,
+    /// The bridge speaks an unsupported ACP version (`OOK_CHECK_ACP_VERSION`).
+    AcpVersion,
+    /// `--once` got no response within `OOK_ONCE_TIMEOUT_SECS`.
+    NoResponse,
+}
+
+impl Exit {
+    fn code(self) -> u8 {
+        match self {
+            Self::Clean => 0,
+            Self::Failed => 1,
+            Self::ConnectFailed => 10,
+            Self::AuthFailed => 11,
+            Self::Protocol => 12,
+            Self::IdleTimeout => 13,
+            Self::AcpVersion => 14,
+            Self::NoResponse => 15,
+        }
+    }
+
+    /// The exit for a connect that failed for good.
+    fn connect_error(e: &WsError) -> Self {
+        // An origin rejection is a 403 too, so it goes first
+        if is_origin_rejection(e) || is_auth_rejection(e) {
+            return Self::AuthFailed;
+        }
+        match e {
+            WsError::Http(_) | WsError::HttpFormat(_) | WsError::Protocol(_) => Self::Protocol,
+            _ => Self::ConnectFailed,
+        }
+    }
+}
+
+/// Log the final line for why the agent stopped (`None`: it was
+/// disconnected at the time) and pick the exit status.
+fn finish(end: Option<SessionEnd>) -> Exit {
+    let reason = end.map_or("shutdown while disconnected", SessionEnd::reason);
+    let exit = match end {
+        None
+        | Some(
+            SessionEnd::StdinClosed
+            | SessionEnd::Signal
+            | SessionEnd::ClientGone
+            | SessionEnd::MaxAge,
+        ) => Exit::Clean,
+        Some(SessionEnd::StdoutFailed) => Exit::Failed,
+        // The loop reconnects on these, so they never end the agent
+        Some(SessionEnd::Disconnected | SessionEnd::Reconnect) => Exit::ConnectFailed,
+        Some(SessionEnd::IdleTimeout) => Exit::IdleTimeout,
+        Some(SessionEnd::AcpVersion) => Exit::AcpVersion,
+    };
+    if matches!(exit, Exit::Clean | Exit::IdleTimeout) {
+        info!(
+            reason,
+            exit_code = exit.code(),
+            "Ook extension shutting down"
+        );
+    } else {
+        error!(
+            reason,
+            exit_code = exit.code(),
+            "Ook extension exiting after an error"
+        );
+    }
+    exit
+}
+
+/// Candidate bridge hosts, in failover order: a comma-separated list from
+/// `--host`, `OOK_REMOTE_HOSTS`, or `OOK_REMOTE_HOST`, else the Lima
+/// default unless `OOK_NO_DEFAULT_HOST` forbids guessing.
+fn get_remote_hosts(cli: Option<String>, config: &Config) -> Result<Vec<String>, String> {
+    let lists = match cli {
+        Some(list) => vec![list],
+        None => config
+            .remote_hosts
+            .clone()
+            .or_else(|| config.remote_host.clone().map(|host| vec![host]))
+            .unwrap_or_default(),
+    };
+    let hosts: Vec<String> = lists
+        .iter()
+        .flat_map(|list| list.split(','))
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .map(|host| unbracket(host.to_string()))
+        .collect();
+    if !hosts.is_empty() {
+        Ok(hosts)
+    } else if env_flag("OOK_NO_DEFAULT_HOST") {
+        Err("No bridge host configured and OOK_NO_DEFAULT_HOST is set; \
+             set OOK_REMOTE_HOST (or OOK_REMOTE_HOSTS, OOK_REMOTE_URL, or --host)"
+            .to_string())
+    } else {
+        Ok(vec![default_remote_host(&local_hostname())])
+    }
+}
+
+fn local_hostname() -> String {
+    hostname::get()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// `lima-<hostname>-sandbox`, from the first label of the local hostname in
+/// lowercase: a machine reporting `MyLaptop.local` still gets
+/// `lima-mylaptop-sandbox`.
+fn default_remote_host(hostname: &str) -> String {
+    let label = hostname.split('.').next().unwrap_or_default();
+    format!("lima-{}-sandbox", label.to_lowercase())
+}
+
+/// The `OOK_SEND_HELLO` frame sent ahead of any ACP traffic on each
+/// connection, so the bridge can log which agent it is talking to.
+fn hello_message(features: &[&str]) -> String {
+    json!({
+        "agent_version": env!("CARGO_PKG_VERSION"),
+        "features": features,
+        "pid": std::process::id(),
+        "hostname": local_hostname(),
+    })
+    .to_string()
+}
+
+/// `--port`, then `OOK_REMOTE_PORT` or the config file, then the bridge's
+/// port file, then `DEFAULT_PORT`.
+fn get_remote_port(cli: Option<u16>, config: &Config) -> Result<u16, String> {
+    if let Some(port) = cli.or(config.remote_port) {
+        return Ok(port);
+    }
+    Ok(read_port_file()?.unwrap_or(DEFAULT_PORT))
+}
+
+/// Where the bridge records the port it bound: `OOK_PORT_FILE`, else
+/// `~/.ook/bridge.port`.
+fn port_file_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("OOK_PORT_FILE") {
+        return Some(PathBuf::from(path));
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(".ook").join("bridge.port"))
+}
+
+/// The port in the bridge's port file. A missing default file just means
+/// there is none; a missing `OOK_PORT_FILE`, or either file holding
+/// anything but a port number, is an error.
+fn read_port_file() -> Result<Option<u16>, String> {
+    let explicit = env::var_os("OOK_PORT_FILE").is_some();
+    let Some(path) = port_file_path() else {
+        return Ok(None);
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit => return Ok(None),
+        Err(e) => {
+            return Err(format!(
+                "Failed to read port file {}: {}",
+                path.display(),
+                e
+            ))
+        }
+    };
+    let contents = contents.trim();
+    let port = contents
+        .parse::<u16>()
+        .ok()
+        .filter(|&port| port != 0)
+        .ok_or_else(|| {
+            format!(
+                "Port file {} does not hold a port number (1-65535): {:?}",
+                path.display(),
+                preview(contents)
+            )
+        })?;
+    info!(port, path = %path.display(), "Using the bridge port from its port file");
+    Ok(Some(port))
+}
+
+fn get_remote_scheme(cli: Option<String>, config: &Config) -> Result<String, String> {
+    let scheme = cli
+        .or_else(|| config.remote_scheme.clone())
+        .unwrap_or_else(|| DEFAULT_SCHEME.to_string());
+    match scheme.as_str() {
+        "ws" | "wss" => Ok(scheme),
+        other => Err(format!(
+            "Unsupported scheme {:?} (expected \"ws\" or \"wss\")",
+            other
+        )),
+    }
+}
+
+/// Accept `[::1]` as well as `::1` for the host; the resolver wants it bare.
+fn unbracket(host: String) -> String {
+    match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(bare) => bare.to_string(),
+        None => host,
+    }
+}
+
+/// Host as written in a URL authority: IPv6 literals need brackets
+/// (`ws://[::1]:8647`), IPv4 addresses and names are used as-is.
+fn url_host(host: &str) -> Cow<'_, str> {
+    if host.parse::<Ipv6Addr>().is_ok() {
+        Cow::Owned(format!("[{}]", host))
+    } else {
+        Cow::Borrowed(host)
+    }
+}
+
+/// Full bridge URL (`OOK_REMOTE_URL`), replacing the host/port/scheme
+/// composition for paths and other setups it cannot express.
+fn get_remote_url(config: &Config) -> Result<Option<Url>, String> {
+    let Some(raw) = &config.remote_url else {
+        return Ok(None);
+    };
+    let url = Url::parse(raw).map_err(|e| format!("Invalid OOK_REMOTE_URL {:?}: {}", raw, e))?;
+    if !matches!(url.scheme(), "ws" | "wss") {
+        return Err(format!(
+            "Unsupported OOK_REMOTE_URL scheme {:?} (expected \"ws\" or \"wss\")",
+            url.scheme()
+        ));
+    }
+    if url.host().is_none() {
+        return Err(format!("OOK_REMOTE_URL {:?} has no host", raw));
+    }
+    Ok(Some(url))
+}
+
+/// HTTP CONNECT proxy from `OOK_HTTPS_PROXY`, falling back to the
+/// conventional `HTTPS_PROXY` / `https_proxy`, bypassed for the hosts in
+/// `NO_PROXY` / `no_proxy`.
+fn get_proxy() -> Result<Option<Proxy>, String> {
+    let bypass = ["NO_PROXY", "no_proxy"]
+        .iter()
+        .find_map(|name| env::var(name).ok())
+        .map(|list| NoProxy::parse(&list))
+        .unwrap_or_default();
+    ["OOK_HTTPS_PROXY", "HTTPS_PROXY", "https_proxy"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()))
+        .map(|raw| Proxy::parse(&raw).map(|proxy| proxy.with_bypass(bypass)))
+        .transpose()
+}
+
+/// Address to accept the bridge on instead of dialing it (`OOK_LISTEN_ADDR`).
+fn get_listen_addr() -> Option<String> {
+    env::var("OOK_LISTEN_ADDR")
+        .ok()
+        .filter(|addr| !addr.is_empty())
+}
+
+/// Where to serve Prometheus scrapes (`OOK_METRICS_ADDR`).
+fn get_metrics_addr() -> Option<String> {
+    env::var("OOK_METRICS_ADDR")
+        .ok()
+        .filter(|addr| !addr.is_empty())
+}
+
+/// Windows named pipe for a bridge on the same host (`OOK_REMOTE_PIPE`).
+fn get_remote_pipe() -> Option<String> {
+    env::var("OOK_REMOTE_PIPE")
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// Capture file for every forwarded message (`OOK_RECORD_PATH`).
+fn get_record_path() -> Option<PathBuf> {
+    env::var_os("OOK_RECORD_PATH").map(PathBuf::from)
+}
+
+/// Where to write our PID (`OOK_PID_FILE`).
+fn get_pid_file() -> Option<PathBuf> {
+    env::var_os("OOK_PID_FILE").map(PathBuf::from)
+}
+
+/// Capture to play back instead of connecting (`OOK_REPLAY_PATH`).
+fn get_replay_path() -> Option<PathBuf> {
+    env::var_os("OOK_REPLAY_PATH").map(PathBuf::from)
+}
+
+/// Where to keep the liveness snapshot (`OOK_STATUS_FILE`).
+fn get_status_file() -> Option<PathBuf> {
+    env::var_os("OOK_STATUS_FILE").map(PathBuf::from)
+}
+
+/// `OOK_SESSION_ID`, or a fresh UUID.
+fn get_session_id() -> String {
+    env::var("OOK_SESSION_ID")
+        .ok()
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// True when the env var is set to `1` or `true`.
+fn env_flag(name: &str) -> bool {
+    matches!(env::var(name).as_deref(), Ok("1") | Ok("true"))
+}
+
+/// Extra handshake headers: `X-Ook-Session`, `Authorization` (`auth`, from
+/// `auth_header`), any
+/// `Name: value` pairs from `OOK_EXTRA_HEADERS` separated by `;`, and
+/// `Sec-WebSocket-Protocol: $OOK_SUBPROTOCOL`.
+fn get_handshake_headers(
+    config: &Config,
+    session_id: &str,
+    auth: Option<HeaderValue>,
+) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+
+    let value = HeaderValue::from_str(session_id)
+        .map_err(|_| "OOK_SESSION_ID contains characters not allowed in a header".to_string())?;
+    headers.insert(SESSION_HEADER, value);
+
+    if let Some(value) = auth {
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    if let Some(extra) = &config.extra_headers {
+        for pair in extra.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, value) = pair.split_once(':').ok_or_else(|| {
+                format!(
+                    "Invalid OOK_EXTRA_HEADERS entry {:?} (expected \"Name: value\")",
+                    pair
+                )
+            })?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("Invalid header name in OOK_EXTRA_HEADERS: {:?}", name))?;
+            let value = HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("Invalid header value for {} in OOK_EXTRA_HEADERS", name))?;
+            headers.append(name, value);
+        }
+    }
+
+    // tungstenite sends no Origin of its own, so unset means none at all
+    if let Ok(origin) = env::var("OOK_ORIGIN") {
+        let value = HeaderValue::from_str(&origin)
+            .map_err(|_| "OOK_ORIGIN contains characters not allowed in a header".to_string())?;
+        headers.insert(ORIGIN, value);
+    }
+
+    // Comma-separated without spaces: tungstenite matches the bridge's
+    // selection against the list split on bare commas
+    let protocols = env::var("OOK_SUBPROTOCOL")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join(",");
+    if !protocols.is_empty() {
+        let value = HeaderValue::from_str(&protocols).map_err(|_| {
+            "OOK_SUBPROTOCOL contains characters not allowed in a header".to_string()
+        })?;
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, value);
+    }
+
+    Ok(headers)
+}
+
+/// `Authorization: Bearer <token>`, if a token is set (`auth_token`). An
+/// `OOK_AUTH_TOKEN_CMD` gets as long as a connect attempt.
+async fn auth_header(config: &Config) -> Result<Option<HeaderValue>, String> {
+    let timeout = get_connect_timeout(config);
+    let Some(token) = auth_token::resolve(config.auth_token.as_deref(), timeout).await? else {
+        return Ok(None);
+    };
+    let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+        .map_err(|_| "The auth token contains characters not allowed in a header".to_string())?;
+    value.set_sensitive(true);
+    Ok(Some(value))
+}
+
+/// Maximum reconnect attempts after the bridge drops; `0` retries forever.
+fn get_reconnect_max_retries(config: &Config) -> u32 {
+    config
+        .reconnect_max_retries
+        .unwrap_or(DEFAULT_RECONNECT_MAX_RETRIES)
+}
+
+/// Consecutive failed reconnects after which attempts move to the cooldown
+/// schedule; `0` disables the breaker.
+fn get_reconnect_cooldown_after(config: &Config) -> Option<u32> {
+    let after = config
+        .reconnect_cooldown_after
+        .unwrap_or(DEFAULT_RECONNECT_COOLDOWN_AFTER);
+    (after > 0).then_some(after)
+}
+
+fn get_reconnect_cooldown(config: &Config) -> Duration {
+    let secs = config
+        .reconnect_cooldown_secs
+        .filter(|&secs| secs > 0)
+        .unwrap_or(DEFAULT_RECONNECT_COOLDOWN_SECS);
+    Duration::from_secs(secs)
+}
+
+/// How stdin is split into messages (`OOK_FRAMING`, default `lines`).
+///
+/// `OOK_STDIO_FRAMING=lsp` switches both stdin and stdout to `Content-Length`
+/// frames and can't be combined with `OOK_FRAMING`, which only splits NDJSON.
+fn get_framing() -> Result<Framing, String> {
+    match env::var("OOK_STDIO_FRAMING").as_deref() {
+        Ok("lsp") if env::var_os("OOK_FRAMING").is_some() => {
+            return Err("OOK_STDIO_FRAMING=lsp cannot be combined with OOK_FRAMING".to_string())
+        }
+        Ok("lsp") => return Ok(Framing::Lsp),
+        Ok("ndjson") | Err(_) => {}
+        Ok(other) => {
+            return Err(format!(
+                "Unsupported OOK_STDIO_FRAMING {:?} (expected \"ndjson\" or \"lsp\")",
+                other
+            ))
+        }
+    }
+    match env::var("OOK_FRAMING") {
+        Ok(value) => Framing::parse(&value),
+        Err(_) => Ok(Framing::Lines),
+    }
+}
+
+/// The first `OOK_FAULT_*` variable set, for warning that this build
+/// ignores them.
+#[cfg(not(feature = "fault-injection"))]
+fn fault_vars_set() -> Option<&'static str> {
+    [
+        "OOK_FAULT_DROP_EVERY_N",
+        "OOK_FAULT_DELAY_MS",
+        "OOK_FAULT_CLOSE_AFTER_N",
+        "OOK_FAULT_PANIC_AFTER_N",
+    ]
+    .into_iter()
+    .find(|name| env::var_os(name).is_some())
+}
+
+/// Warn when bridge timestamps are this far from our clock
+/// (`OOK_MAX_CLOCK_SKEW_SECS`); unset or `0` disables the check.
+fn get_clock_skew() -> Option<SkewCheck> {
+    let max = env::var("OOK_MAX_CLOCK_SKEW_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)?;
+    let field =
+        env::var("OOK_CLOCK_SKEW_FIELD").unwrap_or_else(|_| skew::DEFAULT_FIELD.to_string());
+    info!(field = %field, max_clock_skew_secs = max.as_secs(), "Checking bridge timestamps for clock skew");
+    Some(SkewCheck::new(&field, max))
+}
+
+/// When bridge->zed writes reach stdout (`OOK_FLUSH_POLICY`).
+fn get_flush_policy() -> Result<FlushPolicy, String> {
+    match env::var("OOK_FLUSH_POLICY") {
+        Ok(value) => FlushPolicy::parse(&value),
+        Err(_) => Ok(FlushPolicy::Immediate),
+    }
+}
+
+/// Reconnect (and, with `OOK_PING_JITTER=1`, keepalive) jitter;
+/// `OOK_JITTER_PCT=0` turns it off.
+fn get_jitter() -> Result<Jitter, String> {
+    let percent = match env::var("OOK_JITTER_PCT") {
+        Ok(value) => value
+            .parse()
+            .ok()
+            .filter(|&percent| percent <= 100)
+            .ok_or_else(|| {
+                format!(
+                    "Unsupported OOK_JITTER_PCT {:?} (expected a percentage from 0 to 100)",
+                    value
+                )
+            })?,
+        Err(_) => jitter::DEFAULT_JITTER_PCT,
+    };
+    Ok(Jitter::new(percent, env_flag("OOK_PING_JITTER")))
+}
+
+/// Which bridge close frames are reported to Zed (`OOK_REPORT_CLOSE`).
+fn get_report_close() -> Result<ReportClose, String> {
+    match env::var("OOK_REPORT_CLOSE") {
+        Ok(value) => ReportClose::parse(&value),
+        Err(_) => Ok(ReportClose::App),
+    }
+}
+
+/// Longest stdin line we will buffer before skipping it.
+fn get_max_line_bytes() -> usize {
+    env::var("OOK_MAX_LINE_BYTES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_MAX_LINE_BYTES)
+}
+
+/// Largest WebSocket message or frame relayed in either direction.
+fn get_max_message_bytes() -> usize {
+    env::var("OOK_MAX_MESSAGE_BYTES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES)
+}
+
+/// Limit on each connect attempt; `0` leaves it to the OS.
+fn get_connect_timeout(config: &Config) -> Option<Duration> {
+    let secs = config
+        .connect_timeout_secs
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Local address for outbound TCP connections (`OOK_BIND_ADDR`), e.g. to
+/// pick the interface on a multi-homed host; unset lets the OS choose.
+fn get_bind_addr() -> Result<Option<IpAddr>, String> {
+    let Ok(value) = env::var("OOK_BIND_ADDR") else {
+        return Ok(None);
+    };
+    let addr = value.trim().trim_start_matches('[').trim_end_matches(']');
+    addr.parse().map(Some).map_err(|_| {
+        format!(
+            "Invalid OOK_BIND_ADDR {:?} (expected an IPv4 or IPv6 address)",
+            value
+        )
+    })
+}
+
+/// OS-level TCP keepalive for the bridge socket: off unless
+/// `OOK_TCP_KEEPALIVE_SECS` (the idle time before probing) is set and non-zero.
+fn get_tcp_keepalive() -> Option<TcpKeepalive> {
+    let positive = |name: &str| {
+        env::var(name)
+            .ok()
+            .and_then(|n| n.parse::<u64>().ok())
+            .filter(|&n| n > 0)
+    };
+    let idle = positive("OOK_TCP_KEEPALIVE_SECS")?;
+    let interval =
+        positive("OOK_TCP_KEEPALIVE_INTERVAL_SECS").unwrap_or(DEFAULT_TCP_KEEPALIVE_INTERVAL_SECS);
+    let retries = positive("OOK_TCP_KEEPALIVE_RETRIES")
+        .map_or(DEFAULT_TCP_KEEPALIVE_RETRIES, |n| {
+            u32::try_from(n).unwrap_or(u32::MAX)
+        });
+    Some(TcpKeepalive {
+        idle: Duration::from_secs(idle),
+        interval: Duration::from_secs(interval),
+        retries,
+    })
+}
+
+/// Interval between keepalive pings to the bridge; `0` disables them.
+fn get_ping_interval(config: &Config) -> Option<Duration> {
+    let secs = config
+        .ping_interval_secs
+        .unwrap_or(DEFAULT_PING_INTERVAL_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Interval between JSON-RPC pings through the bridge; unset or `0`
+/// disables them.
+fn get_app_ping_interval() -> Option<Duration> {
+    env::var("OOK_APP_PING_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Interval between metrics log lines; unset or `0` disables them.
+fn get_metrics_interval() -> Option<Duration> {
+    env::var("OOK_METRICS_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Window for coalescing bridge->zed notifications (`OOK_COALESCE_MS`);
+/// unset or `0` forwards everything immediately.
+fn get_coalesce_window() -> Option<Duration> {
+    env::var("OOK_COALESCE_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&ms| ms > 0)
+        .map(Duration::from_millis)
+}
+
+/// Hosts the agent may connect to (`OOK_ALLOWED_HOSTS`); `None` allows any.
+fn get_allowed_hosts() -> Result<Option<HostAllowlist>, String> {
+    env::var("OOK_ALLOWED_HOSTS")
+        .ok()
+        .map(|value| HostAllowlist::parse(&value))
+        .transpose()
+}
+
+/// Split stdout writes of larger messages into pieces this big; unset or
+/// `0` writes each message at once.
+fn get_chunk_write_bytes() -> Option<usize> {
+    env::var("OOK_CHUNK_WRITE_BYTES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+}
+
+/// How long `--once` waits for the response (`OOK_ONCE_TIMEOUT_SECS`).
+fn get_once_timeout() -> Duration {
+    env::var("OOK_ONCE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map_or(DEFAULT_ONCE_TIMEOUT, Duration::from_secs)
+}
+
+/// Quiet time on stdout after which a keepalive notification is written;
+/// unset or `0` writes none.
+fn get_stdout_keepalive() -> Option<Duration> {
+    env::var("OOK_STDOUT_KEEPALIVE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Limit on one stdout write or flush before Zed counts as stuck; unset or
+/// `0` waits indefinitely.
+fn get_stdout_write_timeout() -> Option<Duration> {
+    env::var("OOK_STDOUT_WRITE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Bridge ACP version check, on with `OOK_CHECK_ACP_VERSION=1`: for the
+/// `OOK_ACP_VERSIONS` range, else `SUPPORTED_VERSIONS`.
+fn get_acp_version() -> Result<Option<AcpVersion>, String> {
+    if !env_flag("OOK_CHECK_ACP_VERSION") {
+        return Ok(None);
+    }
+    let supported = match env::var("OOK_ACP_VERSIONS") {
+        Ok(value) => acp_version::parse_versions(&value)?,
+        Err(_) => acp_version::SUPPORTED_VERSIONS,
+    };
+    Ok(Some(AcpVersion::new(supported)))
+}
+
+/// Aggregated problem warning; `OOK_ANOMALY_THRESHOLD=0` turns it off.
+fn get_health() -> Health {
+    let threshold = env::var("OOK_ANOMALY_THRESHOLD")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(health::DEFAULT_ANOMALY_THRESHOLD);
+    let window = env::var("OOK_ANOMALY_WINDOW_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map_or(health::DEFAULT_ANOMALY_WINDOW, Duration::from_secs);
+    Health::new(threshold, window)
+}
+
+/// How long to keep forwarding bridge messages after stdin EOF
+/// (`OOK_SHUTDOWN_GRACE_SECS`); unset or `0` closes straight away.
+fn get_shutdown_grace() -> Option<Duration> {
+    env::var("OOK_SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// How long one bridge connection may last before it is closed and a new
+/// one made (`OOK_MAX_SESSION_SECS`); unset or `0` disables it.
+fn get_max_session() -> Option<Duration> {
+    env::var("OOK_MAX_SESSION_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// How long the session may sit with no traffic before the agent exits;
+/// unset or `0` disables the timeout.
+fn get_idle_timeout(config: &Config) -> Option<Duration> {
+    config
+        .idle_timeout_secs
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Request/response id tracking (`OOK_TRACK_IDS=1`), warning about
+/// requests unanswered after `OOK_TRACK_IDS_TIMEOUT_SECS` and, with
+/// `OOK_SLOW_RESPONSE_MS` (which turns tracking on by itself), about slow
+/// responses from the bridge.
+fn get_id_tracker() -> Option<IdTracker> {
+    let slow = env::var("OOK_SLOW_RESPONSE_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&ms| ms > 0)
+        .map(Duration::from_millis);
+    if !env_flag("OOK_TRACK_IDS") && slow.is_none() {
+        return None;
+    }
+    let timeout = env::var("OOK_TRACK_IDS_TIMEOUT_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(DEFAULT_TRACK_IDS_TIMEOUT_SECS);
+    Some(IdTracker::new(Duration::from_secs(timeout), slow))
+}
+
+/// A timer whose first tick is one `period` from now, or `None` if disabled.
+fn timer(period: Option<Duration>) -> Option<Interval> {
+    period.map(|period| {
+        let mut timer = interval_at(Instant::now() + period, period);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        timer
+    })
+}
+
+/// Wait for the next tick, or forever if the timer is disabled.
+async fn next_tick(timer: &mut Option<Interval>) -> Instant {
+    match timer {
+        Some(timer) => timer.tick().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sleep until `timeout` after `last_activity`, or forever if disabled.
+async fn idle_deadline(last_activity: Instant, timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep_until(last_activity + timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sleep until `at`, or forever if `None`.
+async fn deadline(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Outcome of trying to re-establish the bridge connection.
+enum Reconnect {
+    Connected(Box<WsStream>),
+    /// `max_retries` attempts failed.
+    GaveUp,
+    /// Zed closed stdin or a shutdown signal arrived while disconnected.
+    Shutdown,
+}
+
+/// The Zed-facing half of the agent, which outlives any one bridge connection.
+struct Zed {
+    stdin: StdinChannel,
+    stdout: BufWriter<Box<dyn AsyncWrite + Unpin + Send>>,
+    /// Stdin lines not yet delivered to the bridge.
+    outbound: OutboundQueue,
+    /// Drop stdin lines that are not valid JSON (`OOK_VALIDATE_JSON=1`).
+    validate_json: bool,
+    /// Lines longer than this would exceed the bridge's message limit.
+    max_message_bytes: usize,
+    /// Capture of both directions, when `OOK_RECORD_PATH` is set.
+    recorder: Option<Recorder>,
+    /// JSON-RPC id correlation, when `OOK_TRACK_IDS` is set.
+    ids: Option<IdTracker>,
+    /// Checks the bridge's `initialize` response (`OOK_CHECK_ACP_VERSION`).
+    acp_version: Option<AcpVersion>,
+    /// Lines accepted from stdin so far; non-zero means Zed is mid-session.
+    lines_read: u64,
+    /// When the first stdin line was accepted, for the time-to-first-message log.
+    first_line_at: Option<Instant>,
+    /// Log whole message bodies instead of redacted previews (`OOK_LOG_BODIES`).
+    log_bodies: bool,
+    /// Numbers the forwarding debug logs.
+    seq: Sequence,
+    /// Limits the forwarding debug logs to some methods (`OOK_LOG_METHODS`).
+    log_methods: Option<MethodFilter>,
+    /// Write `Content-Length` frames instead of NDJSON lines
+    /// (`OOK_STDIO_FRAMING=lsp`).
+    lsp_stdout: bool,
+    flush_policy: FlushPolicy,
+    /// Write `ook/connectionState` notifications (`OOK_EMIT_STATE`).
+    emit_state: bool,
+    /// Strip carriage returns in both directions (`OOK_NORMALIZE_EOL`).
+    normalize_eol: bool,
+    /// Channel routing, when `OOK_MUX` is set.
+    mux: Option<Mux>,
+    /// Bridge timestamp check, when `OOK_MAX_CLOCK_SKEW_SECS` is set.
+    skew: Option<SkewCheck>,
+    /// `_ook_seq` numbering of outgoing objects, when `OOK_INJECT_SEQ` is set.
+    seq_tag: Option<SeqTag>,
+    /// Sums up recurring problems in one warning (`OOK_ANOMALY_THRESHOLD`).
+    health: Health,
+    /// Largest single stdout write for a message (`OOK_CHUNK_WRITE_BYTES`).
+    chunk_write_bytes: Option<usize>,
+    /// Longest a message write or flush may block (`OOK_STDOUT_WRITE_TIMEOUT_SECS`).
+    stdout_write_timeout: Option<Duration>,
+    /// When the oldest unflushed write must be flushed by, under `coalesce`.
+    flush_due: Option<Instant>,
+    /// Quiet time on stdout before a keepalive line (`OOK_STDOUT_KEEPALIVE_SECS`).
+    stdout_keepalive: Option<Duration>,
+    /// When the last message was written to stdout.
+    last_stdout_write: Instant,
+}
+
+impl Zed {
+    /// Read one line from stdin into the outbound queue.
+    ///
+    /// Returns `false` once stdin is closed or unreadable.
+    async fn read_line(&mut self) -> bool {
+        match self.stdin.next_line().await {
+            Ok(Some(mut line)) => {
+                // ACP NDJSON never contains blank lines
+                if line.trim().is_empty() {
+                    return true;
+                }
+                if self.normalize_eol {
+                    if let Cow::Owned(normalized) = normalize_eol(&line) {
+                        line = normalized;
+                    }
+                }
+                let line = match &mut self.mux {
+                    Some(mux) => match mux.wrap(&line) {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            warn!(error = %e, preview = preview(&line), "Dropping stdin line not fit for OOK_MUX");
+                            return true;
+                        }
+                    },
+                    None => line,
+                };
+                let line = match self.seq_tag.as_mut().and_then(|seq_tag| seq_tag.tag(&line)) {
+                    Some(tagged) => tagged,
+                    None => line,
+                };
+                if line.len() > self.max_message_bytes {
+                    error!(
+                        direction = "zed->bridge",
+                        bytes = line.len(),
+                        max_message_bytes = self.max_message_bytes,
+                        "Message exceeds OOK_MAX_MESSAGE_BYTES, dropping it"
+                    );
+                    self.health.record(Anomaly::Oversized);
+                    return true;
+                }
+                if self.validate_json {
+                    if let Err(e) = serde_json::from_str::<serde_json::Value>(&line) {
+                        warn!(error = %e, preview = preview(&line), "Dropping invalid JSON from stdin");
+                        self.health.record(Anomaly::InvalidJson);
+                        return true;
+                    }
+                }
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.record(record::ZED_TO_BRIDGE, &line);
+                }
+                if let Some(ids) = &mut self.ids {
+                    ids.observe(Direction::ZedToBridge, &line);
+                }
+                if let Some(acp_version) = &mut self.acp_version {
+                    acp_version.observe_zed(&line);
+                }
+                self.outbound.push(line);
+                self.lines_read += 1;
+                self.first_line_at.get_or_insert_with(Instant::now);
+                true
+            }
+            Ok(None) => {
+                info!("Stdin closed, shutting down");
+                false
+            }
+            Err(e) => {
+                error!(error = %e, "Error reading stdin");
+                false
+            }
+        }
+    }
+
+    /// Write one message from the bridge to stdout: checks it is UTF-8,
+    /// records and tracks it if enabled, frames it, and flushes (or, under
+    /// `OOK_FLUSH_POLICY=coalesce`, schedules the flush).
+    ///
+    /// A message that isn't UTF-8 can't be an ACP message, so it is dropped
+    /// with a warning and `Ok(false)` returned; `Err` means stdout failed.
+    async fn forward_to_stdout(&mut self, bytes: &[u8]) -> io::Result<bool> {
+        let Ok(text) = std::str::from_utf8(bytes) else {
+            warn!(
+                direction = "bridge->zed",
+                bytes = bytes.len(),
+                "Dropping non-UTF-8 message from bridge"
+            );
+            return Ok(false);
+        };
+        let text = if self.normalize_eol {
+            normalize_eol(text)
+        } else {
+            Cow::Borrowed(text)
+        };
+        let text = text.as_ref();
+        if let Some(acp_version) = &mut self.acp_version {
+            match acp_version.check_bridge(text) {
+                Verdict::Forward => {}
+                Verdict::Refuse(response) => {
+                    self.write_own(response).await?;
+                    return Ok(false);
+                }
+                Verdict::Drop => return Ok(false),
+            }
+        }
+        let seq = self.seq.next_to_zed();
+        self.log_forward("bridge->zed", seq, text);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(record::BRIDGE_TO_ZED, text);
+        }
+        if let Some(ids) = &mut self.ids {
+            ids.observe(Direction::BridgeToZed, text);
+        }
+        if let Some(skew) = &mut self.skew {
+            skew.observe(text);
+        }
+        match &mut self.mux {
+            Some(mux) => {
+                let Some(tagged) = mux.unwrap(text) else {
+                    return Ok(false);
+                };
+                self.write_message(&tagged).await?;
+            }
+            None => self.write_message(text).await?,
+        }
+        match self.flush_policy {
+            FlushPolicy::Immediate => self.flush_stdout().await?,
+            FlushPolicy::Coalesce => {
+                self.flush_due
+                    .get_or_insert_with(|| Instant::now() + flush::COALESCE_FLUSH_DELAY);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Flush stdout, including writes held back by `coalesce`.
+    async fn flush_stdout(&mut self) -> io::Result<()> {
+        self.flush_due = None;
+        write_timeout(self.stdout_write_timeout, self.stdout.flush()).await
+    }
+
+    /// Write one message to stdout in the stdio framing, without flushing: an
+    /// NDJSON line (adding the newline if missing), or a `Content-Length`
+    /// frame.
+    async fn write_message(&mut self, message: &str) -> io::Result<()> {
+        // Set first: a failed write must not leave the keepalive due at once
+        self.last_stdout_write = Instant::now();
+        let timeout = self.stdout_write_timeout;
+        write_timeout(timeout, self.write_framed(message)).await
+    }
+
+    /// `write_message` without the timeout.
+    async fn write_framed(&mut self, message: &str) -> io::Result<()> {
+        if self.lsp_stdout {
+            let body = message.strip_suffix('\n').unwrap_or(message);
+            let header = format!("Content-Length: {}\r\n\r\n", body.len());
+            self.stdout.write_all(header.as_bytes()).await?;
+            self.write_chunked(body.as_bytes()).await?;
+        } else {
+            self.write_chunked(message.as_bytes()).await?;
+            if !message.ends_with('\n') {
+                self.stdout.write_all(b"\n").await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `write_all`, split into `OOK_CHUNK_WRITE_BYTES` pieces with a yield
+    /// after each, so one huge message doesn't hold up the runtime. Only the
+    /// writes are split; the bytes on stdout are the same.
+    async fn write_chunked(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let Some(chunk) = self.chunk_write_bytes.filter(|&chunk| bytes.len() > chunk) else {
+            return self.stdout.write_all(bytes).await;
+        };
+        for piece in bytes.chunks(chunk) {
+            self.stdout.write_all(piece).await?;
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+
+    /// The per-message debug log, for messages that pass `OOK_LOG_METHODS`.
+    /// `seq` is from `Sequence`.
+    fn log_forward(&self, direction: &'static str, seq: (u64, u64), message: &str) {
+        if !tracing::enabled!(Level::DEBUG) {
+            return;
+        }
+        if let Some(filter) = &self.log_methods {
+            if !filter.matches(message) {
+                return;
+            }
+        }
+        let (seq, elapsed_us) = seq;
+        debug!(
+            direction,
+            seq,
+            elapsed_us,
+            message = %self.log_body(message),
+            "Forwarding message"
+        );
+    }
+
+    fn log_body<'a>(&self, message: &'a str) -> LogBody<'a> {
+        LogBody {
+            message,
+            full: self.log_bodies,
+        }
+    }
+
+    /// Tell Zed about a failure with a synthetic JSON-RPC error, but only
+    /// once a session is underway; on a cold start there is nobody to tell.
+    async fn report_error(&mut self, code: i64, message: &str) {
+        if self.lines_read == 0 {
+            return;
+        }
+
+        let response = acp::error_response(code, message);
+        if let Err(e) = self.write_own(response).await {
+            warn!(error = %e, "Failed to write error to stdout");
+        }
+    }
+
+    /// Tell Zed about a connection state change (`OOK_EMIT_STATE`).
+    async fn emit_state(&mut self, params: serde_json::Value) {
+        if !self.emit_state {
+            return;
+        }
+        let notification = acp::notification(acp::CONNECTION_STATE, params);
+        if let Err(e) = self.write_own(notification).await {
+            warn!(error = %e, "Failed to write connection state to stdout");
+        }
+    }
+
+    /// When a keepalive line is due, if `OOK_STDOUT_KEEPALIVE_SECS` is set.
+    fn keepalive_due(&self) -> Option<Instant> {
+        self.stdout_keepalive
+            .map(|quiet| self.last_stdout_write + quiet)
+    }
+
+    /// Write a keepalive notification, which Zed ignores.
+    async fn write_keepalive(&mut self) -> io::Result<()> {
+        debug!("Stdout quiet, writing a keepalive notification");
+        self.write_own(acp::notification(acp::KEEPALIVE, json!({})))
+            .await
+    }
+
+    /// Whether the bridge's ACP version was refused, ending the session.
+    fn acp_refused(&self) -> bool {
+        self.acp_version.as_ref().is_some_and(AcpVersion::refused)
+    }
+
+    /// Write a message of the agent's own and flush; with `OOK_MUX` it goes
+    /// to every open channel.
+    async fn write_own(&mut self, message: String) -> io::Result<()> {
+        let lines = match &self.mux {
+            Some(mux) => mux.broadcast(&message),
+            None => vec![message],
+        };
+        for line in &lines {
+            self.write_message(line).await?;
+        }
+        self.flush_stdout().await
+    }
+}
+
+/// Per-direction sequence numbers and a clock for the forwarding debug
+/// logs, so they show whether the agent itself kept messages in order.
+///
+/// A send that fails is retried under a new number.
+struct Sequence {
+    started: Instant,
+    to_bridge: u64,
+    to_zed: u64,
+}
+
+impl Sequence {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            to_bridge: 0,
+            to_zed: 0,
+        }
+    }
+
+    /// `(seq, elapsed_us)` for the next zed->bridge message.
+    fn next_to_bridge(&mut self) -> (u64, u64) {
+        self.to_bridge += 1;
+        (self.to_bridge, self.elapsed_us())
+    }
+
+    /// `(seq, elapsed_us)` for the next bridge->zed message.
+    fn next_to_zed(&mut self) -> (u64, u64) {
+        self.to_zed += 1;
+        (self.to_zed, self.elapsed_us())
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+}
+
+/// Long-lived agent state shared by every bridge connection.
+struct Agent {
+    zed: Zed,
+    signals: ShutdownSignal,
+    metrics: Metrics,
+    timers: Timers,
+    /// Liveness snapshot target (`OOK_STATUS_FILE`).
+    status_file: Option<PathBuf>,
+    /// True while a bridge session is running.
+    connected: bool,
+    max_retries: u32,
+    /// Slows down and quiets reconnects during a long outage.
+    breaker: ReconnectBreaker,
+    /// Spreads reconnect and keepalive timers across agents.
+    jitter: Jitter,
+    ping_interval: Option<Duration>,
+    /// JSON-RPC ping through the bridge (`OOK_APP_PING_SECS`).
+    app_ping: Option<AppPing>,
+    idle_timeout: Option<Duration>,
+    /// Last stdin line or bridge text/ping, for the idle timeout.
+    last_activity: Instant,
+    /// Sent first on every connection (`OOK_SEND_HELLO`).
+    hello: Option<String>,
+    /// Feeds the Prometheus endpoint (`OOK_METRICS_ADDR`).
+    metrics_export: Option<watch::Sender<String>>,
+    /// Holds back bursts of bridge notifications (`OOK_COALESCE_MS`).
+    coalescer: Option<Coalescer>,
+    /// Drops back-to-back repeated notifications (`OOK_DEDUP_CONSECUTIVE`).
+    dedup: Option<Dedup>,
+    /// Which bridge close frames Zed is told about (`OOK_REPORT_CLOSE`).
+    report_close: ReportClose,
+    /// Keep forwarding this long after stdin EOF (`OOK_SHUTDOWN_GRACE_SECS`).
+    shutdown_grace: Option<Duration>,
+    /// Longest a connection may last (`OOK_MAX_SESSION_SECS`).
+    max_session: Option<Duration>,
+    /// Exit instead of reconnecting at `max_session` (`OOK_MAX_SESSION_EXIT`).
+    max_session_exit: bool,
+    /// Bridge frames queued ahead of stdout (`OOK_INBOUND_OVERFLOW`); `None`
+    /// reads the WebSocket only as fast as stdout drains.
+    inbound: Option<InboundOverflow>,
+    #[cfg(feature = "fault-injection")]
+    faults: fault::Faults,
+}
+
+impl Agent {
+    /// Whether a session that ended this way is followed by a reconnect
+    /// rather than the agent exiting.
+    fn reconnects_after(&self, end: SessionEnd) -> bool {
+        match end {
+            SessionEnd::Disconnected | SessionEnd::Reconnect => true,
+            SessionEnd::MaxAge => !self.max_session_exit,
+            SessionEnd::StdinClosed
+            | SessionEnd::Signal
+            | SessionEnd::ClientGone
+            | SessionEnd::StdoutFailed
+            | SessionEnd::IdleTimeout
+            | SessionEnd::AcpVersion => false,
+        }
+    }
+
+    /// Forward a bridge message to Zed, through the coalescer if enabled.
+    async fn forward_to_zed(&mut self, message: Vec<u8>) -> io::Result<()> {
+        if self
+            .app_ping
+            .as_mut()
+            .is_some_and(|ping| ping.answer(&message))
+        {
+            return Ok(());
+        }
+        if self
+            .dedup
+            .as_mut()
+            .is_some_and(|dedup| dedup.is_repeat(&message))
+        {
+            return Ok(());
+        }
+        let Some(coalescer) = &mut self.coalescer else {
+            return self.deliver_all(vec![message]).await;
+        };
+        let (ready, replaced) = coalescer.push(message, Instant::now());
+        self.metrics.coalesced += replaced;
+        self.deliver_all(ready).await
+    }
+
+    /// Forward whatever the coalescer is holding.
+    async fn flush_coalesced(&mut self) -> io::Result<()> {
+        let held = self
+            .coalescer
+            .as_mut()
+            .map(Coalescer::flush)
+            .unwrap_or_default();
+        self.deliver_all(held).await
+    }
+
+    async fn deliver_all(&mut self, messages: Vec<Vec<u8>>) -> io::Result<()> {
+        for message in messages {
+            #[cfg(feature = "fault-injection")]
+            if !self.faults.pass("bridge->zed").await {
+                continue;
+            }
+            if self.zed.forward_to_stdout(&message).await? {
+                self.metrics.record_to_zed(message.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Agent {
+    fn on_tick(&mut self, tick: Tick) {
+        match tick {
+            Tick::Metrics => self.metrics.log(self.zed.stdin.high_water()),
+            Tick::Ids => {
+                if let Some(ids) = &mut self.zed.ids {
+                    ids.warn_unanswered();
+                }
+            }
+            Tick::Status => {
+                if let Some(path) = &self.status_file {
+                    status::write(path, self.connected, &self.metrics);
+                }
+            }
+            Tick::Export => {
+                if let Some(export) = &self.metrics_export {
+                    export.send_replace(prometheus::render(&self.metrics, self.connected));
+                }
+            }
+        }
+    }
+}
+
+/// Periodic housekeeping timers; each is `None` when its feature is off.
+struct Timers {
+    /// Log `Agent::metrics`.
+    metrics: Option<Interval>,
+    /// Look for overdue requests in `Zed::ids`.
+    ids: Option<Interval>,
+    /// Rewrite `Agent::status_file`.
+    status: Option<Interval>,
+    /// Publish `Agent::metrics` to `Agent::metrics_export`.
+    export: Option<Interval>,
+}
+
+enum Tick {
+    Metrics,
+    Ids,
+    Status,
+    Export,
+}
+
+impl Timers {
+    /// Wait for whichever timer fires next.
+    ///
+    /// Only borrows the timers, so a `select!` arm can pass the result to
+    /// `Agent::on_tick` while other arms hold the rest of the agent.
+    async fn next(&mut self) -> Tick {
+        select! {
+            _ = next_tick(&mut self.metrics) => Tick::Metrics,
+            _ = next_tick(&mut self.ids) => Tick::Ids,
+            _ = next_tick(&mut self.status) => Tick::Status,
+            _ = next_tick(&mut self.export) => Tick::Export,
+        }
+    }
+}
+
+/// The first `PREVIEW_CHARS` characters of `message`, for log lines.
+fn preview(message: &str) -> &str {
+    match message.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => &message[..end],
+        None => message,
+    }
+}
+
+/// Run a stdout `write`, failing with `TimedOut` if it blocks longer than
+/// `limit`: Zed has stopped reading, though its pipe isn't broken.
+async fn write_timeout<T>(
+    limit: Option<Duration>,
+    write: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let Some(limit) = limit else {
+        return write.await;
+    };
+    tokio::time::timeout(limit, write)
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "stdout write blocked for over {}s (OOK_STDOUT_WRITE_TIMEOUT_SECS)",
+                    limit.as_secs()
+                ),
+            ))
+        })
+}
+
+/// Drive `fut` to completion while buffering stdin into the outbound queue.
+///
+/// Returns `None` if stdin closes or a shutdown signal arrives first.
+async fn while_buffering_stdin<F: Future>(fut: F, agent: &mut Agent) -> Option<F::Output> {
+    tokio::pin!(fut);
+    loop {
+        // Copied out: `read_line` below borrows all of `agent.zed`
+        let keepalive_due = agent.zed.keepalive_due();
+        select! {
+            output = &mut fut => return Some(output),
+            open = agent.zed.read_line(), if !agent.zed.outbound.is_blocked() => {
+                if !open {
+                    return None;
+                }
+            }
+            signal = agent.signals.recv() => {
+                info!(signal, "Received shutdown signal while disconnected");
+                return None;
+            }
+            tick = agent.timers.next() => agent.on_tick(tick),
+            // A failure shows up again on the next write once connected
+            _ = deadline(keepalive_due) => {
+                if let Err(e) = agent.zed.write_keepalive().await {
+                    warn!(error = %e, "Failed to write stdout keepalive");
+                }
+            }
+        }
+    }
+}
+
+/// Send queued stdin lines to the bridge, oldest first.
+///
+/// A line is only dequeued once its send succeeded, so on error it stays at
+/// the front of the queue for the next connection. Each send waits for the
+/// flush, so a slow bridge backs up into the stdin channel instead of
+/// piling up in tungstenite's write buffer.
+///
+/// This is also what keeps delivery FIFO across a reconnect: stdin is only
+/// read between calls, and every line read goes to the back of this queue,
+/// so a new connection drains everything buffered during the outage before
+/// a line Zed sends afterwards can go out. A line whose send failed mid-way
+/// may reach the old bridge and be sent again, never out of order.
+async fn flush_outbound(ws_sink: &mut impl MessageSink, agent: &mut Agent) -> Result<(), WsError> {
+    while let Some(line) = agent.zed.outbound.front() {
+        #[cfg(feature = "fault-injection")]
+        if !agent.faults.pass("zed->bridge").await {
+            agent.zed.outbound.pop_front();
+            continue;
+        }
+        let seq = agent.zed.seq.next_to_bridge();
+        agent.zed.log_forward("zed->bridge", seq, line);
+        let bytes = line.len();
+        let started = Instant::now();
+        ws_sink.send(Message::Text(line.clone())).await?;
+        agent.zed.outbound.pop_front();
+        agent.metrics.record_to_bridge(bytes, started.elapsed());
+        if agent.metrics.messages_to_bridge == 1 {
+            log_first_message(&agent.zed);
+        }
+    }
+    Ok(())
+}
+
+/// How long the first message took to reach the bridge, from startup and
+/// from Zed sending it: the cold-start cost `OOK_LAZY_CONNECT` trades for.
+fn log_first_message(zed: &Zed) {
+    let since_start_ms = zed.seq.started.elapsed().as_millis() as u64;
+    let waited_ms = zed.first_line_at.map(|at| at.elapsed().as_millis() as u64);
+    info!(
+        since_start_ms,
+        waited_ms, "First message reached the bridge"
+    );
+}
+
+/// With `OOK_LAZY_CONNECT`, hold off connecting until Zed sends something.
+///
+/// Returns `false` if stdin closes or a shutdown signal arrives first.
+async fn wait_for_first_line(agent: &mut Agent) -> bool {
+    while agent.zed.outbound.is_empty() {
+        select! {
+            open = agent.zed.read_line() => {
+                if !open {
+                    return false;
+                }
+            }
+            signal = agent.signals.recv() => {
+                info!(signal, "Received shutdown signal before connecting");
+                return false;
+            }
+            tick = agent.timers.next() => agent.on_tick(tick),
+        }
+    }
+    true
+}
+
+/// Re-read the auth token before a reconnect, so a token rotated in the
+/// `OOK_CONFIG` file or `OOK_AUTH_TOKEN_FILE`, or a fresh one from
+/// `OOK_AUTH_TOKEN_CMD`, is used without a restart. If the config no longer
+/// loads, the token already in use is kept; a token file or command that
+/// fails fails the attempt.
+async fn refresh_auth_token(bridges: &mut Bridges) -> Result<(), String> {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            warn!(error = %e, "Failed to re-read the auth token, keeping the current one");
+            return Ok(());
+        }
+    };
+    if bridges.set_authorization(auth_header(&config).await?) {
+        info!("Auth token changed, using the new one");
+    }
+    Ok(())
+}
+
+/// Re-read the bridge's port file before a reconnect, when it picked the
+/// port, so a bridge restarted on another port is found again. A file that
+/// is gone or can't be read keeps the port already in use.
+fn refresh_port(bridges: &mut Bridges) {
+    match read_port_file() {
+        Ok(Some(port)) => {
+            if bridges.set_port(port) {
+                info!(port, "Bridge port file changed, using the new port");
+                logging::set_target_host(&bridges.candidates()[0].target_host());
+            }
+        }
+        Ok(None) => {}
+        Err(e) => warn!(error = %e, "Failed to re-read the port file, keeping the current port"),
+    }
+}
+
+/// Re-establish the bridge connection with exponential backoff.
+///
+/// Stdin keeps being read into the outbound queue while this runs, so lines
+/// Zed sends during the outage are delivered once a connection succeeds.
+async fn reconnect(bridges: &mut Bridges, agent: &mut Agent) -> Reconnect {
+    let max_retries = agent.max_retries;
+    let mut delay = INITIAL_BACKOFF;
+    let mut attempt: u32 = 0;
+    agent.breaker.disconnected(agent.metrics.messages());
+    agent.zed.health.record(Anomaly::Reconnect);
+
+    loop {
+        attempt += 1;
+        if max_retries != 0 && attempt > max_retries {
+            return Reconnect::GaveUp;
+        }
+
+        // Once the breaker has tripped, attempts are spaced out and quiet
+        let quiet = agent.breaker.tripped();
+        let wait = agent.jitter.reconnect(if quiet {
+            agent.breaker.cooldown()
+        } else {
+            delay
+        });
+        if quiet {
+            debug!(
+                attempt,
+                delay_ms = wait.as_millis() as u64,
+                "Reconnecting to Ook Bridge after cooldown"
+            );
+        } else {
+            info!(
+                attempt,
+                max_retries,
+                delay_ms = wait.as_millis() as u64,
+                queued = agent.zed.outbound.len(),
+                "Reconnecting to Ook Bridge"
+            );
+        }
+        agent
+            .zed
+            .emit_state(json!({
+                "state": "reconnecting",
+                "attempt": attempt,
+                "delay_ms": wait.as_millis() as u64,
+            }))
+            .await;
+        let sleep = tokio::time::sleep(wait);
+        if while_buffering_stdin(sleep, agent).await.is_none() {
+            return Reconnect::Shutdown;
+        }
+
+        if bridges.port_file() {
+            refresh_port(bridges);
+        }
+        // The token command can take a while, so stdin is buffered meanwhile
+        match while_buffering_stdin(refresh_auth_token(bridges), agent).await {
+            None => return Reconnect::Shutdown,
+            Some(Ok(())) => {}
+            Some(Err(e)) => {
+                warn!(
+                    attempt,
+                    failure_class = "auth token",
+                    error = %e,
+                    "Reconnect attempt failed"
+                );
+                delay = (delay * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        }
+        match while_buffering_stdin(bridges.connect(), agent).await {
+            None => return Reconnect::Shutdown,
+            Some(Ok(ws_stream)) => {
+                info!(
+                    attempt,
+                    consecutive_failures = agent.breaker.failures(),
+                    "Reconnected to Ook Bridge"
+                );
+                agent.metrics.reconnects += 1;
+                agent.breaker.connected(agent.metrics.messages());
+                agent
+                    .zed
+                    .emit_state(json!({
+                        "state": "connected",
+                        "reconnects": agent.metrics.reconnects,
+                    }))
+                    .await;
+                return Reconnect::Connected(Box::new(ws_stream));
+            }
+            Some(Err(e)) => {
+                if quiet {
+                    debug!(
+                        attempt,
+                        failure_class = FailureClass::of(&e).as_str(),
+                        error = %describe_connect_error(&e),
+                        "Reconnect attempt failed"
+                    );
+                } else {
+                    warn!(
+                        attempt,
+                        failure_class = FailureClass::of(&e).as_str(),
+                        error = %describe_connect_error(&e),
+                        "Reconnect attempt failed"
+                    );
+                }
+                if agent.breaker.failed() {
+                    warn!(
+                        consecutive_failures = agent.breaker.failures(),
+                        cooldown_secs = agent.breaker.cooldown().as_secs(),
+                        "Ook Bridge keeps failing to connect; retrying once per cooldown and logging further attempts at debug level"
+                    );
+                }
+            }
+        }
+
+        delay = (delay * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Open the first bridge connection.
+///
+/// Fails fast, except that a host name that does not resolve yet (a Lima
+/// VM still booting) is retried on the reconnect backoff schedule. Returns
+/// `Ok(None)` if the agent is asked to shut down while waiting.
+async fn connect_initial(
+    bridges: &mut Bridges,
+    agent: &mut Agent,
+) -> Result<Option<WsStream>, WsError> {
+    let max_retries = agent.max_retries;
+    let mut delay = INITIAL_BACKOFF;
+    let mut attempt: u32 = 0;
+
+    loop {
+        let e = match while_buffering_stdin(bridges.connect(), agent).await {
+            None => return Ok(None),
+            Some(Ok(ws_stream)) => return Ok(Some(ws_stream)),
+            Some(Err(e)) => e,
+        };
+
+        attempt += 1;
+        let failure_class = FailureClass::of(&e);
+        if failure_class != FailureClass::NameResolution
+            || (max_retries != 0 && attempt > max_retries)
+        {
+            return Err(e);
+        }
+
+        let wait = agent.jitter.reconnect(delay);
+        warn!(
+            attempt,
+            max_retries,
+            delay_ms = wait.as_millis() as u64,
+            failure_class = failure_class.as_str(),
+            error = %describe_connect_error(&e),
+            "Ook Bridge host not resolvable yet, retrying"
+        );
+        let sleep = tokio::time::sleep(wait);
+        if while_buffering_stdin(sleep, agent).await.is_none() {
+            return Ok(None);
+        }
+        delay = (delay * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Work out where and how to reach the bridge from flags and env vars, with
+/// `auth` as the `Authorization` header.
+fn resolve_bridges(
+    args: cli::Args,
+    config: &Config,
+    session_id: &str,
+    auth: Option<HeaderValue>,
+) -> Result<Bridges, Box<dyn std::error::Error>> {
+    let headers = get_handshake_headers(config, session_id, auth)?;
+    let ws_config = bridge::ws_config(get_max_message_bytes());
+    let connect_timeout = get_connect_timeout(config);
+
+    let remote_url = get_remote_url(config)?;
+
+    let host_set =
+        args.host.is_some() || config.remote_host.is_some() || config.remote_hosts.is_some();
+    let port_set = args.port.is_some() || config.remote_port.is_some();
+    let remote_set = host_set || port_set || remote_url.is_some();
+    let bind = get_bind_addr()?;
+    if bind.is_some() && (get_remote_pipe().is_some() || config.remote_uds.is_some()) {
+        return Err(
+            "OOK_BIND_ADDR only applies to TCP bridges, not OOK_REMOTE_UDS or OOK_REMOTE_PIPE"
+                .into(),
+        );
+    }
+
+    if let Some(name) = get_remote_pipe() {
+        if remote_set || config.remote_uds.is_some() {
+            return Err("OOK_REMOTE_PIPE cannot be combined with OOK_REMOTE_UDS or a remote host, port, or URL".into());
+        }
+        return Ok(Bridges::new(vec![pipe_target(
+            name,
+            headers,
+            ws_config,
+            connect_timeout,
+        )?]));
+    }
+
+    if let Some(path) = config.remote_uds.clone() {
+        if remote_set {
+            return Err(
+                "OOK_REMOTE_UDS cannot be combined with a remote host, port, or URL".into(),
+            );
+        }
+        return Ok(Bridges::new(vec![unix_target(
+            path,
+            headers,
+            ws_config,
+            connect_timeout,
+        )?]));
+    }
+
+    let (scheme, hosts, port, port_file) = match remote_url {
+        Some(url) => {
+            if args.host.is_some() || args.port.is_some() || args.scheme.is_some() {
+                return Err(
+                    "OOK_REMOTE_URL cannot be combined with --host, --port, or --scheme".into(),
+                );
+            }
+            let host = match url.host() {
+                // Unbracketed, as the resolver and TLS expect
+                Some(Host::Ipv6(addr)) => addr.to_string(),
+                Some(host) => host.to_string(),
+                None => unreachable!("get_remote_url checks for a host"),
+            };
+            let port = url.port_or_known_default().unwrap_or(DEFAULT_PORT);
+            (
+                url.scheme().to_string(),
+                vec![(host, url.to_string())],
+                port,
+                false,
+            )
+        }
+        None => {
+            let scheme = get_remote_scheme(args.scheme, config)?;
+            let port_file = args.port.is_none() && config.remote_port.is_none();
+            let port = get_remote_port(args.port, config)?;
+            let hosts = get_remote_hosts(args.host, config)?
+                .into_iter()
+                .map(|host| {
+                    let url = format!("{}://{}:{}", scheme, url_host(&host), port);
+                    (host, url)
+                })
+                .collect();
+            (scheme, hosts, port, port_file)
+        }
+    };
+
+    let allowed_hosts = get_allowed_hosts()?;
+    if let Some(allowlist) = &allowed_hosts {
+        for (host, _) in &hosts {
+            allowlist.check(host)?;
+        }
+    }
+
+    let proxy = get_proxy()?;
+    if let Some(proxy) = &proxy {
+        info!(proxy = %proxy.describe(), "Tunnelling to the bridge through HTTP proxy");
+        for (host, _) in hosts.iter().filter(|(host, _)| !proxy.covers(host)) {
+            info!(host = %host, "Connecting to this bridge host directly (NO_PROXY or loopback)");
+        }
+    }
+
+    let insecure = config.tls_insecure.unwrap_or(false);
+    if insecure {
+        warn!("!!! OOK_TLS_INSECURE is set: TLS certificates will NOT be verified. Use for development only !!!");
+    }
+    let tls = match scheme.as_str() {
+        "wss" => Some(bridge::tls_connector(insecure)?),
+        _ => None,
+    };
+
+    let keepalive = get_tcp_keepalive();
+    let follow_redirects = env_flag("OOK_FOLLOW_REDIRECTS");
+    let candidates = hosts
+        .into_iter()
+        .map(|(host, url)| BridgeTarget {
+            url,
+            endpoint: Endpoint::Tcp {
+                host,
+                port,
+                keepalive,
+                bind,
+            },
+            headers: headers.clone(),
+            tls: tls.clone(),
+            ws_config,
+            proxy: proxy.clone(),
+            connect_timeout,
+            follow_redirects,
+            allowed_hosts: allowed_hosts.clone(),
+            log_handshake: env_flag("OOK_LOG_HANDSHAKE"),
+        })
+        .collect();
+    let bridges = Bridges::new(candidates);
+    Ok(if port_file {
+        bridges.with_port_file()
+    } else {
+        bridges
+    })
+}
+
+#[cfg(unix)]
+fn unix_target(
+    path: PathBuf,
+    headers: HeaderMap,
+    ws_config: WebSocketConfig,
+    connect_timeout: Option<Duration>,
+) -> Result<BridgeTarget, Box<dyn std::error::Error>> {
+    Ok(BridgeTarget {
+        // The host is never resolved; the upgrade just needs a valid URL
+        url: "ws://localhost/".to_string(),
+        endpoint: Endpoint::Unix(path),
+        headers,
+        tls: None,
+        ws_config,
+        proxy: None,
+        connect_timeout,
+        follow_redirects: false,
+        allowed_hosts: None,
+        log_handshake: env_flag("OOK_LOG_HANDSHAKE"),
+    })
+}
+
+#[cfg(not(unix))]
+fn unix_target(
+    _path: PathBuf,
+    _headers: HeaderMap,
+    _ws_config: WebSocketConfig,
+    _connect_timeout: Option<Duration>,
+) -> Result<BridgeTarget, Box<dyn std::error::Error>> {
+    Err("OOK_REMOTE_UDS is only supported on Unix".into())
+}
+
+#[cfg(windows)]
+fn pipe_target(
+    name: String,
+    headers: HeaderMap,
+    ws_config: WebSocketConfig,
+    connect_timeout: Option<Duration>,
+) -> Result<BridgeTarget, Box<dyn std::error::Error>> {
+    Ok(BridgeTarget {
+        // As with Unix sockets, only the path of the upgrade URL matters
+        url: "ws://localhost/".to_string(),
+        endpoint: Endpoint::Pipe(name),
+        headers,
+        tls: None,
+        ws_config,
+        proxy: None,
+        connect_timeout,
+        follow_redirects: false,
+        allowed_hosts: None,
+        log_handshake: env_flag("OOK_LOG_HANDSHAKE"),
+    })
+}
+
+#[cfg(not(windows))]
+fn pipe_target(
+    _name: String,
+    _headers: HeaderMap,
+    _ws_config: WebSocketConfig,
+    _connect_timeout: Option<Duration>,
+) -> Result<BridgeTarget, Box<dyn std::error::Error>> {
+    Err("OOK_REMOTE_PIPE is only supported on Windows".into())
+}
+
+/// What this run of the agent does, from the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    /// Proxy between Zed and the bridge (or replay a capture).
+    Proxy,
+    Check,
+    /// `--once`, with the inline request if one was given.
+    Once(Option<String>),
+    DryRun,
+    PrintConfig,
+}
+
+/// The `ook` binary: parse the command line, set up logging, and run.
+pub async fn main() -> ExitCode {
+    let (args, mode) = match cli::parse(env::args().skip(1)) {
+        Ok(cli::Command::Run(args)) => (args, Mode::Proxy),
+        Ok(cli::Command::Check(args)) => (args, Mode::Check),
+        Ok(cli::Command::Once(args, request)) => (args, Mode::Once(request)),
+        Ok(cli::Command::DryRun(args)) => (args, Mode::DryRun),
+        Ok(cli::Command::PrintConfig(args)) => (args, Mode::PrintConfig),
+        Ok(cli::Command::Show(show)) => {
+            return match record::show(&show).await {
+                Ok(()) => ExitCode::SUCCESS,
+                // E.g. piped into `head`
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {}: {}", show.path.display(), e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Ok(cli::Command::Help) => {
+            print!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Ok(cli::Command::Version) => {
+            println!("{}", cli::version());
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // Initialize logging to stderr (stdout is for ACP messages)
+    if let Err(e) = logging::init() {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    }
+
+    // Every log line carries the session id, as does the handshake, so one
+    // session can be followed across Zed, the agent, and the bridge
+    let session_id = get_session_id();
+    let span = info_span!(
+        "session",
+        session_id = %session_id,
+        target_host = tracing::field::Empty
+    );
+    let exit = match run(args, mode, session_id).instrument(span).await {
+        Ok(exit) => exit,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            Exit::Failed
+        }
+    };
+    #[cfg(feature = "otel")]
+    otel::shutdown().await;
+    ExitCode::from(exit.code())
+}
+
+async fn run(
+    args: cli::Args,
+    mode: Mode,
+    session_id: String,
+) -> Result<Exit, Box<dyn std::error::Error>> {
+    info!(version = %cli::version(), "Session id for this run (the `session_id` on every log line)");
+
+    if let (Mode::Proxy, Some(path)) = (&mode, get_replay_path()) {
+        info!(path = %path.display(), "Replaying capture, not connecting to the bridge");
+        record::replay(&path).await.map_err(|e| {
+            error!(error = %e, path = %path.display(), "Replay failed");
+            e
+        })?;
+        return Ok(Exit::Clean);
+    }
+
+    let config = Config::load().map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    #[cfg(feature = "loopback")]
+    let config = if env_flag("OOK_LOOPBACK") {
+        // Stands in for whatever bridge the config names
+        let addr = loopback::spawn().await?;
+        Config {
+            remote_url: Some(format!("ws://{}", addr)),
+            remote_host: None,
+            remote_hosts: None,
+            remote_port: None,
+            remote_scheme: None,
+            remote_uds: None,
+            ..config
+        }
+    } else {
+        config
+    };
+    #[cfg(not(feature = "loopback"))]
+    if env_flag("OOK_LOOPBACK") {
+        warn!("OOK_LOOPBACK is set but this build has no loopback bridge (cargo feature `loopback`); connecting as usual");
+    }
+    let auth = auth_header(&config).await.map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    let mut bridges = resolve_bridges(args, &config, &session_id, auth).map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    logging::set_target_host(&bridges.candidates()[0].target_host());
+
+    if mode == Mode::PrintConfig {
+        println!("{}", print_config::render(&config, &bridges));
+        return Ok(Exit::Clean);
+    }
+
+    if mode == Mode::Check {
+        match bridges.check(CHECK_PONG_TIMEOUT).await {
+            Ok((target, rtt)) => {
+                println!(
+                    "ok: Ook Bridge at {} answered in {:.1}ms",
+                    target.describe(),
+                    rtt.as_secs_f64() * 1000.0
+                );
+                return Ok(Exit::Clean);
+            }
+            Err(e) => {
+                eprintln!("error: Ook Bridge at {} {}", bridges.describe(), e);
+                return Ok(Exit::Failed);
+            }
+        }
+    }
+
+    if let Mode::Once(request) = mode {
+        return Ok(once::run(&mut bridges, request, get_once_timeout()).await);
+    }
+
+    // Removed when `run` returns
+    let _pid_file = match get_pid_file().filter(|_| mode == Mode::Proxy) {
+        Some(path) => Some(
+            PidFile::create(&path, env_flag("OOK_PID_FILE_EXCLUSIVE")).map_err(|e| {
+                error!(error = %e, "Refusing to start");
+                e
+            })?,
+        ),
+        None => None,
+    };
+
+    let mut agent = build_agent(&config, &mode, tokio::io::stdin(), tokio::io::stdout()).await?;
+    panic_hook::install(agent.zed.lsp_stdout, agent.zed.mux.is_some());
+
+    if mode == Mode::DryRun {
+        dry_run::run(&mut agent).await;
+        return Ok(Exit::Clean);
+    }
+
+    if let Some(addr) = get_listen_addr() {
+        let queue = env_flag("OOK_LISTEN_QUEUE");
+        let mut hangup = ReconnectSignal::install()?;
+        let end = listen::run(&addr, queue, &mut agent, &mut hangup)
+            .await
+            .map_err(|e| {
+                error!(error = %e, addr = %addr, "Failed to listen for the bridge");
+                e
+            })?;
+        if let Some(mux) = &agent.zed.mux {
+            mux.log_summary();
+        }
+        return Ok(finish(end));
+    }
+
+    if env_flag("OOK_LAZY_CONNECT") {
+        info!("Waiting for Zed's first message before connecting to Ook Bridge");
+        if !wait_for_first_line(&mut agent).await {
+            info!("Ook extension shutting down");
+            return Ok(Exit::Clean);
+        }
+    }
+
+    info!(url = %bridges.describe(), "Connecting to Ook Bridge");
+
+    let mut ws_stream = match connect_initial(&mut bridges, &mut agent).await {
+        Ok(Some(ws_stream)) => ws_stream,
+        Ok(None) => {
+            info!("Ook extension shutting down");
+            return Ok(Exit::Clean);
+        }
+        Err(e) => {
+            let error = describe_connect_error(&e);
+            error!(
+                error = %error,
+                failure_class = FailureClass::of(&e).as_str(),
+                url = %bridges.describe(),
+                "Failed to connect to Ook Bridge"
+            );
+            let message = format!("Ook Bridge unreachable at {}", bridges.describe());
+            agent
+                .zed
+                .report_error(acp::BRIDGE_UNREACHABLE, &message)
+                .await;
+            return Ok(Exit::connect_error(&e));
+        }
+    };
+
+    info!("Connected to Ook Bridge");
+    agent
+        .zed
+        .emit_state(json!({ "state": "connected", "reconnects": 0 }))
+        .await;
+
+    let mut hangup = ReconnectSignal::install()?;
+    let end = loop {
+        match run_session(ws_stream, &mut agent, &mut hangup).await {
+            end if agent.reconnects_after(end) => {
+                if end == SessionEnd::Reconnect {
+                    info!(
+                        queued = agent.zed.outbound.len(),
+                        "Closed connection to Ook Bridge on SIGHUP, reconnecting"
+                    );
+                } else if end == SessionEnd::MaxAge {
+                    info!(
+                        queued = agent.zed.outbound.len(),
+                        "Closed connection to Ook Bridge at OOK_MAX_SESSION_SECS, reconnecting"
+                    );
+                } else {
+                    warn!(
+                        queued = agent.zed.outbound.len(),
+                        "Lost connection to Ook Bridge"
+                    );
+                }
+                agent
+                    .zed
+                    .emit_state(json!({
+                        "state": "disconnected",
+                        "reason": end.reason(),
+                        "queued": agent.zed.outbound.len(),
+                    }))
+                    .await;
+                match reconnect(&mut bridges, &mut agent).await {
+                    Reconnect::Connected(stream) => ws_stream = *stream,
+                    Reconnect::Shutdown => {
+                        if !agent.zed.outbound.is_empty() {
+                            warn!(
+                                dropped = agent.zed.outbound.len(),
+                                "Discarding queued messages, shutting down while disconnected"
+                            );
+                        }
+                        break None;
+                    }
+                    Reconnect::GaveUp => {
+                        error!(
+                            max_retries = agent.max_retries,
+                            url = %bridges.describe(),
+                            "Giving up reconnecting to Ook Bridge"
+                        );
+                        let message = format!("Ook Bridge unreachable at {}", bridges.describe());
+                        agent
+                            .zed
+                            .report_error(acp::BRIDGE_UNREACHABLE, &message)
+                            .await;
+                        return Ok(Exit::ConnectFailed);
+                    }
+                }
+            }
+            end => break Some(end),
+        }
+    };
+
+    if let Some(mux) = &agent.zed.mux {
+        mux.log_summary();
+    }
+    Ok(finish(end))
+}
+
+/// Set up the Zed side and the state every connection shares, from the env,
+/// over the given stdio: the process's own, or in-memory pipes in tests.
+async fn build_agent(
+    config: &Config,
+    mode: &Mode,
+    stdin: impl AsyncRead + Unpin + Send + 'static,
+    stdout: impl AsyncWrite + Unpin + Send + 'static,
+) -> Result<Agent, Box<dyn std::error::Error>> {
+    let signals = ShutdownSignal::install()?;
+
+    let outbound = OutboundQueue::from_env().map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    let inbound = InboundOverflow::from_env().map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    if let Some(inbound) = &inbound {
+        info!(
+            policy = inbound.policy_name(),
+            capacity = inbound.capacity(),
+            "Queueing bridge messages ahead of stdout (OOK_INBOUND_OVERFLOW)"
+        );
+    }
+    let framing = get_framing().map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    let flush_policy = get_flush_policy().map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    let mux = env_flag("OOK_MUX");
+    if mux && framing == Framing::Lsp {
+        let e = "OOK_MUX cannot be combined with OOK_STDIO_FRAMING=lsp";
+        error!(error = %e, "Invalid configuration");
+        return Err(e.into());
+    }
+    if mux {
+        info!("Multiplexing ACP sessions by channel tag");
+    }
+    let app_ping = get_app_ping_interval().map(AppPing::new);
+    if mux && app_ping.is_some() {
+        // The bridge would expect our ping inside a channel envelope
+        let e = "OOK_APP_PING_SECS cannot be combined with OOK_MUX";
+        error!(error = %e, "Invalid configuration");
+        return Err(e.into());
+    }
+    let acp_version = get_acp_version().map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    if let Some(acp_version) = &acp_version {
+        if mux {
+            // Each channel would have its own `initialize`
+            let e = "OOK_CHECK_ACP_VERSION cannot be combined with OOK_MUX";
+            error!(error = %e, "Invalid configuration");
+            return Err(e.into());
+        }
+        let supported = acp_version.supported();
+        info!(
+            min = supported.start(),
+            max = supported.end(),
+            "Checking the bridge's ACP version"
+        );
+    }
+    let report_close = get_report_close().map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    let jitter = get_jitter().map_err(|e| {
+        error!(error = %e, "Invalid configuration");
+        e
+    })?;
+    let recorder = match get_record_path() {
+        Some(path) => {
+            let recorder = Recorder::open(&path).map_err(|e| {
+                error!(error = %e, path = %path.display(), "Failed to open OOK_RECORD_PATH");
+                e
+            })?;
+            info!(path = %path.display(), "Recording session");
+            Some(recorder)
+        }
+        None => None,
+    };
+    let ids = get_id_tracker();
+    if let Some(ids) = &ids {
+        info!(
+            timeout_secs = ids.timeout().as_secs(),
+            slow_response_ms = ids.slow().map(|slow| slow.as_millis() as u64),
+            "Tracking JSON-RPC request ids"
+        );
+    }
+    let metrics_export = match get_metrics_addr().filter(|_| *mode != Mode::DryRun) {
+        Some(addr) => Some(prometheus::spawn(&addr).await.map_err(|e| {
+            error!(error = %e, addr = %addr, "Failed to serve OOK_METRICS_ADDR");
+            e
+        })?),
+        None => None,
+    };
+    let timers = Timers {
+        metrics: timer(get_metrics_interval()),
+        ids: timer(ids.as_ref().map(|_| TRACK_IDS_SWEEP)),
+        status: timer(get_status_file().map(|_| status::STATUS_INTERVAL)),
+        export: timer(metrics_export.as_ref().map(|_| prometheus::EXPORT_INTERVAL)),
+    };
+    let zed = Zed {
+        stdin: StdinChannel::spawn(
+            LineReader::new(BufReader::new(stdin), get_max_line_bytes(), "stdin")
+                .with_framing(framing),
+        ),
+        stdout: BufWriter::new(Box::new(stdout)),
+        outbound,
+        validate_json: env_flag("OOK_VALIDATE_JSON"),
+        max_message_bytes: get_max_message_bytes(),
+        recorder,
+        ids,
+        acp_version,
+        lines_read: 0,
+        first_line_at: None,
+        log_bodies: env_flag("OOK_LOG_BODIES"),
+        seq: Sequence::new(),
+        log_methods: env::var("OOK_LOG_METHODS")
+            .ok()
+            .map(|list| MethodFilter::parse(&list)),
+        lsp_stdout: framing == Framing::Lsp,
+        flush_policy,
+        emit_state: env_flag("OOK_EMIT_STATE"),
+        normalize_eol: env_flag("OOK_NORMALIZE_EOL"),
+        mux: mux.then(Mux::default),
+        skew: get_clock_skew(),
+        seq_tag: env_flag("OOK_INJECT_SEQ").then(SeqTag::new),
+        health: get_health(),
+        chunk_write_bytes: get_chunk_write_bytes(),
+        stdout_write_timeout: get_stdout_write_timeout(),
+        flush_due: None,
+        stdout_keepalive: get_stdout_keepalive(),
+        last_stdout_write: Instant::now(),
+    };
+    info!(flush_policy = flush_policy.name(), "Stdout flush policy");
+    let ping_interval = get_ping_interval(config);
+    let hello = env_flag("OOK_SEND_HELLO").then(|| {
+        let features = [
+            ("validate_json", zed.validate_json),
+            ("json_framing", framing == Framing::Json),
+            ("track_ids", zed.ids.is_some()),
+            ("record", zed.recorder.is_some()),
+            ("keepalive", ping_interval.is_some()),
+        ];
+        let enabled: Vec<&str> = features
+            .iter()
+            .filter(|(_, on)| *on)
+            .map(|(name, _)| *name)
+            .collect();
+        hello_message(&enabled)
+    });
+    let agent = Agent {
+        zed,
+        signals,
+        metrics: Metrics::default(),
+        timers,
+        status_file: get_status_file(),
+        connected: false,
+        max_retries: get_reconnect_max_retries(config),
+        breaker: ReconnectBreaker::new(
+            get_reconnect_cooldown_after(config),
+            get_reconnect_cooldown(config),
+        ),
+        jitter,
+        ping_interval,
+        app_ping,
+        idle_timeout: get_idle_timeout(config),
+        last_activity: Instant::now(),
+        hello,
+        metrics_export,
+        coalescer: get_coalesce_window().map(Coalescer::new),
+        dedup: env_flag("OOK_DEDUP_CONSECUTIVE").then(Dedup::default),
+        report_close,
+        shutdown_grace: get_shutdown_grace(),
+        max_session: get_max_session(),
+        max_session_exit: env_flag("OOK_MAX_SESSION_EXIT"),
+        inbound,
+        #[cfg(feature = "fault-injection")]
+        faults: fault::Faults::from_env(),
+    };
+    #[cfg(not(feature = "fault-injection"))]
+    if let Some(name) = fault_vars_set() {
+        warn!(var = name, "OOK_FAULT_* is set but this build has no fault injection (cargo feature `fault-injection`); ignoring it");
+    }
+    if let Some(coalescer) = &agent.coalescer {
+        info!(
+            window_ms = coalescer.window().as_millis() as u64,
+            "Coalescing bursts of bridge notifications"
+        );
+    }
+    Ok(agent)
+}
+
+/// Proxy messages between stdio and one WebSocket connection until either
+/// side goes away.
+///
+/// The order on every connection is fixed: setup (stdin reader, queues,
+/// timers) is already done before the connect, then the hello goes out,
+/// then lines queued while disconnected, and only then does the loop start
+/// reading. The bridge may push frames as soon as the upgrade completes;
+/// those wait unread in the socket and tungstenite's buffer and reach Zed in
+/// order on the loop's first reads, after our hello has been sent.
+async fn run_session(
+    transport: impl Transport,
+    agent: &mut Agent,
+    hangup: &mut ReconnectSignal,
+) -> SessionEnd {
+    let (mut ws_sink, ws_stream) = transport.into_split();
+    let mut ws_stream = match agent.inbound {
+        Some(overflow) => Either::Right(InboundQueue::spawn(ws_stream, overflow)),
+        None => Either::Left(ws_stream),
+    };
+
+    // Keepalive: `ping_sent_at` is set while a ping is awaiting its pong.
+    // Each ping schedules the next, so jitter applies afresh every time
+    let next_ping = |agent: &mut Agent| {
+        agent
+            .ping_interval
+            .map(|interval| Instant::now() + agent.jitter.ping(interval))
+    };
+    let mut ping_due = next_ping(agent);
+    let mut ping_sent_at: Option<Instant> = None;
+    let mut unanswered_pings: u32 = 0;
+
+    if let Some(hello) = &agent.hello {
+        debug!(message = %hello, "Sending hello");
+        if let Err(e) = ws_sink.send(Message::Text(hello.clone())).await {
+            error!(error = %e, "Failed to send hello to WebSocket");
+            return SessionEnd::Disconnected;
+        }
+    }
+
+    // Deliver anything Zed sent while we were disconnected first
+    if let Err(e) = flush_outbound(&mut ws_sink, agent).await {
+        error!(error = %e, "Failed to send to WebSocket");
+        return SessionEnd::Disconnected;
+    }
+    agent.connected = true;
+    if let Some(app_ping) = &mut agent.app_ping {
+        app_ping.reset();
+    }
+    let max_age_due = agent.max_session.map(|max| Instant::now() + max);
+
+    let end = loop {
+        #[cfg(feature = "fault-injection")]
+        if agent.faults.close_due() {
+            break SessionEnd::Disconnected;
+        }
+        // Copied out: `read_line` below borrows all of `agent.zed`
+        let flush_due = agent.zed.flush_due;
+        let keepalive_due = agent.zed.keepalive_due();
+        let app_ping_due = agent.app_ping.as_ref().map(AppPing::due);
+        select! {
+            // Read from stdin (Zed -> Bridge)
+            open = agent.zed.read_line() => {
+                if !open {
+                    break SessionEnd::StdinClosed;
+                }
+                agent.last_activity = Instant::now();
+                if let Err(e) = flush_outbound(&mut ws_sink, agent).await {
+                    error!(error = %e, "Failed to send to WebSocket");
+                    break SessionEnd::Disconnected;
+                }
+            }
+
+            // Read from WebSocket (Bridge -> Zed)
+            // Under `coalesce`, a read that would block is the cue to flush
+            msg = flush::read_or_idle(ws_stream.next(), flush_due.is_some()) => {
+                let Read::Item(msg) = msg else {
+                    if let Err(e) = agent.zed.flush_stdout().await {
+                        break SessionEnd::stdout_error(&e);
+                    }
+                    continue;
+                };
+                match msg {
+                    // Binary frames carrying UTF-8 are forwarded exactly like text
+                    Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => {
+                        agent.last_activity = Instant::now();
+                        if let Err(e) = agent.forward_to_zed(msg.into_data()).await {
+                            break SessionEnd::stdout_error(&e);
+                        }
+                        if agent.zed.acp_refused() {
+                            break SessionEnd::AcpVersion;
+                        }
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        info!(frame = ?frame, "WebSocket closed by server");
+                        if let Some(frame) = frame.filter(|f| agent.report_close.covers(f.code.into())) {
+                            let message = acp::close_message(frame.code.into(), &frame.reason);
+                            agent.zed.report_error(acp::BRIDGE_CLOSED, &message).await;
+                        }
+                        break SessionEnd::Disconnected;
+                    }
+                    Some(Ok(Message::Ping(data))) => {
+                        agent.last_activity = Instant::now();
+                        debug!("Received ping, sending pong");
+                        if let Err(e) = ws_sink.send(Message::Pong(data)).await {
+                            warn!(error = %e, "Failed to send pong");
+                        }
+                    }
+                    Some(Ok(Message::Pong(_))) => {
+                        if let Some(sent_at) = ping_sent_at.take() {
+                            let rtt_ms = sent_at.elapsed().as_secs_f64() * 1000.0;
+                            debug!(rtt_ms, "Received pong");
+                            agent.metrics.last_pong_rtt_ms = Some(rtt_ms);
+                            unanswered_pings = 0;
+                        }
+                    }
+                    Some(Ok(Message::Frame(frame))) => {
+                        // tungstenite only uses `Frame` for sending raw
+                        // frames: its reader reassembles fragments and
+                        // yields the messages above, so this can't happen.
+                        // Named rather than caught by `_` so a new variant
+                        // is a compile error here instead of a silent drop
+                        debug!(frame = %frame, "Ignoring raw WebSocket frame from the bridge");
+                    }
+                    Some(Err(WsError::Capacity(CapacityError::MessageTooLong { size, max_size }))) => {
+                        error!(
+                            direction = "bridge->zed",
+                            bytes = size,
+                            max_message_bytes = max_size,
+                            "Message exceeds OOK_MAX_MESSAGE_BYTES, dropping the connection"
+                        );
+                        agent.zed.health.record(Anomaly::Oversized);
+                        break SessionEnd::Disconnected;
+                    }
+                    Some(Err(e)) => {
+                        error!(error = %e, "WebSocket error");
+                        break SessionEnd::Disconnected;
+                    }
+                    None => {
+                        info!("WebSocket stream ended");
+                        break SessionEnd::Disconnected;
+                    }
+                }
+            }
+
+            // Keepalive ping (Agent -> Bridge)
+            _ = deadline(ping_due) => {
+                if ping_sent_at.is_some() {
+                    unanswered_pings += 1;
+                    warn!(unanswered_pings, "Keepalive ping went unanswered");
+                    if unanswered_pings >= MAX_UNANSWERED_PINGS {
+                        error!("Bridge stopped answering pings, treating connection as dead");
+                        break SessionEnd::Disconnected;
+                    }
+                }
+                if let Err(e) = ws_sink.send(Message::Ping(Vec::new())).await {
+                    error!(error = %e, "Failed to send ping");
+                    break SessionEnd::Disconnected;
+                }
+                ping_sent_at = Some(Instant::now());
+                ping_due = next_ping(agent);
+            }
+
+            // Application-level ping (Agent -> Bridge -> Agent)
+            _ = deadline(app_ping_due) => {
+                let Some(app_ping) = &mut agent.app_ping else {
+                    continue;
+                };
+                let Some(request) = app_ping.ping() else {
+                    error!(
+                        waited_ms = app_ping.waited().map(|d| d.as_millis() as u64),
+                        "Bridge did not answer the app-level ping, treating connection as dead"
+                    );
+                    break SessionEnd::Disconnected;
+                };
+                if let Err(e) = ws_sink.send(Message::Text(request)).await {
+                    error!(error = %e, "Failed to send app-level ping");
+                    break SessionEnd::Disconnected;
+                }
+            }
+
+            // SIGTERM/SIGINT (or Ctrl-C on Windows)
+            signal = agent.signals.recv() => {
+                info!(signal, "Received shutdown signal");
+                break SessionEnd::Signal;
+            }
+
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, closing the connection to reconnect");
+                break SessionEnd::Reconnect;
+            }
+
+            _ = deadline(max_age_due) => {
+                info!(
+                    max_session_secs = agent.max_session.map(|max| max.as_secs()),
+                    exit = agent.max_session_exit,
+                    "Connection reached its maximum age, rotating it"
+                );
+                break SessionEnd::MaxAge;
+            }
+
+            tick = agent.timers.next() => agent.on_tick(tick),
+
+            _ = deadline(agent.coalescer.as_ref().and_then(Coalescer::deadline)) => {
+                if let Err(e) = agent.flush_coalesced().await {
+                    break SessionEnd::stdout_error(&e);
+                }
+            }
+
+            _ = deadline(flush_due) => {
+                if let Err(e) = agent.zed.flush_stdout().await {
+                    break SessionEnd::stdout_error(&e);
+                }
+            }
+
+            _ = deadline(keepalive_due) => {
+                if let Err(e) = agent.zed.write_keepalive().await {
+                    break SessionEnd::stdout_error(&e);
+                }
+            }
+
+            _ = idle_deadline(agent.last_activity, agent.idle_timeout) => {
+                info!(
+                    idle_timeout_secs = agent.idle_timeout.map(|t| t.as_secs()),
+                    "Idle timeout reached with no traffic, shutting down"
+                );
+                break SessionEnd::IdleTimeout;
+            }
+        }
+    };
+
+    // A response may still be streaming for Zed's last request
+    let mut bridge_closed = false;
+    if let (SessionEnd::StdinClosed, Some(grace)) = (end, agent.shutdown_grace) {
+        bridge_closed = shutdown_grace(&mut ws_sink, &mut ws_stream, agent, grace).await;
+    }
+
+    agent.connected = false;
+
+    // Clean shutdown: close our side, then pass on anything the bridge sent
+    // before it saw the close frame so Zed gets the session's last messages
+    let frame = CloseFrame {
+        code: end.close_code(),
+        reason: end.reason().into(),
+    };
+    debug!(code = u16::from(frame.code), reason = %frame.reason, "Closing WebSocket");
+    let _ = ws_sink.send(Message::Close(Some(frame))).await;
+    let _ = ws_sink.close().await;
+    // Nothing more can reach Zed once stdout has failed
+    let stdout_ok = !matches!(end, SessionEnd::ClientGone | SessionEnd::StdoutFailed);
+    if stdout_ok {
+        if let Err(e) = agent.flush_coalesced().await {
+            warn!(error = %e, "Failed to write to stdout on shutdown");
+        }
+    }
+    if stdout_ok && end != SessionEnd::Disconnected && !bridge_closed {
+        drain_to_stdout(&mut ws_stream, agent).await;
+    }
+    if stdout_ok {
+        if let Err(e) = agent.zed.flush_stdout().await {
+            warn!(error = %e, "Failed to flush stdout on shutdown");
+        }
+    }
+
+    end
+}
+
+/// After stdin EOF, keep forwarding bridge messages for up to `grace`
+/// before our close frame goes out. Only the WebSocket side is read; stdin
+/// is gone. Returns `true` if the connection ended meanwhile.
+async fn shutdown_grace(
+    ws_sink: &mut impl MessageSink,
+    ws_stream: &mut impl MessageStream,
+    agent: &mut Agent,
+    grace: Duration,
+) -> bool {
+    info!(
+        grace_secs = grace.as_secs(),
+        "Forwarding bridge messages for OOK_SHUTDOWN_GRACE_SECS before closing"
+    );
+    let until = Instant::now() + grace;
+    loop {
+        select! {
+            msg = ws_stream.next() => match msg {
+                Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => {
+                    if let Err(e) = agent.forward_to_zed(msg.into_data()).await {
+                        warn!(error = %e, "Failed to write to stdout during the shutdown grace period");
+                        return false;
+                    }
+                }
+                Some(Ok(Message::Ping(data))) => {
+                    let _ = ws_sink.send(Message::Pong(data)).await;
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    info!(frame = ?frame, "WebSocket closed by server during the shutdown grace period");
+                    return true;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    warn!(error = %e, "WebSocket error during the shutdown grace period");
+                    return true;
+                }
+                None => return true,
+            },
+
+            _ = deadline(agent.zed.flush_due) => {
+                if let Err(e) = agent.zed.flush_stdout().await {
+                    warn!(error = %e, "Failed to flush stdout during the shutdown grace period");
+                    return false;
+                }
+            }
+
+            _ = deadline(agent.coalescer.as_ref().and_then(Coalescer::deadline)) => {
+                if let Err(e) = agent.flush_coalesced().await {
+                    warn!(error = %e, "Failed to write to stdout during the shutdown grace period");
+                    return false;
+                }
+            }
+
+            _ = tokio::time::sleep_until(until) => {
+                debug!("Shutdown grace period over");
+                return false;
+            }
+
+            signal = agent.signals.recv() => {
+                info!(signal, "Received shutdown signal, ending the grace period");
+                return false;
+            }
+        }
+    }
+}
+
+/// Forward bridge messages still in flight after our close frame, until
+/// the bridge's close reply or `SHUTDOWN_DRAIN_TIMEOUT`.
+async fn drain_to_stdout(ws_stream: &mut impl MessageStream, agent: &mut Agent) {
+    let mut drained = 0u64;
+    let drain = async {
+        while let Some(Ok(msg)) = ws_stream.next().await {
+            let data = match msg {
+                Message::Text(_) | Message::Binary(_) => msg.into_data(),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if agent
+                .app_ping
+                .as_mut()
+                .is_some_and(|ping| ping.answer(&data))
+            {
+                continue;
+            }
+            match agent.zed.forward_to_stdout(&data).await {
+                Ok(true) => agent.metrics.record_to_zed(data.len()),
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!(error = %e, "Failed to write to stdout on shutdown");
+                    break;
+                }
+            }
+            drained += 1;
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, drain)
+        .await
+        .is_err()
+    {
+        debug!("Bridge did not acknowledge the close frame in time");
+    }
+    if drained > 0 {
+        info!(
+            messages = drained,
+            "Forwarded in-flight messages on shutdown"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncBufReadExt, DuplexStream, Lines};
+    use transport::memory::{self, Peer};
+
+    /// Zed's ends of the agent's stdio.
+    struct Stdio {
+        stdin: DuplexStream,
+        stdout: Lines<BufReader<DuplexStream>>,
+    }
+
+    /// An agent on the default config, over in-memory stdio.
+    async fn agent() -> (Agent, Stdio) {
+        let (stdin, agent_stdin) = tokio::io::duplex(64 * 1024);
+        let (agent_stdout, stdout) = tokio::io::duplex(64 * 1024);
+        let agent = build_agent(&Config::default(), &Mode::Proxy, agent_stdin, agent_stdout)
+            .await
+            .expect("agent from the default config");
+        let stdout = BufReader::new(stdout).lines();
+        (agent, Stdio { stdin, stdout })
+    }
+
+    /// Run one session over a fresh in-memory transport alongside `zed`,
+    /// which plays both Zed and the bridge.
+    async fn session<F>(agent: &mut Agent, zed: impl FnOnce(Peer) -> F) -> SessionEnd
+    where
+        F: Future<Output = ()>,
+    {
+        let (transport, bridge) = memory::pair();
+        let mut hangup = ReconnectSignal::install().expect("SIGHUP handler");
+        let (end, ()) = tokio::join!(run_session(transport, agent, &mut hangup), zed(bridge));
+        end
+    }
+
+    const REQUEST: &str = r#"{"jsonrpc":"2.0","id":1,"method":"x"}"#;
+
+    fn text(message: &str) -> Message {
+        Message::Text(message.to_string())
+    }
+
+    #[tokio::test]
+    async fn run_session_forwards_both_ways_until_stdin_closes() {
+        let (mut agent, mut stdio) = agent().await;
+        let end = session(&mut agent, |mut bridge| async move {
+            let request = r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#;
+            let response = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+            stdio.stdin.write_all(request.as_bytes()).await.unwrap();
+            stdio.stdin.write_all(b"\n").await.unwrap();
+            assert_eq!(bridge.recv().await, Some(text(request)));
+            bridge.send(text(response));
+            assert_eq!(
+                stdio.stdout.next_line().await.unwrap().as_deref(),
+                Some(response)
+            );
+
+            drop(stdio.stdin);
+            let Some(Message::Close(Some(frame))) = bridge.recv().await else {
+                panic!("expected a close frame");
+            };
+            assert_eq!(frame.code, CloseCode::Normal);
+            bridge.send(Message::Close(None));
+        })
+        .await;
+
+        assert_eq!(end, SessionEnd::StdinClosed);
+        assert_eq!(agent.metrics.messages_to_bridge, 1);
+        assert_eq!(agent.metrics.messages_to_zed, 1);
+    }
+
+    #[tokio::test]
+    async fn run_session_relays_lines_over_a_raw_connection() {
+        let (mut agent, mut stdio) = agent().await;
+        let (stream, bridge) = tokio::io::duplex(64 * 1024);
+        let transport = transport::lines::LineTransport::new(stream, get_max_line_bytes());
+        let mut hangup = ReconnectSignal::install().expect("SIGHUP handler");
+        let zed = async move {
+            let (bridge_read, mut bridge_write) = tokio::io::split(bridge);
+            let mut bridge_lines = BufReader::new(bridge_read).lines();
+            stdio.stdin.write_all(REQUEST.as_bytes()).await.unwrap();
+            stdio.stdin.write_all(b"\n").await.unwrap();
+            assert_eq!(
+                bridge_lines.next_line().await.unwrap().as_deref(),
+                Some(REQUEST)
+            );
+            let response = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+            bridge_write
+                .write_all(format!("\n{}\n", response).as_bytes())
+                .await
+                .unwrap();
+            assert_eq!(
+                stdio.stdout.next_line().await.unwrap().as_deref(),
+                Some(response)
+            );
+
+            // The close frame has no line form: our side just shuts down
+            drop(stdio.stdin);
+            assert_eq!(bridge_lines.next_line().await.unwrap(), None);
+            drop(bridge_write);
+        };
+        let (end, ()) = tokio::join!(run_session(transport, &mut agent, &mut hangup), zed);
+
+        assert_eq!(end, SessionEnd::StdinClosed);
+        assert_eq!(agent.metrics.messages_to_bridge, 1);
+        assert_eq!(agent.metrics.messages_to_zed, 1);
+    }
+
+    #[tokio::test]
+    async fn run_session_disconnects_on_a_read_error() {
+        let (mut agent, _stdio) = agent().await;
+        let end = session(&mut agent, |mut bridge| async move {
+            bridge.fail(WsError::Io(io::Error::other("connection reset")));
+            let Some(Message::Close(Some(frame))) = bridge.recv().await else {
+                panic!("expected a close frame");
+            };
+            assert_eq!(frame.code, SessionEnd::Disconnected.close_code());
+        })
+        .await;
+
+        assert_eq!(end, SessionEnd::Disconnected);
+    }
+
+    /// Write one line to the agent's stdin and wait for the bridge to get it.
+    async fn send_line(stdio: &mut Stdio, bridge: &mut Peer, line: &str) {
+        stdio.stdin.write_all(line.as_bytes()).await.unwrap();
+        stdio.stdin.write_all(b"\n").await.unwrap();
+        assert_eq!(bridge.recv().await, Some(text(line)));
+    }
+
+    /// Everything the agent wrote to stdout that wasn't read yet, once the
+    /// agent is gone.
+    async fn rest_of_stdout(
+        agent: Agent,
+        mut stdout: Lines<BufReader<DuplexStream>>,
+    ) -> Vec<String> {
+        drop(agent);
+        let mut lines = Vec::new();
+        while let Some(line) = stdout.next_line().await.unwrap() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn normal_close_ends_the_session_without_a_diagnostic() {
+        let (mut agent, mut stdio) = agent().await;
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":{}}"#;
+        let zed = &mut stdio;
+        let end = session(&mut agent, |mut bridge| async move {
+            send_line(zed, &mut bridge, REQUEST).await;
+            bridge.send(text(response));
+            bridge.send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "".into(),
+            })));
+            assert_eq!(
+                zed.stdout.next_line().await.unwrap().as_deref(),
+                Some(response)
+            );
+            // Our side of the close
+            assert!(matches!(bridge.recv().await, Some(Message::Close(_))));
+        })
+        .await;
+
+        // A bridge closing normally is still the connection going away,
+        // not Zed's doing, so the agent reconnects rather than exiting
+        assert_eq!(end, SessionEnd::Disconnected);
+        assert!(agent.reconnects_after(end));
+        assert!(rest_of_stdout(agent, stdio.stdout).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn coded_close_is_reported_to_zed() {
+        let (mut agent, mut stdio) = agent().await;
+        let zed = &mut stdio;
+        let end = session(&mut agent, |mut bridge| async move {
+            send_line(zed, &mut bridge, REQUEST).await;
+            bridge.send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Library(4100),
+                reason: "session expired".into(),
+            })));
+            let line = zed.stdout.next_line().await.unwrap().unwrap();
+            let error: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(error["error"]["code"], acp::BRIDGE_CLOSED);
+            assert_eq!(
+                error["error"]["message"],
+                acp::close_message(4100, "session expired")
+            );
+        })
+        .await;
+
+        assert_eq!(end, SessionEnd::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn stream_end_leads_to_a_reconnect() {
+        let (mut agent, _stdio) = agent().await;
+        let end = session(&mut agent, |bridge| async move { drop(bridge) }).await;
+
+        assert_eq!(end, SessionEnd::Disconnected);
+        assert!(agent.reconnects_after(end));
+    }
+
+    #[tokio::test]
+    async fn queued_lines_keep_their_order_across_a_reconnect() {
+        let line = |n: u32| format!(r#"{{"jsonrpc":"2.0","id":{},"method":"x"}}"#, n);
+        let (mut agent, mut stdio) = agent().await;
+
+        // The bridge goes away after line 1; line 2 can't be sent
+        let zed = &mut stdio;
+        let end = session(&mut agent, |mut bridge| async move {
+            send_line(zed, &mut bridge, &line(1)).await;
+            drop(bridge);
+            zed.stdin
+                .write_all(format!("{}\n", line(2)).as_bytes())
+                .await
+                .unwrap();
+        })
+        .await;
+        assert_eq!(end, SessionEnd::Disconnected);
+
+        // Lines written while disconnected queue up behind it
+        for n in [3, 4] {
+            stdio
+                .stdin
+                .write_all(format!("{}\n", line(n)).as_bytes())
+                .await
+                .unwrap();
+        }
+        let outage = tokio::time::sleep(Duration::from_millis(50));
+        assert!(while_buffering_stdin(outage, &mut agent).await.is_some());
+        assert_eq!(agent.zed.outbound.len(), 3);
+
+        // The new connection gets the queue first, then a line written as it came up
+        let zed = &mut stdio;
+        let end = session(&mut agent, |mut bridge| async move {
+            zed.stdin
+                .write_all(format!("{}\n", line(5)).as_bytes())
+                .await
+                .unwrap();
+            for n in 2..=5 {
+                assert_eq!(bridge.recv().await, Some(text(&line(n))));
+            }
+        })
+        .await;
+        assert_eq!(end, SessionEnd::Disconnected);
+        assert!(agent.zed.outbound.is_empty());
+    }
+
+    #[tokio::test]
+    async fn frames_sent_before_the_session_starts_reach_zed_in_order() {
+        let (mut agent, mut stdio) = agent().await;
+        let hello = r#"{"agent_version":"test"}"#;
+        agent.hello = Some(hello.to_string());
+        agent.zed.outbound.push(REQUEST.to_string());
+        let (transport, mut bridge) = memory::pair();
+        let early: Vec<String> = (1..=3)
+            .map(|n| {
+                format!(
+                    r#"{{"jsonrpc":"2.0","method":"early","params":{{"n":{}}}}}"#,
+                    n
+                )
+            })
+            .collect();
+        for frame in &early {
+            bridge.send(text(frame));
+        }
+
+        let mut hangup = ReconnectSignal::install().expect("SIGHUP handler");
+        let zed = async {
+            // Hello, then the queued line, before anything is read
+            assert_eq!(bridge.recv().await, Some(text(hello)));
+            assert_eq!(bridge.recv().await, Some(text(REQUEST)));
+            for frame in &early {
+                assert_eq!(
+                    stdio.stdout.next_line().await.unwrap().as_ref(),
+                    Some(frame)
+                );
+            }
+            drop(bridge);
+        };
+        let (end, ()) = tokio::join!(run_session(transport, &mut agent, &mut hangup), zed);
+        assert_eq!(end, SessionEnd::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn lsp_stdout_frames_read_back_as_the_bridge_sent_them() {
+        let (mut agent, Stdio { stdin, stdout }) = agent().await;
+        agent.zed.lsp_stdout = true;
+        let mut frames =
+            LineReader::new(stdout.into_inner(), 1024, "stdout").with_framing(Framing::Lsp);
+        // Content-Length counts bytes; a trailing newline is not part of the body
+        let sent = [REQUEST, "{\"text\":\"ünïcödé\"}\n", "{\"a\":\"\\n\\r\"}"];
+        let end = session(&mut agent, |bridge| async move {
+            for message in sent {
+                bridge.send(text(message));
+            }
+            for message in sent {
+                assert_eq!(
+                    frames.next_line().await.unwrap().as_deref(),
+                    Some(message.trim_end_matches('\n'))
+                );
+            }
+            drop(bridge);
+        })
+        .await;
+        assert_eq!(end, SessionEnd::Disconnected);
+        drop(stdin);
+    }
+
+    /// A stdout that takes at most 3 bytes per write, keeping every write.
+    #[derive(Clone, Default)]
+    struct ShortWrites(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl AsyncWrite for ShortWrites {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(3);
+            self.0.lock().unwrap().push(buf[..n].to_vec());
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_writes_reassemble_through_short_writes() {
+        let stdout = ShortWrites::default();
+        let (_stdin, agent_stdin) = tokio::io::duplex(64);
+        let mut agent = build_agent(
+            &Config::default(),
+            &Mode::Proxy,
+            agent_stdin,
+            stdout.clone(),
+        )
+        .await
+        .expect("agent from the default config");
+        agent.zed.chunk_write_bytes = Some(4);
+
+        // Bigger than the stdout buffer, so it reaches the writer mid-message too
+        let big = format!(r#"{{"result":"{}"}}"#, "0123456789".repeat(2000));
+        let messages = ["{}", big.as_str(), r#"{"id":"ü"}"#];
+        for message in messages {
+            agent.zed.write_message(message).await.unwrap();
+        }
+        agent.zed.flush_stdout().await.unwrap();
+
+        let writes = stdout.0.lock().unwrap();
+        assert!(writes.iter().all(|w| w.len() <= 3));
+        let written = String::from_utf8(writes.concat()).unwrap();
+        assert_eq!(
+            written,
+            format!("{}\n{}\n{}\n", messages[0], big, messages[2])
+        );
+    }
+
+    #[test]
+    fn url_host_brackets_bare_ipv6_only() {
+        assert_eq!(url_host("::1"), "[::1]");
+        assert_eq!(url_host("2001:db8::8647"), "[2001:db8::8647]");
+        assert_eq!(url_host("[::1]"), "[::1]");
+        assert_eq!(url_host("127.0.0.1"), "127.0.0.1");
+        assert_eq!(url_host("lima-box-sandbox"), "lima-box-sandbox");
+        assert!(matches!(url_host("127.0.0.1"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn default_remote_host_uses_the_first_label_in_lowercase() {
+        assert_eq!(
+            default_remote_host("MyLaptop.local"),
+            "lima-mylaptop-sandbox"
+        );
+        assert_eq!(
+            default_remote_host("build-07.ci.example.com"),
+            "lima-build-07-sandbox"
+        );
+        assert_eq!(default_remote_host("DESKTOP"), "lima-desktop-sandbox");
+        assert_eq!(default_remote_host("box"), "lima-box-sandbox");
+    }
+
+    #[test]
+    fn hello_message_lists_the_enabled_features() {
+        let hello: serde_json::Value =
+            serde_json::from_str(&hello_message(&["validate_json", "keepalive"])).unwrap();
+        assert_eq!(hello["agent_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(hello["features"], json!(["validate_json", "keepalive"]));
+        assert_eq!(hello["pid"], std::process::id());
+        assert_eq!(hello["hostname"], local_hostname());
+        assert_eq!(hello.as_object().unwrap().len(), 4);
+
+        let bare: serde_json::Value = serde_json::from_str(&hello_message(&[])).unwrap();
+        assert_eq!(bare["features"], json!([]));
+    }
+
+    #[test]
+    fn rejected_handshakes_exit_with_the_auth_code() {
+        use tokio_tungstenite::tungstenite::handshake::client::Response;
+        use tokio_tungstenite::tungstenite::http::StatusCode;
+        let rejection = |status, body: &str| {
+            let mut response = Response::new(Some(body.as_bytes().to_vec()));
+            *response.status_mut() = status;
+            WsError::Http(response)
+        };
+        for (status, body) in [
+            (StatusCode::FORBIDDEN, "origin not allowed"),
+            (StatusCode::FORBIDDEN, ""),
+            (StatusCode::UNAUTHORIZED, ""),
+        ] {
+            assert_eq!(
+                Exit::connect_error(&rejection(status, body)),
+                Exit::AuthFailed
+            );
+        }
+        assert_eq!(
+            Exit::connect_error(&rejection(StatusCode::NOT_FOUND, "")),
+            Exit::Protocol
+        );
+        assert_eq!(
+            Exit::connect_error(&WsError::Io(io::ErrorKind::ConnectionRefused.into())),
+            Exit::ConnectFailed
+        );
+    }
+
+    #[test]
+    fn session_ends_map_to_exit_codes() {
+        for (end, exit) in [
+            (None, Exit::Clean),
+            (Some(SessionEnd::StdinClosed), Exit::Clean),
+            (Some(SessionEnd::Signal), Exit::Clean),
+            (Some(SessionEnd::ClientGone), Exit::Clean),
+            (Some(SessionEnd::MaxAge), Exit::Clean),
+            (Some(SessionEnd::StdoutFailed), Exit::Failed),
+            (Some(SessionEnd::Disconnected), Exit::ConnectFailed),
+            (Some(SessionEnd::Reconnect), Exit::ConnectFailed),
+            (Some(SessionEnd::IdleTimeout), Exit::IdleTimeout),
+            (Some(SessionEnd::AcpVersion), Exit::AcpVersion),
+        ] {
+            assert_eq!(finish(end), exit, "{:?}", end);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Reverse mode (`OOK_LISTEN_ADDR`): instead of dialing the bridge, accept
+//! a raw TCP connection from it and relay NDJSON lines to and from stdio.
+//!
+//! One connection is served at a time, by the same session loop as a
+//! WebSocket, over a `LineTransport`. While it is active, further
+//! connections are accepted and closed straight away, or with
+//! `OOK_LISTEN_QUEUE=1` left in the listen backlog until it ends.
+
+use crate::signal::ReconnectSignal;
+use crate::transport::lines::LineTransport;
+use crate::{get_max_line_bytes, run_session, while_buffering_stdin, Agent, SessionEnd};
+use std::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tracing::{debug, info, warn};
+
+/// Serve bridge connections on `addr` until shutdown, returning how the
+/// last session ended (`None` if none was active).
+pub async fn run(
+    addr: &str,
+    queue: bool,
+    agent: &mut Agent,
+    hangup: &mut ReconnectSignal,
+) -> io::Result<Option<SessionEnd>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(addr = %listener.local_addr()?, queue, "Listening for the bridge");
+    // A raw connection has no ping frames to answer them
+    if agent.ping_interval.take().is_some() {
+        debug!("Not sending keepalive pings over a raw TCP connection");
+    }
+
+    loop {
+        let Some(accepted) = while_buffering_stdin(listener.accept(), agent).await else {
+            return Ok(None);
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(error = %e, "Failed to accept bridge connection");
+                continue;
+            }
+        };
+        info!(peer = %peer, "Bridge connected");
+
+        let transport = LineTransport::new(stream, get_max_line_bytes());
+        let end = serve(transport, &listener, queue, agent, hangup).await;
+        match end {
+            SessionEnd::Disconnected => warn!(
+                queued = agent.zed.outbound.len(),
+                "Bridge connection ended, waiting for the next one"
+            ),
+            end if agent.reconnects_after(end) => info!(
+                reason = end.reason(),
+                queued = agent.zed.outbound.len(),
+                "Closed the bridge connection, waiting for the next one"
+            ),
+            end => return Ok(Some(end)),
+        }
+    }
+}
+
+/// Run the session on one accepted connection, turning away any others
+/// that arrive meanwhile unless they queue.
+async fn serve(
+    transport: LineTransport<TcpStream>,
+    listener: &TcpListener,
+    queue: bool,
+    agent: &mut Agent,
+    hangup: &mut ReconnectSignal,
+) -> SessionEnd {
+    let session = run_session(transport, agent, hangup);
+    tokio::pin!(session);
+    loop {
+        select! {
+            end = &mut session => return end,
+
+            accepted = listener.accept(), if !queue => {
+                // Dropping the stream closes it
+                if let Ok((_, peer)) = accepted {
+                    warn!(peer = %peer, "Rejecting bridge connection, one is already active");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Log setup. Logs go to stderr, plus an optional file; stdout is reserved for ACP.
+
+use std::borrow::Cow;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use tracing::{warn, Event, Span, Subscriber};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::{FmtSpan, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// Filter when neither `OOK_LOG` nor `RUST_LOG` is set: the agent at info,
+/// the WebSocket crates only when something is wrong.
+const DEFAULT_FILTER: &str = "error,ook=info,tungstenite=warn,tokio_tungstenite=warn";
+
+/// `OOK_LOG_TARGET_HOST=1`: tag log lines with the bridge they concern.
+static TAG_TARGET_HOST: AtomicBool = AtomicBool::new(false);
+
+/// The bridge last set by `set_target_host`, for the human-format prefix.
+static TARGET_HOST: RwLock<Option<String>> = RwLock::new(None);
+
+/// Note the bridge being connected to: with `OOK_LOG_TARGET_HOST=1`, it is
+/// recorded as `target_host` on the current span (the `session` span, which
+/// declares the field) and shown as a `[host:port]` prefix in pretty and
+/// compact output. Replaces any earlier target, e.g. after a failover.
+pub fn set_target_host(host: &str) {
+    if !TAG_TARGET_HOST.load(Ordering::Relaxed) {
+        return;
+    }
+    Span::current().record("target_host", host);
+    if let Ok(mut target) = TARGET_HOST.write() {
+        *target = Some(host.to_string());
+    }
+}
+
+/// Prefixes each event from `F` with the target host, once one is set.
+struct TargetPrefix<F>(F);
+
+impl<S, N, F> FormatEvent<S, N> for TargetPrefix<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        if let Some(host) = TARGET_HOST.read().ok().as_deref().and_then(Option::as_ref) {
+            write!(writer, "[{}] ", host)?;
+        }
+        self.0.format_event(ctx, writer, event)
+    }
+}
+
+/// Layout of stderr log lines (`OOK_LOG_FORMAT`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// One JSON object per line; what Zed's log view and tooling expect.
+    Json,
+    /// Multi-line, human-oriented output for running the agent by hand.
+    Pretty,
+    /// Single-line human-oriented output.
+    Compact,
+}
+
+impl LogFormat {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "json" => Ok(Self::Json),
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            other => Err(format!(
+                "Unsupported OOK_LOG_FORMAT {:?} (expected \"json\", \"pretty\" or \"compact\")",
+                other
+            )),
+        }
+    }
+
+    /// `OOK_LOG_FORMAT`, else pretty in a terminal and JSON otherwise (as
+    /// under Zed).
+    fn from_env() -> Result<Self, String> {
+        match env::var("OOK_LOG_FORMAT") {
+            Ok(value) => Self::parse(&value),
+            Err(_) if io::stderr().is_terminal() => Ok(Self::Pretty),
+            Err(_) => Ok(Self::Json),
+        }
+    }
+}
+
+/// The filter directives to use and the env var they came from: `OOK_LOG`
+/// (so users needn't set `RUST_LOG` for their whole shell), then `RUST_LOG`,
+/// then `DEFAULT_FILTER`. Either var replaces the default entirely.
+fn filter_directives(
+    ook_log: Option<String>,
+    rust_log: Option<String>,
+) -> (&'static str, Cow<'static, str>) {
+    match (ook_log, rust_log) {
+        (Some(value), _) => ("OOK_LOG", value.into()),
+        (None, Some(value)) => ("RUST_LOG", value.into()),
+        (None, None) => ("default", DEFAULT_FILTER.into()),
+    }
+}
+
+fn env_filter() -> Result<EnvFilter, String> {
+    let non_empty = |name| env::var(name).ok().filter(|v| !v.trim().is_empty());
+    let (source, directives) = filter_directives(non_empty("OOK_LOG"), non_empty("RUST_LOG"));
+    EnvFilter::try_new(directives.as_ref())
+        .map_err(|e| format!("Invalid {} filter {:?}: {}", source, directives, e))
+}
+
+/// Install the global subscriber. Must run before anything logs.
+///
+/// With `OOK_LOG_FILE` set, events are also appended as JSON to that file,
+/// rotated daily (`<name>.YYYY-MM-DD`). If the file can't be set up the
+/// agent warns and keeps logging to stderr only. With `OOK_OTLP_ENDPOINT`
+/// set, spans are also exported there (cargo feature `otel`).
+pub fn init() -> Result<(), String> {
+    let format = LogFormat::from_env()?;
+    let filter = env_filter()?;
+    let ansi = io::stderr().is_terminal();
+    TAG_TARGET_HOST.store(
+        env::var("OOK_LOG_TARGET_HOST").is_ok_and(|v| v == "1" || v == "true"),
+        Ordering::Relaxed,
+    );
+
+    // Span close events carry `time.busy`/`time.idle`, e.g. the connect
+    // phases in `bridge`
+    let stderr_layer = match format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(io::stderr)
+            .boxed(),
+        LogFormat::Pretty => fmt::layer()
+            .pretty()
+            .with_ansi(ansi)
+            .with_span_events(FmtSpan::CLOSE)
+            .map_event_format(TargetPrefix)
+            .with_writer(io::stderr)
+            .boxed(),
+        LogFormat::Compact => fmt::layer()
+            .compact()
+            .with_ansi(ansi)
+            .with_span_events(FmtSpan::CLOSE)
+            .map_event_format(TargetPrefix)
+            .with_writer(io::stderr)
+            .boxed(),
+    };
+
+    let log_file = env::var_os("OOK_LOG_FILE").map(PathBuf::from);
+    let (file_layer, file_error) = match log_file.as_deref().map(open_log_file) {
+        Some(Ok(appender)) => (
+            Some(
+                fmt::layer()
+                    .json()
+                    .with_ansi(false)
+                    .with_span_events(FmtSpan::CLOSE)
+                    .with_writer(appender),
+            ),
+            None,
+        ),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
+    #[cfg(feature = "otel")]
+    let otel_layer = env::var("OOK_OTLP_ENDPOINT")
+        .ok()
+        .map(|endpoint| crate::otel::layer(&endpoint))
+        .transpose()?;
+    #[cfg(not(feature = "otel"))]
+    let otel_layer: Option<tracing_subscriber::layer::Identity> = None;
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stderr_layer)
+        .with(file_layer)
+        .with(otel_layer)
+        .init();
+
+    #[cfg(not(feature = "otel"))]
+    if env::var_os("OOK_OTLP_ENDPOINT").is_some() {
+        warn!("OOK_OTLP_ENDPOINT is set but this build has no OpenTelemetry export (cargo feature `otel`); logging locally only");
+    }
+
+    if let (Some(path), Some(e)) = (log_file, file_error) {
+        warn!(
+            error = %e,
+            path = %path.display(),
+            "Failed to open OOK_LOG_FILE, logging to stderr only"
+        );
+    }
+    Ok(())
+}
+
+fn open_log_file(path: &Path) -> Result<RollingFileAppender, String> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| "path has no file name".to_string())?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(file_name.to_string_lossy())
+        .build(dir)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn ook_log_wins_over_rust_log_and_either_replaces_the_default() {
+        let (source, directives) = filter_directives(some("ook=trace"), some("debug"));
+        assert_eq!((source, directives.as_ref()), ("OOK_LOG", "ook=trace"));
+        let (source, directives) = filter_directives(None, some("debug"));
+        assert_eq!((source, directives.as_ref()), ("RUST_LOG", "debug"));
+        let (source, directives) = filter_directives(None, None);
+        assert_eq!((source, directives.as_ref()), ("default", DEFAULT_FILTER));
+    }
+
+    #[test]
+    fn default_filter_parses_and_caps_everything_at_info() {
+        let filter = EnvFilter::try_new(DEFAULT_FILTER).unwrap();
+        assert_eq!(filter.max_level_hint(), Some(tracing::Level::INFO.into()));
+    }
+
+    #[test]
+    fn bad_directives_are_rejected() {
+        assert!(EnvFilter::try_new("ook=loud").is_err());
+        assert!(EnvFilter::try_new("ook=debug,tungstenite=warn").is_ok());
+    }
+
+    #[test]
+    fn log_format_names() {
+        assert_eq!(LogFormat::parse("json"), Ok(LogFormat::Json));
+        assert_eq!(LogFormat::parse("pretty"), Ok(LogFormat::Pretty));
+        assert_eq!(LogFormat::parse("compact"), Ok(LogFormat::Compact));
+        assert!(LogFormat::parse("JSON").is_err());
+    }
+}
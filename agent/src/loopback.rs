@@ -0,0 +1,79 @@
+//! In-process echo bridge for testing without a real Ook Bridge
+//! (`OOK_LOOPBACK=1`, cargo feature `loopback`).
+//!
+//! Every text or binary frame is sent straight back, so stdin lines come
+//! out on stdout having gone through the handshake, framing and forwarding
+//! code exactly as they would against the bridge.
+
+use futures_util::{SinkExt, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+/// Bind an ephemeral port on localhost and echo on a background task.
+/// Returns the address to connect to; the task ends with the process.
+pub async fn spawn() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    info!(addr = %addr, "Serving loopback echo bridge");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(echo(stream));
+                }
+                Err(e) => warn!(error = %e, "Failed to accept loopback connection"),
+            }
+        }
+    });
+    Ok(addr)
+}
+
+async fn echo(stream: TcpStream) {
+    let mut ws = match tokio_tungstenite::accept_hdr_async(stream, select_subprotocol).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            warn!(error = %e, "Loopback handshake failed");
+            return;
+        }
+    };
+
+    while let Some(Ok(msg)) = ws.next().await {
+        let reply = match msg {
+            Message::Text(_) | Message::Binary(_) => msg,
+            Message::Close(_) => break,
+            // tungstenite answers pings itself
+            _ => continue,
+        };
+        if ws.send(reply).await.is_err() {
+            break;
+        }
+    }
+    debug!("Loopback connection closed");
+}
+
+/// Accept the first subprotocol offered, so `OOK_SUBPROTOCOL` works too.
+// The signature is tungstenite's handshake callback
+#[allow(clippy::result_large_err)]
+fn select_subprotocol(
+    request: &Request,
+    mut response: Response,
+) -> Result<Response, ErrorResponse> {
+    let offered = request
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .and_then(|first| first.trim().parse().ok());
+    if let Some(protocol) = offered {
+        response
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    Ok(response)
+}
@@ -0,0 +1,72 @@
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// A single send blocked this long means the link to the bridge is slow.
+const SLOW_SEND: Duration = Duration::from_secs(1);
+
+/// Traffic counters for the whole process, across reconnects.
+///
+/// Updated inline on the forwarding path (integer adds and a clock read)
+/// and logged as one JSON line every `OOK_METRICS_INTERVAL_SECS`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub messages_to_bridge: u64,
+    pub messages_to_zed: u64,
+    pub bytes_to_bridge: u64,
+    pub bytes_to_zed: u64,
+    pub reconnects: u64,
+    pub last_pong_rtt_ms: Option<f64>,
+    /// Time spent waiting for the bridge connection to accept sends, during
+    /// which stdin lines are not forwarded.
+    pub send_blocked_ms: u64,
+    /// Bridge notifications replaced by a newer one (`OOK_COALESCE_MS`).
+    pub coalesced: u64,
+    pub last_to_bridge: Option<SystemTime>,
+    pub last_from_bridge: Option<SystemTime>,
+}
+
+impl Metrics {
+    /// Count a message sent to the bridge; `blocked` is how long the send
+    /// (including the flush) took.
+    pub fn record_to_bridge(&mut self, bytes: usize, blocked: Duration) {
+        if blocked >= SLOW_SEND {
+            warn!(
+                blocked_ms = blocked.as_millis() as u64,
+                bytes,
+                "Bridge connection is slow to accept data; stdin forwarding is paused meanwhile"
+            );
+        }
+        self.send_blocked_ms += blocked.as_millis() as u64;
+        self.messages_to_bridge += 1;
+        self.bytes_to_bridge += bytes as u64;
+        self.last_to_bridge = Some(SystemTime::now());
+    }
+
+    pub fn record_to_zed(&mut self, bytes: usize) {
+        self.messages_to_zed += 1;
+        self.bytes_to_zed += bytes as u64;
+        self.last_from_bridge = Some(SystemTime::now());
+    }
+
+    /// Messages forwarded either way.
+    pub fn messages(&self) -> u64 {
+        self.messages_to_bridge + self.messages_to_zed
+    }
+
+    /// `stdin_high_water` is the most lines ever waiting in the stdin
+    /// channel, which is kept outside the counters.
+    pub fn log(&self, stdin_high_water: usize) {
+        info!(
+            messages_to_bridge = self.messages_to_bridge,
+            messages_to_zed = self.messages_to_zed,
+            bytes_to_bridge = self.bytes_to_bridge,
+            bytes_to_zed = self.bytes_to_zed,
+            reconnects = self.reconnects,
+            last_pong_rtt_ms = self.last_pong_rtt_ms,
+            send_blocked_ms = self.send_blocked_ms,
+            coalesced = self.coalesced,
+            stdin_high_water,
+            "Connection metrics"
+        );
+    }
+}
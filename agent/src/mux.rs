@@ -0,0 +1,185 @@
+//! Several logical ACP sessions over one process and one WebSocket
+//! (`OOK_MUX=1`).
+//!
+//! Wire tagging:
+//!
+//! - stdio: each line is `<channel> <message>`, a channel id (1-64 of
+//!   `A-Z a-z 0-9 - _ .`), one space, then the JSON-RPC message. Lines from
+//!   the agent to Zed use the same form.
+//! - WebSocket: each text frame is an envelope,
+//!   `{"channel":"<channel>","message":<message>}`, in both directions.
+//!
+//! A channel opens with its first stdin line. Bridge envelopes for a
+//! channel that never opened, and anything malformed, are dropped with a
+//! warning. Errors the agent reports itself (bridge unreachable or closed)
+//! go to every open channel.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+const MAX_CHANNEL_LEN: usize = 64;
+
+/// One logical session.
+struct Channel {
+    opened: Instant,
+    to_bridge: u64,
+    to_zed: u64,
+}
+
+#[derive(Default)]
+pub struct Mux {
+    channels: HashMap<String, Channel>,
+}
+
+impl Mux {
+    /// Turn a tagged stdin line into a bridge envelope, opening its channel
+    /// if new.
+    pub fn wrap(&mut self, line: &str) -> Result<String, String> {
+        let (channel, message) = line
+            .split_once(' ')
+            .ok_or("missing channel tag (expected \"<channel> <message>\")")?;
+        if !valid_channel(channel) {
+            return Err(format!("invalid channel id {:?}", channel));
+        }
+        let message: Value =
+            serde_json::from_str(message).map_err(|e| format!("invalid JSON: {}", e))?;
+
+        let state = self.channels.entry(channel.to_string()).or_insert_with(|| {
+            info!(channel, "Opened mux channel");
+            Channel {
+                opened: Instant::now(),
+                to_bridge: 0,
+                to_zed: 0,
+            }
+        });
+        state.to_bridge += 1;
+        Ok(serde_json::json!({ "channel": channel, "message": message }).to_string())
+    }
+
+    /// Turn a bridge envelope back into a tagged line, without the newline.
+    /// `None` (after a warning) if it is malformed or for an unknown channel.
+    pub fn unwrap(&mut self, envelope: &str) -> Option<String> {
+        let parsed = serde_json::from_str::<Value>(envelope).ok();
+        let fields = parsed.as_ref().and_then(|envelope| {
+            let channel = envelope.get("channel")?.as_str()?;
+            Some((channel, envelope.get("message")?))
+        });
+        let Some((channel, message)) = fields else {
+            warn!(
+                direction = "bridge->zed",
+                bytes = envelope.len(),
+                "Dropping bridge message that isn't a mux envelope"
+            );
+            return None;
+        };
+        let Some(state) = self.channels.get_mut(channel) else {
+            warn!(
+                channel,
+                "Dropping bridge message for an unknown mux channel"
+            );
+            return None;
+        };
+        state.to_zed += 1;
+        Some(format!("{} {}", channel, message))
+    }
+
+    /// Tag `message` for every open channel, for errors that aren't tied to
+    /// one of them.
+    pub fn broadcast(&self, message: &str) -> Vec<String> {
+        self.channels
+            .keys()
+            .map(|channel| format!("{} {}", channel, message))
+            .collect()
+    }
+
+    /// Log per-channel totals, on shutdown.
+    pub fn log_summary(&self) {
+        for (channel, state) in &self.channels {
+            info!(
+                channel = %channel,
+                to_bridge = state.to_bridge,
+                to_zed = state.to_zed,
+                open_secs = state.opened.elapsed().as_secs(),
+                "Mux channel summary"
+            );
+        }
+    }
+}
+
+fn valid_channel(channel: &str) -> bool {
+    (1..=MAX_CHANNEL_LEN).contains(&channel.len())
+        && channel
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut mux = Mux::default();
+        let envelope = mux.wrap(r#"zed-1 {"jsonrpc":"2.0","id":1}"#).unwrap();
+        let parsed: Value = serde_json::from_str(&envelope).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!({"channel": "zed-1", "message": {"jsonrpc": "2.0", "id": 1}})
+        );
+        assert_eq!(
+            mux.unwrap(&envelope).as_deref(),
+            Some(r#"zed-1 {"id":1,"jsonrpc":"2.0"}"#)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_stdin_lines() {
+        let mut mux = Mux::default();
+        let long = "c".repeat(MAX_CHANNEL_LEN + 1);
+        for line in [
+            r#"{"jsonrpc":"2.0"}"#.to_string(),
+            r#"a/b {"jsonrpc":"2.0"}"#.to_string(),
+            r#" {"jsonrpc":"2.0"}"#.to_string(),
+            format!(r#"{} {{"jsonrpc":"2.0"}}"#, long),
+            "zed-1 not json".to_string(),
+        ] {
+            assert!(mux.wrap(&line).is_err(), "accepted {:?}", line);
+        }
+        assert!(mux.broadcast("{}").is_empty());
+
+        let longest = "c".repeat(MAX_CHANNEL_LEN);
+        assert!(mux.wrap(&format!("{} {{}}", longest)).is_ok());
+    }
+
+    #[test]
+    fn drops_unknown_channels_and_non_envelopes() {
+        let mut mux = Mux::default();
+        mux.wrap("a {}").unwrap();
+        for frame in [
+            r#"{"channel":"b","message":{}}"#,
+            r#"{"jsonrpc":"2.0","id":1}"#,
+            r#"{"channel":"a"}"#,
+            r#"{"channel":1,"message":{}}"#,
+            "not json",
+        ] {
+            assert_eq!(mux.unwrap(frame), None, "kept {:?}", frame);
+        }
+        assert_eq!(
+            mux.unwrap(r#"{"channel":"a","message":{}}"#).as_deref(),
+            Some("a {}")
+        );
+    }
+
+    #[test]
+    fn broadcast_reaches_every_open_channel() {
+        let mut mux = Mux::default();
+        for line in ["a {}", "b {}", "a {}"] {
+            mux.wrap(line).unwrap();
+        }
+        let mut lines = mux.broadcast(r#"{"error":1}"#);
+        lines.sort();
+        assert_eq!(lines, [r#"a {"error":1}"#, r#"b {"error":1}"#]);
+    }
+}
@@ -0,0 +1,123 @@
+//! `--once`: send one JSON-RPC request to the bridge, print its response,
+//! and exit, for shell pipelines and CI checks.
+//!
+//! The request comes from `--once=<JSON>`, or else all of stdin. It goes
+//! through the usual connect (failover, connect timeout, auth token and
+//! handshake headers). Messages from the bridge other than the response to
+//! the request's `id` are skipped. The response is printed as the bridge
+//! sent it, whether it carries a result or an error; with none within
+//! `OOK_ONCE_TIMEOUT_SECS` the agent exits with `Exit::NoResponse`.
+
+use crate::bridge::{describe_connect_error, Bridges};
+use crate::Exit;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::debug;
+
+pub async fn run(bridges: &mut Bridges, inline: Option<String>, timeout: Duration) -> Exit {
+    let text = match inline {
+        Some(text) => text,
+        None => {
+            let mut text = String::new();
+            if let Err(e) = tokio::io::stdin().read_to_string(&mut text).await {
+                eprintln!("error: failed to read the request from stdin: {}", e);
+                return Exit::Failed;
+            }
+            text
+        }
+    };
+    let (request, id) = match parse_request(&text) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return Exit::Failed;
+        }
+    };
+
+    let mut ws_stream = match bridges.connect().await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            eprintln!(
+                "error: Ook Bridge at {} handshake failed: {}",
+                bridges.describe(),
+                describe_connect_error(&e)
+            );
+            return Exit::connect_error(&e);
+        }
+    };
+    if let Err(e) = ws_stream.send(Message::Text(request)).await {
+        eprintln!("error: failed to send the request: {}", e);
+        return Exit::ConnectFailed;
+    }
+
+    let response = tokio::time::timeout(timeout, async {
+        while let Some(msg) = ws_stream.next().await {
+            let data = match msg {
+                Ok(msg @ (Message::Text(_) | Message::Binary(_))) => msg.into_data(),
+                Ok(Message::Close(frame)) => {
+                    return Err(format!(
+                        "bridge closed the connection before responding: {:?}",
+                        frame
+                    ))
+                }
+                Ok(_) => continue,
+                Err(e) => return Err(format!("WebSocket error: {}", e)),
+            };
+            let Ok(value) = serde_json::from_slice::<Value>(&data) else {
+                debug!(
+                    bytes = data.len(),
+                    "Skipping a bridge message that isn't JSON"
+                );
+                continue;
+            };
+            if value.get("id") == Some(&id) && value.get("method").is_none() {
+                return Ok(data);
+            }
+            debug!(
+                bytes = data.len(),
+                "Skipping a bridge message that isn't the response"
+            );
+        }
+        Err("connection ended before a response".to_string())
+    })
+    .await;
+    let _ = ws_stream.close(None).await;
+
+    match response {
+        Ok(Ok(data)) => {
+            println!("{}", String::from_utf8_lossy(&data).trim_end());
+            Exit::Clean
+        }
+        Ok(Err(e)) => {
+            eprintln!("error: {}", e);
+            Exit::ConnectFailed
+        }
+        Err(_) => {
+            eprintln!(
+                "error: no response to request id {} within {}s (OOK_ONCE_TIMEOUT_SECS)",
+                id,
+                timeout.as_secs()
+            );
+            Exit::NoResponse
+        }
+    }
+}
+
+/// The request as one line, and its `id`. Notifications are refused: no
+/// response would ever come.
+fn parse_request(text: &str) -> Result<(String, Value), String> {
+    let value: Value =
+        serde_json::from_str(text).map_err(|e| format!("request is not valid JSON: {}", e))?;
+    if value.get("method").and_then(Value::as_str).is_none() {
+        return Err("request has no \"method\"".to_string());
+    }
+    let id = value
+        .get("id")
+        .filter(|id| !id.is_null())
+        .cloned()
+        .ok_or_else(|| "request has no \"id\", so no response would come".to_string())?;
+    Ok((value.to_string(), id))
+}
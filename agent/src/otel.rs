@@ -0,0 +1,71 @@
+//! OpenTelemetry trace export (`OOK_OTLP_ENDPOINT`, cargo feature `otel`).
+//!
+//! The agent's `tracing` spans (`session`, `connect` and its steps) become
+//! OTLP spans, sent to a collector over HTTP/protobuf in batches, and each
+//! log event becomes an event on the span it happened in. The same
+//! `OOK_LOG` filter applies, so the per-message events only go out with
+//! `ook=debug`. A span is exported when it closes; for the `session` span
+//! that is when the agent exits.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Config, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use std::sync::OnceLock;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+use url::Url;
+
+/// Where an endpoint without a path sends traces, per the OTLP spec.
+const TRACES_PATH: &str = "/v1/traces";
+
+/// Kept for `shutdown`: the layer's tracer holds the provider open, so
+/// dropping it wouldn't flush anything.
+static PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+/// The layer feeding spans to the collector at `endpoint`, e.g.
+/// `http://collector:4318`. Needs the Tokio runtime, for the batch export.
+pub fn layer<S>(endpoint: &str) -> Result<OpenTelemetryLayer<S, Tracer>, String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let mut url = Url::parse(endpoint)
+        .map_err(|e| format!("Invalid OOK_OTLP_ENDPOINT {:?}: {}", endpoint, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "Unsupported OOK_OTLP_ENDPOINT scheme {:?} (expected \"http\" or \"https\")",
+            url.scheme()
+        ));
+    }
+    if url.path() == "/" {
+        url.set_path(TRACES_PATH);
+    }
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(url.as_str());
+    let resource = Resource::new([
+        KeyValue::new("service.name", "ook"),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ]);
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(Config::default().with_resource(resource))
+        .install_batch(runtime::Tokio)
+        .map_err(|e| format!("Failed to set up OTLP export to {}: {}", url, e))?;
+    let tracer = provider.tracer("ook");
+    let _ = PROVIDER.set(provider);
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Export the spans still batched, e.g. the `session` span just closed.
+/// Waits on the exporter, so runs on a blocking thread.
+pub async fn shutdown() {
+    let Some(provider) = PROVIDER.get() else {
+        return;
+    };
+    let _ = tokio::task::spawn_blocking(|| provider.shutdown()).await;
+}
@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use std::env;
+use tracing::warn;
+
+const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// What to do with a new stdin line when the outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading stdin until the queue drains (Zed blocks on the pipe).
+    Block,
+    /// Discard the oldest queued line to make room.
+    DropOldest,
+}
+
+impl OverflowPolicy {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            other => Err(format!(
+                "Unsupported OOK_OUTBOUND_QUEUE_POLICY {:?} (expected \"block\" or \"drop-oldest\")",
+                other
+            )),
+        }
+    }
+}
+
+/// Lines read from stdin that have not yet been sent to the bridge.
+///
+/// While the bridge is connected the queue is flushed after every line, so
+/// it only fills up during a reconnect. Lines leave in exactly the order
+/// they were read, including across a reconnect: every line is queued,
+/// never sent past the queue (see `flush_outbound`).
+pub struct OutboundQueue {
+    lines: VecDeque<String>,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl OutboundQueue {
+    /// Build from `OOK_OUTBOUND_QUEUE_SIZE` and `OOK_OUTBOUND_QUEUE_POLICY`.
+    pub fn from_env() -> Result<Self, String> {
+        let capacity = env::var("OOK_OUTBOUND_QUEUE_SIZE")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_QUEUE_SIZE);
+        let policy = match env::var("OOK_OUTBOUND_QUEUE_POLICY") {
+            Ok(value) => OverflowPolicy::parse(&value)?,
+            Err(_) => OverflowPolicy::Block,
+        };
+
+        Ok(Self {
+            lines: VecDeque::with_capacity(capacity.min(DEFAULT_QUEUE_SIZE)),
+            capacity,
+            policy,
+        })
+    }
+
+    /// True when stdin must not be read until the queue drains.
+    pub fn is_blocked(&self) -> bool {
+        self.policy == OverflowPolicy::Block && self.lines.len() >= self.capacity
+    }
+
+    pub fn push(&mut self, line: String) {
+        if self.lines.len() >= self.capacity {
+            // Only reachable with DropOldest; Block stops reading first.
+            self.lines.pop_front();
+            warn!(
+                capacity = self.capacity,
+                "Outbound queue full, dropped oldest message"
+            );
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn front(&self) -> Option<&String> {
+        self.lines.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<String> {
+        self.lines.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
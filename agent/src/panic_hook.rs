@@ -0,0 +1,87 @@
+//! Last-ditch report to Zed when the agent panics.
+//!
+//! Without this a panic just ends the process and Zed sees the agent
+//! vanish. The hook logs the panic, writes an "internal agent error"
+//! JSON-RPC error to stdout in the stdio framing, and aborts. Only the
+//! panic location is sent to Zed: the payload may hold message contents.
+//! The error bypasses the agent's stdout buffer, so anything buffered but
+//! unflushed at the time is lost; it is the last thing Zed receives.
+
+use crate::acp;
+use std::io::Write;
+use std::panic::{self, PanicHookInfo};
+use tracing::error;
+
+/// Install the hook; `lsp` selects `Content-Length` framing on stdout. With
+/// `OOK_MUX` nothing is written: there is no channel to tag it with, and an
+/// untagged line would just be dropped.
+pub fn install(lsp: bool, mux: bool) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info: &PanicHookInfo| {
+        default_hook(info);
+        let location = info
+            .location()
+            .map_or_else(|| "unknown location".to_string(), ToString::to_string);
+        error!(location = %location, "Agent panicked, aborting");
+
+        if !mux {
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(report(&location, lsp).as_bytes());
+            let _ = stdout.flush();
+        }
+        std::process::abort();
+    }));
+}
+
+/// What the hook writes to stdout for a panic at `location`.
+fn report(location: &str, lsp: bool) -> String {
+    let message = acp::error_response(
+        acp::INTERNAL_ERROR,
+        &format!("internal agent error (panic at {})", location),
+    );
+    if lsp {
+        format!("Content-Length: {}\r\n\r\n{}", message.len(), message)
+    } else {
+        format!("{}\n", message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn parse(report: &str) -> Value {
+        serde_json::from_str(report).expect("the report is JSON")
+    }
+
+    #[test]
+    fn report_is_one_json_rpc_error_line() {
+        let report = report("src/lib.rs:12:5", false);
+        let (line, rest) = report.split_once('\n').unwrap();
+        assert_eq!(rest, "");
+        let error = parse(line);
+        assert_eq!(error["jsonrpc"], "2.0");
+        assert_eq!(error["error"]["code"], acp::INTERNAL_ERROR);
+        assert_eq!(
+            error["error"]["message"],
+            "internal agent error (panic at src/lib.rs:12:5)"
+        );
+    }
+
+    #[test]
+    fn lsp_report_has_the_body_length_in_bytes() {
+        // Not that a path should hold any, but the length must count bytes
+        let report = report("src/ü.rs:1:1", true);
+        let (header, body) = report.split_once("\r\n\r\n").unwrap();
+        assert_eq!(header, format!("Content-Length: {}", body.len()));
+        parse(body);
+    }
+
+    #[test]
+    fn locations_with_quotes_stay_valid_json() {
+        let error = parse(report(r#"C:\src\"odd".rs:1:1"#, false).trim_end());
+        let message = error["error"]["message"].as_str().unwrap();
+        assert!(message.ends_with(r#"(panic at C:\src\"odd".rs:1:1)"#));
+    }
+}
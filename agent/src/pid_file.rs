@@ -0,0 +1,80 @@
+//! PID file for process supervisors (`OOK_PID_FILE`).
+//!
+//! Written at startup and removed when the agent exits cleanly; removal is
+//! best effort, so a crash leaves a stale file behind. A file naming a dead
+//! process, or not naming one at all, is replaced. One naming a live
+//! process is replaced with a warning, or with `OOK_PID_FILE_EXCLUSIVE=1`
+//! stops this agent from starting.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    pub fn create(path: &Path, exclusive: bool) -> Result<Self, String> {
+        let pid = std::process::id();
+        if let Some(other) = read_pid(path).filter(|&other| other != pid) {
+            if is_alive(other) {
+                if exclusive {
+                    return Err(format!(
+                        "another agent (pid {}) is running per OOK_PID_FILE {}",
+                        other,
+                        path.display()
+                    ));
+                }
+                warn!(path = %path.display(), pid = other, "PID file names a running process; replacing it");
+            } else {
+                info!(path = %path.display(), pid = other, "Replacing stale PID file");
+            }
+        }
+        fs::write(path, format!("{}\n", pid))
+            .map_err(|e| format!("Failed to write OOK_PID_FILE {}: {}", path.display(), e))?;
+        debug!(path = %path.display(), pid, "Wrote PID file");
+        Ok(Self {
+            path: path.to_path_buf(),
+            pid,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave it alone if another agent has since taken it over
+        if read_pid(&self.path) != Some(self.pid) {
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!(error = %e, path = %self.path.display(), "Failed to remove PID file");
+            }
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks the process exists; EPERM means it does but
+    // belongs to someone else
+    let found = unsafe { libc::kill(pid, 0) } == 0;
+    found || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// No cheap check without extra dependencies; an existing file is taken
+/// as stale.
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    false
+}
@@ -0,0 +1,143 @@
+//! Prometheus scrape endpoint (`OOK_METRICS_ADDR`).
+//!
+//! The forwarding loop never waits on a scrape: every `EXPORT_INTERVAL` it
+//! renders `Metrics` into a `watch` channel, and the server, on its own
+//! tasks, answers `GET /metrics` with the latest rendering.
+
+use crate::metrics::Metrics;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+pub const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bind `addr` and serve scrapes on a background task. Returns the sender
+/// to publish renderings through.
+///
+/// Binding happens up front so a bad address fails startup; the task
+/// itself ends with the process.
+pub async fn spawn(addr: &str) -> io::Result<watch::Sender<String>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(addr = %listener.local_addr()?, "Serving Prometheus metrics");
+
+    let (tx, rx) = watch::channel(render(&Metrics::default(), false));
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(error = %e, "Failed to accept metrics connection");
+                    continue;
+                }
+            };
+            let rx = rx.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    let body = rx.borrow().clone();
+                    async move { Ok::<_, Infallible>(respond(&request, body)) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!(error = %e, "Metrics connection failed");
+                }
+            });
+        }
+    });
+    Ok(tx)
+}
+
+fn respond(request: &Request<Incoming>, body: String) -> Response<Full<Bytes>> {
+    let (status, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => (StatusCode::OK, body),
+        _ => (StatusCode::NOT_FOUND, "not found\n".to_string()),
+    };
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        "text/plain; version=0.0.4"
+            .parse()
+            .expect("static header value"),
+    );
+    response
+}
+
+/// `metrics` in the Prometheus text exposition format.
+pub fn render(metrics: &Metrics, connected: bool) -> String {
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: &[(&str, f64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+
+    family(
+        "ook_messages_total",
+        "counter",
+        "Messages forwarded, by direction.",
+        &[
+            (
+                "{direction=\"zed_to_bridge\"}",
+                metrics.messages_to_bridge as f64,
+            ),
+            (
+                "{direction=\"bridge_to_zed\"}",
+                metrics.messages_to_zed as f64,
+            ),
+        ],
+    );
+    family(
+        "ook_bytes_total",
+        "counter",
+        "Message bytes forwarded, by direction.",
+        &[
+            (
+                "{direction=\"zed_to_bridge\"}",
+                metrics.bytes_to_bridge as f64,
+            ),
+            ("{direction=\"bridge_to_zed\"}", metrics.bytes_to_zed as f64),
+        ],
+    );
+    family(
+        "ook_reconnects_total",
+        "counter",
+        "Successful reconnects to the bridge.",
+        &[("", metrics.reconnects as f64)],
+    );
+    family(
+        "ook_send_blocked_seconds_total",
+        "counter",
+        "Time spent waiting for the bridge connection to accept sends.",
+        &[("", metrics.send_blocked_ms as f64 / 1000.0)],
+    );
+    family(
+        "ook_connected",
+        "gauge",
+        "1 while a bridge session is running.",
+        &[("", if connected { 1.0 } else { 0.0 })],
+    );
+    if let Some(rtt_ms) = metrics.last_pong_rtt_ms {
+        family(
+            "ook_last_pong_rtt_seconds",
+            "gauge",
+            "Round-trip time of the latest keepalive ping.",
+            &[("", rtt_ms / 1000.0)],
+        );
+    }
+    out
+}
@@ -0,0 +1,224 @@
+//! Capturing ACP sessions to JSONL (`OOK_RECORD_PATH`), replaying the
+//! bridge side of a capture to stdout (`OOK_REPLAY_PATH`), and printing a
+//! capture for reading (`ook show`).
+//!
+//! Each capture line is `{"ts_ms":..,"direction":..,"message":..}`, where
+//! `ts_ms` is milliseconds since the Unix epoch and `message` is the raw
+//! ACP line.
+
+use crate::cli::ShowArgs;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tracing::{info, warn};
+
+pub const ZED_TO_BRIDGE: &str = "zed->bridge";
+pub const BRIDGE_TO_ZED: &str = "bridge->zed";
+
+/// Appends every forwarded message to a capture file.
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Append one message. Written with a single unbuffered write, so a
+    /// crash leaves every earlier line intact.
+    pub fn record(&mut self, direction: &str, message: &str) {
+        let ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let mut line = json!({
+            "ts_ms": ts_ms,
+            "direction": direction,
+            "message": message,
+        })
+        .to_string();
+        line.push('\n');
+
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!(error = %e, "Failed to write to OOK_RECORD_PATH");
+        }
+    }
+}
+
+/// Write the bridge->zed messages of a capture to stdout, keeping their
+/// original spacing in time. Malformed lines are skipped with a warning.
+pub async fn replay(path: &Path) -> io::Result<()> {
+    let capture = tokio::fs::read_to_string(path).await?;
+    let mut stdout = tokio::io::stdout();
+    let start = tokio::time::Instant::now();
+    let mut first_ts: Option<u64> = None;
+    let mut replayed = 0u64;
+
+    for (index, line) in capture.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Value = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(line = index + 1, error = %e, "Skipping malformed capture line");
+                continue;
+            }
+        };
+        if entry["direction"] != BRIDGE_TO_ZED {
+            continue;
+        }
+        let (Some(ts_ms), Some(message)) = (entry["ts_ms"].as_u64(), entry["message"].as_str())
+        else {
+            warn!(
+                line = index + 1,
+                "Skipping capture line without ts_ms/message"
+            );
+            continue;
+        };
+
+        let offset = ts_ms.saturating_sub(*first_ts.get_or_insert(ts_ms));
+        tokio::time::sleep_until(start + Duration::from_millis(offset)).await;
+
+        stdout.write_all(message.as_bytes()).await?;
+        if !message.ends_with('\n') {
+            stdout.write_all(b"\n").await?;
+        }
+        stdout.flush().await?;
+        replayed += 1;
+    }
+
+    info!(messages = replayed, "Replay finished");
+    Ok(())
+}
+
+/// Print a capture one frame at a time: a header line with the time, the
+/// delta since the previous printed frame, the direction, and the method
+/// and id, then the message as indented JSON. A response is labelled with
+/// the method of the request it answers, when that request is in the
+/// capture, and `--method` matches it too. The file is streamed, so its
+/// size doesn't matter; malformed lines are noted on stderr and skipped.
+pub async fn show(args: &ShowArgs) -> io::Result<()> {
+    let file = tokio::fs::File::open(&args.path).await?;
+    let mut lines = BufReader::new(file).lines();
+    let mut stdout = BufWriter::new(tokio::io::stdout());
+    // Methods of requests not yet answered, keyed by direction and id
+    let mut pending: HashMap<(String, String), String> = HashMap::new();
+    let mut previous_ts: Option<u64> = None;
+    let mut index = 0;
+
+    while let Some(line) = lines.next_line().await? {
+        index += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Value = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("line {}: skipping malformed capture line: {}", index, e);
+                continue;
+            }
+        };
+        let (Some(ts_ms), Some(direction), Some(message)) = (
+            entry["ts_ms"].as_u64(),
+            entry["direction"].as_str(),
+            entry["message"].as_str(),
+        ) else {
+            eprintln!(
+                "line {}: skipping capture line without ts_ms/direction/message",
+                index
+            );
+            continue;
+        };
+
+        let parsed: Option<Value> = serde_json::from_str(message).ok();
+        let id = parsed
+            .as_ref()
+            .and_then(|value| value.get("id"))
+            .filter(|id| !id.is_null())
+            .map(Value::to_string);
+        let method = parsed
+            .as_ref()
+            .and_then(|value| value.get("method"))
+            .and_then(Value::as_str);
+        let label = match (method, &id) {
+            (Some(method), Some(id)) => {
+                pending.insert((direction.to_string(), id.clone()), method.to_string());
+                Some(method.to_string())
+            }
+            (Some(method), None) => Some(method.to_string()),
+            // A response travels the other way from its request
+            (None, Some(id)) => {
+                let request_direction = if direction == ZED_TO_BRIDGE {
+                    BRIDGE_TO_ZED
+                } else {
+                    ZED_TO_BRIDGE
+                };
+                pending.remove(&(request_direction.to_string(), id.clone()))
+            }
+            (None, None) => None,
+        };
+
+        if args.direction.as_deref().is_some_and(|d| d != direction) {
+            continue;
+        }
+        if let Some(wanted) = &args.method {
+            if label.as_deref() != Some(wanted.as_str()) {
+                continue;
+            }
+        }
+
+        let delta_ms = previous_ts.map_or(0, |previous| ts_ms.saturating_sub(previous));
+        previous_ts = Some(ts_ms);
+        let kind = match (method, &id) {
+            (Some(_), Some(_)) => "request",
+            (Some(_), None) => "notification",
+            (None, Some(_)) if parsed.as_ref().is_some_and(|v| v.get("error").is_some()) => "error",
+            (None, Some(_)) => "response",
+            (None, None) => "message",
+        };
+        let mut header = format!(
+            "{}  +{}.{:03}s  {}  {}",
+            format_ts(ts_ms),
+            delta_ms / 1000,
+            delta_ms % 1000,
+            direction,
+            kind
+        );
+        if let Some(label) = &label {
+            header.push_str(&format!(" {}", label));
+        }
+        if let Some(id) = &id {
+            header.push_str(&format!(" id={}", id));
+        }
+        let body = match &parsed {
+            Some(value) => serde_json::to_string_pretty(value).unwrap_or_default(),
+            None => message.trim_end().to_string(),
+        };
+
+        stdout.write_all(header.as_bytes()).await?;
+        stdout.write_all(b"\n").await?;
+        for body_line in body.lines() {
+            stdout.write_all(b"  ").await?;
+            stdout.write_all(body_line.as_bytes()).await?;
+            stdout.write_all(b"\n").await?;
+        }
+    }
+    stdout.flush().await
+}
+
+/// `ts_ms` as RFC 3339 UTC, or the raw number if it is out of range.
+fn format_ts(ts_ms: u64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(ts_ms) * 1_000_000)
+        .ok()
+        .and_then(|ts| ts.format(&Rfc3339).ok())
+        .unwrap_or_else(|| ts_ms.to_string())
+}
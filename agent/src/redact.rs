@@ -0,0 +1,185 @@
+//! Keeping message bodies out of debug logs unless asked (`OOK_LOG_BODIES`),
+//! and narrowing which messages are logged at all (`OOK_LOG_METHODS`).
+//!
+//! Only what gets logged is affected; forwarded messages are never touched.
+
+use crate::preview;
+use serde_json::Value;
+use std::fmt;
+
+/// JSON keys (compared case-insensitively) whose values are masked.
+const SENSITIVE_KEYS: &[&str] = &[
+    "authorization",
+    "token",
+    "access_token",
+    "api_key",
+    "password",
+    "secret",
+    "content",
+];
+
+const MASK: &str = "[redacted]";
+
+/// A message as it should appear in a log line: in full with
+/// `OOK_LOG_BODIES=1`, else redacted and truncated.
+///
+/// The work happens in `Display`, so nothing is parsed unless the event is
+/// actually logged.
+pub struct LogBody<'a> {
+    pub message: &'a str,
+    pub full: bool,
+}
+
+impl fmt::Display for LogBody<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.full {
+            f.write_str(self.message)
+        } else {
+            f.write_str(&redact(self.message))
+        }
+    }
+}
+
+/// Mask the values of `SENSITIVE_KEYS` anywhere in a JSON message, then cut
+/// it to `PREVIEW_CHARS` characters, noting the full size if it was longer.
+/// Input that isn't JSON is only truncated.
+pub fn redact(message: &str) -> String {
+    let masked = match serde_json::from_str::<Value>(message) {
+        Ok(mut value) => {
+            mask(&mut value);
+            value.to_string()
+        }
+        Err(_) => message.to_string(),
+    };
+    let cut = preview(&masked);
+    if cut.len() == masked.len() {
+        masked
+    } else {
+        format!("{}... ({} bytes)", cut, message.len())
+    }
+}
+
+fn mask(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SENSITIVE_KEYS.iter().any(|k| key.eq_ignore_ascii_case(k)) {
+                    *value = Value::String(MASK.to_string());
+                } else {
+                    mask(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(mask),
+        _ => {}
+    }
+}
+
+/// In `OOK_LOG_METHODS`, stands for messages without a `method`
+/// (responses, and anything that isn't JSON-RPC).
+const RESPONSE_TOKEN: &str = "*response*";
+
+/// Which messages get a per-message debug log (`OOK_LOG_METHODS`).
+pub struct MethodFilter {
+    methods: Vec<String>,
+    responses: bool,
+}
+
+impl MethodFilter {
+    /// A comma-separated list of methods, optionally with `*response*`.
+    pub fn parse(list: &str) -> Self {
+        let mut filter = Self {
+            methods: Vec::new(),
+            responses: false,
+        };
+        for token in list.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            if token == RESPONSE_TOKEN {
+                filter.responses = true;
+            } else {
+                filter.methods.push(token.to_string());
+            }
+        }
+        filter
+    }
+
+    pub fn matches(&self, message: &str) -> bool {
+        let parsed = serde_json::from_str::<Value>(message).ok();
+        match parsed
+            .as_ref()
+            .and_then(|v| v.get("method"))
+            .and_then(Value::as_str)
+        {
+            Some(method) => self.methods.iter().any(|m| m == method),
+            None => self.responses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PREVIEW_CHARS;
+
+    #[test]
+    fn sensitive_values_are_masked_at_any_depth() {
+        let message = r#"{"p":{"Authorization":"Bearer abc","i":[{"api_key":"k1","n":"x"}],"a":{"PASSWORD":"hunter2","token":{"t":"t"}}}}"#;
+        let mut value: Value = serde_json::from_str(message).unwrap();
+        mask(&mut value);
+        let masked = value.to_string();
+        for secret in ["Bearer abc", "k1", "hunter2", "\"t\""] {
+            assert!(!masked.contains(secret), "{} leaked in {}", secret, masked);
+        }
+        assert_eq!(value["p"]["Authorization"], MASK);
+        assert_eq!(value["p"]["i"][0]["api_key"], MASK);
+        assert_eq!(value["p"]["i"][0]["n"], "x");
+        assert_eq!(value["p"]["a"]["PASSWORD"], MASK);
+        assert_eq!(value["p"]["a"]["token"], MASK);
+    }
+
+    #[test]
+    fn file_contents_are_masked() {
+        let message =
+            r#"{"method":"fs/write_text_file","params":{"path":"/etc/x","content":"private"}}"#;
+        let redacted = redact(message);
+        assert!(!redacted.contains("private"));
+        assert!(redacted.contains("/etc/x"));
+    }
+
+    #[test]
+    fn long_messages_are_truncated_with_their_size() {
+        let message = format!(r#"{{"a":"{}"}}"#, "x".repeat(500));
+        let redacted = redact(&message);
+        assert!(redacted.ends_with(&format!("... ({} bytes)", message.len())));
+        assert!(redacted.chars().count() < PREVIEW_CHARS + 20);
+    }
+
+    #[test]
+    fn non_json_is_only_truncated() {
+        assert_eq!(redact("token=abc not json"), "token=abc not json");
+    }
+
+    #[test]
+    fn full_bodies_are_left_alone() {
+        let message = r#"{"token":"abc"}"#;
+        let full = LogBody {
+            message,
+            full: true,
+        };
+        assert_eq!(full.to_string(), message);
+        let redacted = LogBody {
+            message,
+            full: false,
+        };
+        assert_eq!(redacted.to_string(), r#"{"token":"[redacted]"}"#);
+    }
+
+    #[test]
+    fn method_filter_matches_methods_and_responses() {
+        let filter = MethodFilter::parse(" session/prompt, *response* ,");
+        assert!(filter.matches(r#"{"jsonrpc":"2.0","id":1,"method":"session/prompt"}"#));
+        assert!(!filter.matches(r#"{"jsonrpc":"2.0","method":"session/update"}"#));
+        assert!(filter.matches(r#"{"jsonrpc":"2.0","id":1,"result":{}}"#));
+        assert!(filter.matches("not json"));
+        assert!(!MethodFilter::parse("initialize").matches(r#"{"id":1,"result":{}}"#));
+    }
+}
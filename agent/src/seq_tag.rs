@@ -0,0 +1,95 @@
+//! Client sequence numbers on outgoing messages (`OOK_INJECT_SEQ=1`).
+//!
+//! Each stdin line that is a JSON object gets a `_ook_seq` field, counting
+//! up from 1 for the life of the agent, so the bridge can spot a dropped or
+//! reordered frame. This changes the payload the bridge forwards, so it is
+//! off by default. The field is inserted as the object's first key and the
+//! rest of the line is left byte for byte as Zed sent it. Lines that aren't
+//! objects, or already have a `_ook_seq`, pass through untouched and don't
+//! use up a number. A line resent after a reconnect keeps its number.
+//! After `u64::MAX` the count wraps around to 0 rather than stopping the
+//! agent, however unlikely that is.
+
+use serde::de::IgnoredAny;
+use std::collections::HashMap;
+
+pub const FIELD: &str = "_ook_seq";
+
+pub struct SeqTag {
+    next: u64,
+}
+
+impl SeqTag {
+    pub fn new() -> Self {
+        Self { next: 1 }
+    }
+
+    /// `line` with the next sequence number added, or `None` to send it as is.
+    pub fn tag(&mut self, line: &str) -> Option<String> {
+        // Only the keys are kept; values are checked and skipped
+        let keys = serde_json::from_str::<HashMap<String, IgnoredAny>>(line).ok()?;
+        if keys.contains_key(FIELD) {
+            return None;
+        }
+        let body = line.trim_start().strip_prefix('{')?;
+        let separator = if keys.is_empty() { "" } else { "," };
+        let tagged = format!("{{\"{}\":{}{}{}", FIELD, self.next, separator, body);
+        self.next = self.next.wrapping_add(1);
+        Some(tagged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn objects_are_numbered_from_one() {
+        let mut seq = SeqTag::new();
+        assert_eq!(
+            seq.tag(r#"{"jsonrpc":"2.0","id":1}"#).as_deref(),
+            Some(r#"{"_ook_seq":1,"jsonrpc":"2.0","id":1}"#)
+        );
+        assert_eq!(seq.tag("{}").as_deref(), Some(r#"{"_ook_seq":2}"#));
+        // The rest of the line is kept byte for byte, whitespace included
+        assert_eq!(
+            seq.tag(r#"  { "b" : 2 , "a":1 }"#).as_deref(),
+            Some(r#"{"_ook_seq":3, "b" : 2 , "a":1 }"#)
+        );
+    }
+
+    #[test]
+    fn untagged_lines_do_not_use_up_a_number() {
+        let mut seq = SeqTag::new();
+        for line in [
+            "[1,2]",
+            "\"text\"",
+            "not json",
+            r#"{"truncated":"#,
+            r#"{"_ook_seq":7,"id":1}"#,
+        ] {
+            assert_eq!(seq.tag(line), None, "{}", line);
+        }
+        assert_eq!(seq.tag("{}").as_deref(), Some(r#"{"_ook_seq":1}"#));
+    }
+
+    #[test]
+    fn tagged_lines_are_still_json() {
+        let mut seq = SeqTag::new();
+        let tagged = seq
+            .tag(r#"{"params":{"_ook_seq":"nested is fine"}}"#)
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&tagged).unwrap();
+        assert_eq!(value[FIELD], 1);
+        assert_eq!(value["params"][FIELD], "nested is fine");
+    }
+
+    #[test]
+    fn the_count_wraps_around_to_zero() {
+        let mut seq = SeqTag { next: u64::MAX };
+        let max = format!(r#"{{"_ook_seq":{}}}"#, u64::MAX);
+        assert_eq!(seq.tag("{}"), Some(max));
+        assert_eq!(seq.tag("{}").as_deref(), Some(r#"{"_ook_seq":0}"#));
+        assert_eq!(seq.tag("{}").as_deref(), Some(r#"{"_ook_seq":1}"#));
+    }
+}
@@ -0,0 +1,85 @@
+use std::io;
+
+/// Listens for the signals that ask the agent to shut down cleanly.
+///
+/// Handlers are installed once at startup so a signal arriving between two
+/// `select!` iterations is not lost.
+pub struct ShutdownSignal {
+    #[cfg(unix)]
+    sigterm: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    sigint: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    ctrl_c: tokio::signal::windows::CtrlC,
+}
+
+impl ShutdownSignal {
+    #[cfg(unix)]
+    pub fn install() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn install() -> io::Result<Self> {
+        Ok(Self {
+            ctrl_c: tokio::signal::windows::ctrl_c()?,
+        })
+    }
+
+    /// Wait for the next shutdown signal and return its name.
+    #[cfg(unix)]
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigterm.recv() => "SIGTERM",
+            _ = self.sigint.recv() => "SIGINT",
+        }
+    }
+
+    /// Wait for the next shutdown signal and return its name.
+    #[cfg(windows)]
+    pub async fn recv(&mut self) -> &'static str {
+        self.ctrl_c.recv().await;
+        "CTRL_C"
+    }
+}
+
+/// Listens for SIGHUP, which asks for a fresh bridge connection without
+/// restarting the agent. Windows has no SIGHUP, so there it never fires.
+pub struct ReconnectSignal {
+    #[cfg(unix)]
+    sighup: tokio::signal::unix::Signal,
+}
+
+impl ReconnectSignal {
+    /// Only install this where SIGHUP is acted on: it replaces the default
+    /// of terminating the process.
+    #[cfg(unix)]
+    pub fn install() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            sighup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn install() -> io::Result<Self> {
+        tracing::warn!("SIGHUP does not exist on Windows; reconnect-on-SIGHUP is unavailable");
+        Ok(Self {})
+    }
+
+    /// Wait for the next SIGHUP.
+    #[cfg(unix)]
+    pub async fn recv(&mut self) {
+        self.sighup.recv().await;
+    }
+
+    /// Never returns: there is no SIGHUP on Windows.
+    #[cfg(windows)]
+    pub async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,96 @@
+//! Clock skew warnings (`OOK_MAX_CLOCK_SKEW_SECS`).
+//!
+//! When a bridge->zed message carries a timestamp in the configured field
+//! (`OOK_CLOCK_SKEW_FIELD`, a dotted path such as `params.ts`), it is
+//! compared against the local clock. A drifted VM clock is logged once when
+//! the skew first exceeds the limit, and again when it is back within it.
+//! Messages are never changed; ones without the field, or with a value that
+//! isn't a timestamp, are skipped.
+//!
+//! Timestamps are RFC 3339 strings or Unix epoch numbers, in seconds or (if
+//! too large to be seconds) milliseconds. Transit time counts as skew, so
+//! the limit should be well above the bridge round trip.
+
+use serde_json::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+/// Field checked when `OOK_CLOCK_SKEW_FIELD` is unset.
+pub const DEFAULT_FIELD: &str = "timestamp";
+
+/// Epoch numbers from here up are taken as milliseconds (about the year
+/// 5138 in seconds, early 1973 in milliseconds).
+const EPOCH_MS_FROM: f64 = 1e11;
+
+pub struct SkewCheck {
+    field: String,
+    max: Duration,
+    /// The last skew seen was over `max`.
+    skewed: bool,
+}
+
+impl SkewCheck {
+    pub fn new(field: &str, max: Duration) -> Self {
+        Self {
+            field: field.to_string(),
+            max,
+            skewed: false,
+        }
+    }
+
+    /// Compare a bridge message's timestamp, if it has one, to our clock.
+    pub fn observe(&mut self, message: &str) {
+        let Ok(value) = serde_json::from_str::<Value>(message) else {
+            return;
+        };
+        let Some(timestamp) = self
+            .field
+            .split('.')
+            .try_fold(&value, |value, key| value.get(key))
+            .and_then(parse_timestamp)
+        else {
+            return;
+        };
+
+        // Positive when the bridge is ahead of us
+        let skew_secs = match timestamp.duration_since(SystemTime::now()) {
+            Ok(ahead) => ahead.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        };
+        let skewed = skew_secs.abs() > self.max.as_secs_f64();
+        if skewed && !self.skewed {
+            warn!(
+                skew_secs,
+                max_clock_skew_secs = self.max.as_secs(),
+                field = %self.field,
+                "Bridge clock differs from ours by more than OOK_MAX_CLOCK_SKEW_SECS"
+            );
+        } else if !skewed && self.skewed {
+            info!(
+                skew_secs,
+                "Bridge clock back within OOK_MAX_CLOCK_SKEW_SECS"
+            );
+        }
+        self.skewed = skewed;
+    }
+}
+
+fn parse_timestamp(value: &Value) -> Option<SystemTime> {
+    match value {
+        Value::String(text) => OffsetDateTime::parse(text, &Rfc3339)
+            .ok()
+            .map(SystemTime::from),
+        Value::Number(number) => {
+            let epoch = number.as_f64().filter(|n| n.is_finite() && *n >= 0.0)?;
+            let secs = if epoch >= EPOCH_MS_FROM {
+                epoch / 1000.0
+            } else {
+                epoch
+            };
+            UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(secs).ok()?)
+        }
+        _ => None,
+    }
+}
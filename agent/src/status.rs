@@ -0,0 +1,45 @@
+//! Liveness snapshot for process supervisors (`OOK_STATUS_FILE`).
+//!
+//! Rewritten every `STATUS_INTERVAL`, so a stale mtime means a hung agent.
+//! Unlike the metrics log line this is only ever the current state.
+
+use crate::metrics::Metrics;
+use serde_json::json;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Replace the status file with a fresh snapshot.
+///
+/// The write happens on a background task (temp file, then rename, so
+/// readers never see a partial file); failures are logged, never fatal.
+pub fn write(path: &Path, connected: bool, metrics: &Metrics) {
+    let snapshot = json!({
+        "connected": connected,
+        "last_rx_ts": metrics.last_from_bridge.map(unix_ms),
+        "last_tx_ts": metrics.last_to_bridge.map(unix_ms),
+        "reconnects": metrics.reconnects,
+    })
+    .to_string();
+
+    let path = path.to_path_buf();
+    tokio::spawn(async move {
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let written = match tokio::fs::write(&tmp, snapshot).await {
+            Ok(()) => tokio::fs::rename(&tmp, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!(error = %e, path = %path.display(), "Failed to write OOK_STATUS_FILE");
+        }
+    });
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
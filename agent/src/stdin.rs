@@ -0,0 +1,70 @@
+//! Reading stdin on its own task, so a slow send to the bridge doesn't
+//! stop the next line from being read.
+//!
+//! Lines reach the forwarding loop through a bounded channel, in order.
+//! Backpressure is kept: once `CHANNEL_CAPACITY` lines are waiting the
+//! reader stops, and Zed's writes to our stdin block as before.
+
+use crate::framing::LineReader;
+use std::io;
+use tokio::io::AsyncBufRead;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Lines the reader may get ahead of the forwarding loop.
+pub const CHANNEL_CAPACITY: usize = 64;
+
+pub struct StdinChannel {
+    rx: mpsc::Receiver<io::Result<String>>,
+    /// Most lines ever waiting in the channel at once.
+    high_water: usize,
+}
+
+impl StdinChannel {
+    /// Start reading `lines` on a background task.
+    ///
+    /// The task ends at EOF, after a read error (which is passed on), or
+    /// once the channel is dropped.
+    pub fn spawn<R>(mut lines: LineReader<R>) -> Self
+    where
+        R: AsyncBufRead + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        tokio::spawn(async move {
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => Ok(line),
+                    Ok(None) => return,
+                    Err(e) => Err(e),
+                };
+                let failed = line.is_err();
+                if tx.send(line).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Self { rx, high_water: 0 }
+    }
+
+    /// The next line, or `Ok(None)` once stdin is closed. Cancel-safe.
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        let line = self.rx.recv().await.transpose()?;
+        if line.is_some() {
+            // Lines read ahead, behind the one just taken
+            let queued = self.rx.len();
+            if queued > self.high_water {
+                self.high_water = queued;
+                debug!(
+                    high_water = queued,
+                    capacity = CHANNEL_CAPACITY,
+                    "Stdin channel reached a new high-water mark"
+                );
+            }
+        }
+        Ok(line)
+    }
+
+    pub fn high_water(&self) -> usize {
+        self.high_water
+    }
+}
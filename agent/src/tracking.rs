@@ -0,0 +1,372 @@
+//! JSON-RPC id correlation for diagnosing lost or duplicated responses
+//! (`OOK_TRACK_IDS=1`).
+//!
+//! Purely observational: messages are parsed on the side and forwarded
+//! untouched. Anything that isn't a JSON-RPC request or response
+//! (notifications, unparsable lines) is ignored.
+//!
+//! With `OOK_SLOW_RESPONSE_MS`, a bridge response to one of Zed's requests
+//! that took longer than that is warned about as it arrives. A response
+//! that never arrives is left to the unanswered warning; if one turns up
+//! after that warning, it is noted at info level rather than warned about
+//! twice.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// Which way a message is travelling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ZedToBridge,
+    BridgeToZed,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ZedToBridge => "zed->bridge",
+            Self::BridgeToZed => "bridge->zed",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Self::ZedToBridge => 0,
+            Self::BridgeToZed => 1,
+        }
+    }
+
+    fn reverse(self) -> Self {
+        match self {
+            Self::ZedToBridge => Self::BridgeToZed,
+            Self::BridgeToZed => Self::ZedToBridge,
+        }
+    }
+}
+
+struct Pending {
+    method: String,
+    sent_at: Instant,
+    /// Already reported as unanswered; don't repeat the warning.
+    warned: bool,
+}
+
+/// Outstanding requests in each direction, keyed by serialized id.
+pub struct IdTracker {
+    pending: [HashMap<String, Pending>; 2],
+    timeout: Duration,
+    /// Round trip above which a bridge response is slow (`OOK_SLOW_RESPONSE_MS`).
+    slow: Option<Duration>,
+}
+
+impl IdTracker {
+    pub fn new(timeout: Duration, slow: Option<Duration>) -> Self {
+        Self {
+            pending: [HashMap::new(), HashMap::new()],
+            timeout,
+            slow,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn slow(&self) -> Option<Duration> {
+        self.slow
+    }
+
+    /// Note a message being forwarded in `direction`.
+    pub fn observe(&mut self, direction: Direction, line: &str) {
+        match serde_json::from_str::<Value>(line) {
+            Ok(Value::Array(batch)) => {
+                for message in &batch {
+                    self.observe_one(direction, message);
+                }
+            }
+            Ok(message) => self.observe_one(direction, &message),
+            Err(_) => {}
+        }
+    }
+
+    fn observe_one(&mut self, direction: Direction, message: &Value) {
+        let Some(id) = message.get("id").filter(|id| !id.is_null()) else {
+            return;
+        };
+        let id = id.to_string();
+
+        if let Some(method) = message.get("method").and_then(Value::as_str) {
+            let pending = Pending {
+                method: method.to_string(),
+                sent_at: Instant::now(),
+                warned: false,
+            };
+            if self.pending[direction.index()]
+                .insert(id.clone(), pending)
+                .is_some()
+            {
+                warn!(
+                    direction = direction.as_str(),
+                    id, method, "Duplicate JSON-RPC request id"
+                );
+            }
+        } else if message.get("result").is_some() || message.get("error").is_some() {
+            // A response answers a request that went the other way
+            let requests = &mut self.pending[direction.reverse().index()];
+            match requests.remove(&id) {
+                Some(pending) if direction == Direction::BridgeToZed => {
+                    self.check_latency(&id, &pending);
+                }
+                Some(_) => {}
+                None => warn!(
+                    direction = direction.as_str(),
+                    id, "JSON-RPC response for unknown or already answered id"
+                ),
+            }
+        }
+    }
+
+    /// Warn if the bridge's answer to `pending` was slow.
+    fn check_latency(&self, id: &str, pending: &Pending) {
+        let Some(slow) = self.slow else {
+            return;
+        };
+        let rtt = pending.sent_at.elapsed();
+        if rtt < slow {
+            return;
+        }
+        let rtt_ms = rtt.as_millis() as u64;
+        if pending.warned {
+            info!(
+                id = %id,
+                method = %pending.method,
+                rtt_ms,
+                "JSON-RPC request reported unanswered got its response"
+            );
+        } else {
+            warn!(
+                id = %id,
+                method = %pending.method,
+                rtt_ms,
+                slow_response_ms = slow.as_millis() as u64,
+                "Slow JSON-RPC response from the bridge"
+            );
+        }
+    }
+
+    /// Warn (once each) about requests that have waited longer than the timeout.
+    pub fn warn_unanswered(&mut self) {
+        for direction in [Direction::ZedToBridge, Direction::BridgeToZed] {
+            for (id, pending) in &mut self.pending[direction.index()] {
+                let waited = pending.sent_at.elapsed();
+                if !pending.warned && waited >= self.timeout {
+                    pending.warned = true;
+                    warn!(
+                        direction = direction.as_str(),
+                        id = %id,
+                        method = %pending.method,
+                        waited_secs = waited.as_secs(),
+                        "JSON-RPC request still unanswered"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+    const SLOW: Duration = Duration::from_millis(500);
+
+    fn tracker() -> IdTracker {
+        IdTracker::new(TIMEOUT, Some(SLOW))
+    }
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// What `f` logs, one line per event.
+    fn logs(f: impl FnOnce()) -> String {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let bytes = capture.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    /// The ids waiting for an answer that went out in `direction`, sorted.
+    fn pending(tracker: &IdTracker, direction: Direction) -> Vec<String> {
+        let mut ids: Vec<_> = tracker.pending[direction.index()].keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn responses_answer_requests_from_the_other_side() {
+        let mut tracker = tracker();
+        tracker.observe(
+            Direction::ZedToBridge,
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#,
+        );
+        tracker.observe(
+            Direction::BridgeToZed,
+            r#"{"jsonrpc":"2.0","id":"p-1","method":"session/request_permission"}"#,
+        );
+        assert_eq!(pending(&tracker, Direction::ZedToBridge), ["1"]);
+        assert_eq!(pending(&tracker, Direction::BridgeToZed), [r#""p-1""#]);
+
+        // A response travelling the same way as the request answers nothing
+        tracker.observe(
+            Direction::ZedToBridge,
+            r#"{"jsonrpc":"2.0","id":1,"result":{}}"#,
+        );
+        assert_eq!(pending(&tracker, Direction::ZedToBridge), ["1"]);
+
+        tracker.observe(
+            Direction::BridgeToZed,
+            r#"{"jsonrpc":"2.0","id":1,"result":{}}"#,
+        );
+        tracker.observe(
+            Direction::ZedToBridge,
+            r#"{"jsonrpc":"2.0","id":"p-1","error":{"code":1,"message":"no"}}"#,
+        );
+        assert!(pending(&tracker, Direction::ZedToBridge).is_empty());
+        assert!(pending(&tracker, Direction::BridgeToZed).is_empty());
+    }
+
+    #[tokio::test]
+    async fn ids_are_told_apart_by_type() {
+        let mut tracker = tracker();
+        tracker.observe(Direction::ZedToBridge, r#"{"id":1,"method":"a"}"#);
+        tracker.observe(Direction::ZedToBridge, r#"{"id":"1","method":"b"}"#);
+        tracker.observe(Direction::BridgeToZed, r#"{"id":"1","result":null}"#);
+        assert_eq!(pending(&tracker, Direction::ZedToBridge), ["1"]);
+    }
+
+    #[tokio::test]
+    async fn batches_are_tracked_per_message() {
+        let mut tracker = tracker();
+        tracker.observe(
+            Direction::ZedToBridge,
+            r#"[{"id":1,"method":"a"},{"method":"note"},{"id":2,"method":"b"}]"#,
+        );
+        assert_eq!(pending(&tracker, Direction::ZedToBridge), ["1", "2"]);
+        tracker.observe(
+            Direction::BridgeToZed,
+            r#"[{"id":2,"result":1},{"id":1,"result":1}]"#,
+        );
+        assert!(pending(&tracker, Direction::ZedToBridge).is_empty());
+    }
+
+    #[tokio::test]
+    async fn notifications_null_ids_and_non_json_are_ignored() {
+        let mut tracker = tracker();
+        for line in [
+            r#"{"jsonrpc":"2.0","method":"session/update"}"#,
+            r#"{"jsonrpc":"2.0","id":null,"method":"x"}"#,
+            r#"{"jsonrpc":"2.0","id":3}"#,
+            "not json",
+        ] {
+            tracker.observe(Direction::ZedToBridge, line);
+        }
+        assert!(pending(&tracker, Direction::ZedToBridge).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_reused_id_replaces_the_earlier_request() {
+        let mut tracker = tracker();
+        tracker.observe(Direction::ZedToBridge, r#"{"id":1,"method":"first"}"#);
+        tracker.observe(Direction::ZedToBridge, r#"{"id":1,"method":"second"}"#);
+        let pending = &tracker.pending[Direction::ZedToBridge.index()];
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending["1"].method, "second");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_requests_are_marked_once_past_the_timeout() {
+        let mut tracker = tracker();
+        tracker.observe(Direction::ZedToBridge, r#"{"id":1,"method":"old"}"#);
+        tokio::time::advance(TIMEOUT / 2).await;
+        tracker.observe(Direction::BridgeToZed, r#"{"id":9,"method":"newer"}"#);
+
+        tracker.warn_unanswered();
+        assert!(!tracker.pending[0]["1"].warned);
+        tokio::time::advance(TIMEOUT / 2).await;
+        tracker.warn_unanswered();
+        assert!(tracker.pending[Direction::ZedToBridge.index()]["1"].warned);
+        assert!(!tracker.pending[Direction::BridgeToZed.index()]["9"].warned);
+
+        // Still tracked, so a late answer is matched rather than unknown
+        tokio::time::advance(TIMEOUT).await;
+        tracker.observe(Direction::BridgeToZed, r#"{"id":1,"result":{}}"#);
+        assert!(pending(&tracker, Direction::ZedToBridge).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_response_just_under_the_threshold_is_not_slow() {
+        let mut tracker = tracker();
+        tracker.observe(Direction::ZedToBridge, r#"{"id":1,"method":"fast"}"#);
+        tokio::time::advance(SLOW - Duration::from_millis(1)).await;
+        let logged = logs(|| tracker.observe(Direction::BridgeToZed, r#"{"id":1,"result":{}}"#));
+        assert_eq!(logged, "");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_slow_response_is_warned_about_by_method_and_id() {
+        let mut tracker = tracker();
+        tracker.observe(
+            Direction::ZedToBridge,
+            r#"{"id":7,"method":"session/prompt"}"#,
+        );
+        tokio::time::advance(SLOW + Duration::from_millis(250)).await;
+        let logged = logs(|| tracker.observe(Direction::BridgeToZed, r#"{"id":7,"result":{}}"#));
+        assert!(logged.starts_with(" WARN"), "{}", logged);
+        assert!(logged.contains("Slow JSON-RPC response"), "{}", logged);
+        assert!(
+            logged.contains("id=7 method=session/prompt rtt_ms=750"),
+            "{}",
+            logged
+        );
+        assert_eq!(logged.lines().count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_late_answer_after_the_unanswered_warning_is_only_noted() {
+        let mut tracker = tracker();
+        tracker.observe(Direction::ZedToBridge, r#"{"id":2,"method":"stuck"}"#);
+        tokio::time::advance(TIMEOUT).await;
+        tracker.warn_unanswered();
+        let logged = logs(|| tracker.observe(Direction::BridgeToZed, r#"{"id":2,"result":{}}"#));
+        assert!(logged.starts_with(" INFO"), "{}", logged);
+        assert!(
+            logged.contains("reported unanswered got its response"),
+            "{}",
+            logged
+        );
+        assert!(!logged.contains("WARN"), "{}", logged);
+    }
+}
@@ -0,0 +1,208 @@
+//! What a session runs over: a bridge connection split into a sink and a
+//! stream of WebSocket messages.
+//!
+//! The session loop (`run_session`) is generic over `Transport`, so it only
+//! sees messages. Every bridge transport today (TCP, TLS, Unix socket, named
+//! pipe) is a byte stream under the same WebSocket and arrives as one
+//! `WsStream`; a new byte stream is a `bridge::Endpoint`, not a
+//! `Transport`. Implement this for a connection that doesn't carry
+//! WebSocket frames over bytes at all: `lines`, the raw NDJSON connections
+//! of `OOK_LISTEN_ADDR`, and `memory`, the channel pair the tests run
+//! sessions over.
+
+use crate::bridge::WsStream;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{Sink, Stream, StreamExt};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+/// The sending half of a transport.
+pub trait MessageSink: Sink<Message, Error = WsError> + Unpin {}
+impl<T: Sink<Message, Error = WsError> + Unpin> MessageSink for T {}
+
+/// The receiving half of a transport; `None` once the connection is gone.
+pub trait MessageStream: Stream<Item = Result<Message, WsError>> + Unpin {}
+impl<T: Stream<Item = Result<Message, WsError>> + Unpin> MessageStream for T {}
+
+/// An open bridge connection.
+pub trait Transport {
+    type Sink: MessageSink;
+    /// `Send`, so it can be read on its own task (`OOK_INBOUND_OVERFLOW`).
+    type Stream: MessageStream + Send + 'static;
+
+    fn into_split(self) -> (Self::Sink, Self::Stream);
+}
+
+impl Transport for WsStream {
+    type Sink = SplitSink<WsStream, Message>;
+    type Stream = SplitStream<WsStream>;
+
+    fn into_split(self) -> (Self::Sink, Self::Stream) {
+        self.split()
+    }
+}
+
+/// A transport over in-process channels, with the bridge's end held by the
+/// caller: what the agent sends comes out of the `Peer`, and what the `Peer`
+/// sends is what the agent reads. For the tests and `bench`.
+pub mod memory {
+    use super::Transport;
+    use futures_util::{Sink, Stream};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+    /// The agent's end of the pair.
+    pub struct MemoryTransport {
+        sink: ChannelSink,
+        stream: ChannelStream,
+    }
+
+    /// The bridge's end of the pair. Dropping it ends the agent's stream
+    /// and fails its sends, like a connection that went away.
+    pub struct Peer {
+        tx: mpsc::UnboundedSender<Result<Message, WsError>>,
+        rx: mpsc::UnboundedReceiver<Message>,
+    }
+
+    pub fn pair() -> (MemoryTransport, Peer) {
+        let (to_agent, from_peer) = mpsc::unbounded_channel();
+        let (to_peer, from_agent) = mpsc::unbounded_channel();
+        let transport = MemoryTransport {
+            sink: ChannelSink(Some(to_peer)),
+            stream: ChannelStream(from_peer),
+        };
+        let peer = Peer {
+            tx: to_agent,
+            rx: from_agent,
+        };
+        (transport, peer)
+    }
+
+    impl Peer {
+        /// Queue a frame for the agent to read.
+        pub fn send(&self, msg: Message) {
+            let _ = self.tx.send(Ok(msg));
+        }
+
+        /// Queue a read error for the agent.
+        pub fn fail(&self, error: WsError) {
+            let _ = self.tx.send(Err(error));
+        }
+
+        /// The next frame the agent sent, or `None` once it closed its sink.
+        pub async fn recv(&mut self) -> Option<Message> {
+            self.rx.recv().await
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        type Sink = ChannelSink;
+        type Stream = ChannelStream;
+
+        fn into_split(self) -> (Self::Sink, Self::Stream) {
+            (self.sink, self.stream)
+        }
+    }
+
+    /// `None` once closed, as a WebSocket refuses to send after its close.
+    pub struct ChannelSink(Option<mpsc::UnboundedSender<Message>>);
+
+    impl Sink<Message> for ChannelSink {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<(), WsError> {
+            let Some(tx) = &self.0 else {
+                return Err(WsError::AlreadyClosed);
+            };
+            tx.send(msg).map_err(|_| WsError::ConnectionClosed)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            self.get_mut().0 = None;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    pub struct ChannelStream(mpsc::UnboundedReceiver<Result<Message, WsError>>);
+
+    impl Stream for ChannelStream {
+        type Item = Result<Message, WsError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.0.poll_recv(cx)
+        }
+    }
+}
+
+/// A transport over a raw byte stream of NDJSON lines, for the bridge
+/// connections `OOK_LISTEN_ADDR` accepts: each text or binary message is
+/// written as one line, and each non-blank line read is a text message.
+/// There are no control frames, so pings are dropped and a close shuts
+/// down our side of the stream.
+pub mod lines {
+    use super::Transport;
+    use crate::framing::LineReader;
+    use futures_util::{sink, stream, Sink, Stream};
+    use std::pin::Pin;
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+    pub struct LineTransport<S> {
+        stream: S,
+        max_line_bytes: usize,
+    }
+
+    impl<S> LineTransport<S> {
+        /// Lines over `max_line_bytes` are skipped, as on stdin.
+        pub fn new(stream: S, max_line_bytes: usize) -> Self {
+            Self {
+                stream,
+                max_line_bytes,
+            }
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Send + 'static> Transport for LineTransport<S> {
+        type Sink = Pin<Box<dyn Sink<Message, Error = WsError> + Send>>;
+        type Stream = Pin<Box<dyn Stream<Item = Result<Message, WsError>> + Send>>;
+
+        fn into_split(self) -> (Self::Sink, Self::Stream) {
+            let (reader, writer) = tokio::io::split(self.stream);
+            let lines = LineReader::new(BufReader::new(reader), self.max_line_bytes, "bridge");
+
+            let sink = sink::unfold(writer, |mut writer, msg: Message| async move {
+                match msg {
+                    Message::Text(_) | Message::Binary(_) => {
+                        writer.write_all(&msg.into_data()).await?;
+                        writer.write_all(b"\n").await?;
+                        writer.flush().await?;
+                    }
+                    Message::Close(_) => writer.shutdown().await?,
+                    Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+                }
+                Ok::<_, WsError>(writer)
+            });
+            let stream = stream::unfold(lines, |mut lines| async move {
+                loop {
+                    let msg = match lines.next_line().await {
+                        Ok(Some(line)) if line.trim().is_empty() => continue,
+                        Ok(Some(line)) => Ok(Message::Text(line)),
+                        Ok(None) => return None,
+                        Err(e) => Err(WsError::Io(e)),
+                    };
+                    return Some((msg, lines));
+                }
+            });
+            (Box::pin(sink), Box::pin(stream))
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! The real binary against its loopback echo bridge (`OOK_LOOPBACK=1`):
+//! `cargo test --features loopback`.
+
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::time::timeout;
+
+const DEADLINE: Duration = Duration::from_secs(10);
+
+#[tokio::test]
+async fn stdin_lines_come_back_on_stdout_through_the_echo_bridge() {
+    let mut agent = Command::new(env!("CARGO_BIN_EXE_ook"))
+        .env_clear()
+        .env("OOK_LOOPBACK", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("spawn ook");
+    let mut stdin = agent.stdin.take().unwrap();
+    let mut stdout = BufReader::new(agent.stdout.take().unwrap()).lines();
+
+    let lines = [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"session/cancel","params":{"sessionId":"s"}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"result":{"text":"ünïcödé"}}"#,
+    ];
+    for line in lines {
+        stdin
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+        stdin.flush().await.unwrap();
+        let echoed = timeout(DEADLINE, stdout.next_line())
+            .await
+            .expect("echo in time");
+        assert_eq!(echoed.unwrap().as_deref(), Some(line));
+    }
+
+    drop(stdin);
+    let status = timeout(DEADLINE, agent.wait()).await.expect("exit in time");
+    assert!(status.unwrap().success());
+    assert_eq!(stdout.next_line().await.unwrap(), None);
+}
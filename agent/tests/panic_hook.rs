@@ -0,0 +1,57 @@
+//! The panic hook in the real binary, set off by `OOK_FAULT_PANIC_AFTER_N`
+//! against the loopback bridge: `cargo test --features loopback,fault-injection`.
+
+use serde_json::Value;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::time::timeout;
+
+const DEADLINE: Duration = Duration::from_secs(10);
+
+#[tokio::test]
+async fn a_panic_reaches_zed_as_an_internal_error() {
+    let mut agent = Command::new(env!("CARGO_BIN_EXE_ook"))
+        .env_clear()
+        .env("OOK_LOOPBACK", "1")
+        .env("OOK_FAULT_PANIC_AFTER_N", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("spawn ook");
+    let mut stdin = agent.stdin.take().unwrap();
+    let mut stdout = BufReader::new(agent.stdout.take().unwrap()).lines();
+
+    let secret = "do-not-leak";
+    let line = format!(
+        r#"{{"jsonrpc":"2.0","id":1,"method":"x","params":{{"p":"{}"}}}}"#,
+        secret
+    );
+    stdin
+        .write_all(format!("{}\n", line).as_bytes())
+        .await
+        .unwrap();
+    stdin.flush().await.unwrap();
+
+    let report = timeout(DEADLINE, stdout.next_line())
+        .await
+        .expect("report in time")
+        .unwrap()
+        .expect("a line before the agent died");
+    let error: Value = serde_json::from_str(&report).expect("the report is JSON");
+    assert_eq!(error["error"]["code"], -32603);
+    let message = error["error"]["message"].as_str().unwrap();
+    assert!(
+        message.starts_with("internal agent error (panic at src/fault.rs:"),
+        "{}",
+        message
+    );
+    assert!(!report.contains(secret));
+
+    assert_eq!(stdout.next_line().await.unwrap(), None);
+    let status = timeout(DEADLINE, agent.wait()).await.expect("exit in time");
+    assert!(!status.unwrap().success());
+}
@@ -9,6 +9,7 @@ async function main(): Promise<void> {
   const port = parseInt(process.env.OOK_PORT ?? String(DEFAULT_PORT), 10);
   const command = process.env.OOK_COMMAND ?? DEFAULT_COMMAND;
   const commandArgs = process.env.OOK_COMMAND_ARGS?.split(" ").filter(Boolean) ?? [];
+  const portFile = process.env.OOK_PORT_FILE;
 
   logger.info({ port, command, commandArgs }, "Starting Ook Bridge");
 
@@ -19,6 +20,7 @@ async function main(): Promise<void> {
     port,
     command,
     commandArgs,
+    portFile,
   });
 
   // Graceful shutdown
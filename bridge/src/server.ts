@@ -1,3 +1,5 @@
+import { mkdirSync, writeFileSync } from "node:fs";
+import { dirname } from "node:path";
 import { WebSocketServer, WebSocket, type RawData } from "ws";
 import { trace, SpanStatusCode, type Span } from "@opentelemetry/api";
 import { createChildLogger } from "./logger.js";
@@ -11,6 +13,8 @@ export interface ServerConfig {
   port: number;
   command: string;
   commandArgs: string[];
+  /** Where to record the bound port for the agent (`OOK_PORT_FILE`). */
+  portFile?: string;
 }
 
 interface PendingRequest {
@@ -35,7 +39,15 @@ export class BridgeServer {
     const metrics = getMetrics();
 
     this.wss = new WebSocketServer({ port: this.config.port });
-    log.info({ port: this.config.port }, "WebSocket server started");
+    this.wss.on("listening", () => {
+      // The actual port, which differs from the configured one for port 0
+      const address = this.wss?.address();
+      const port = typeof address === "object" && address ? address.port : this.config.port;
+      log.info({ port }, "WebSocket server started");
+      if (this.config.portFile) {
+        this.writePortFile(this.config.portFile, port);
+      }
+    });
 
     this.wss.on("connection", (ws, req) => {
       const clientIp = req.socket.remoteAddress;
@@ -219,6 +231,16 @@ export class BridgeServer {
     }
   }
 
+  private writePortFile(portFile: string, port: number): void {
+    try {
+      mkdirSync(dirname(portFile), { recursive: true });
+      writeFileSync(portFile, `${port}\n`);
+      log.info({ portFile, port }, "Wrote port file");
+    } catch (err) {
+      log.warn({ err, portFile }, "Failed to write port file");
+    }
+  }
+
   async stop(): Promise<void> {
     log.info("Stopping server");
 